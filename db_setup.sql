@@ -9,6 +9,17 @@ CREATE TABLE IF NOT EXISTS memora.files (
     status Text,
     created_at Timestamp,
     modified_at Timestamp,
+    checksum Text,
+    size Bigint,
+    etag Text,
+    opened_at Timestamp,
+    opened_on Date,
+    key_id Text,
+    chunked Boolean,
+    codec Text,
+    metadata Text,
+    device_id Uuid,
+    updated_at Timestamp,
     PRIMARY KEY (user_id, id)
 );
 CREATE MATERIALIZED VIEW IF NOT EXISTS memora.files_by_directory AS
@@ -19,12 +30,124 @@ SELECT user_id,
     file_type,
     status,
     created_at,
-    modified_at
+    modified_at,
+    checksum,
+    size,
+    etag,
+    opened_at,
+    opened_on,
+    key_id,
+    chunked,
+    codec,
+    metadata,
+    device_id,
+    updated_at
 FROM memora.files
 WHERE directory IS NOT NULL
     AND user_id IS NOT NULL
     AND id IS NOT NULL PRIMARY KEY (user_id, directory, id) WITH CLUSTERING
 ORDER BY (directory ASC, id DESC);
+CREATE MATERIALIZED VIEW IF NOT EXISTS memora.files_by_opened_on AS
+SELECT user_id,
+    id,
+    name,
+    directory,
+    file_type,
+    status,
+    created_at,
+    modified_at,
+    checksum,
+    size,
+    etag,
+    opened_at,
+    opened_on,
+    key_id,
+    chunked,
+    codec,
+    metadata,
+    device_id,
+    updated_at
+FROM memora.files
+WHERE opened_on IS NOT NULL
+    AND user_id IS NOT NULL
+    AND id IS NOT NULL PRIMARY KEY (opened_on, user_id, id);
+CREATE MATERIALIZED VIEW IF NOT EXISTS memora.files_by_updated_at AS
+SELECT user_id,
+    id,
+    name,
+    directory,
+    file_type,
+    status,
+    created_at,
+    modified_at,
+    checksum,
+    size,
+    etag,
+    opened_at,
+    opened_on,
+    key_id,
+    chunked,
+    codec,
+    metadata,
+    device_id,
+    updated_at
+FROM memora.files
+WHERE updated_at IS NOT NULL
+    AND user_id IS NOT NULL
+    AND id IS NOT NULL PRIMARY KEY (user_id, updated_at, id) WITH CLUSTERING
+ORDER BY (updated_at ASC, id ASC);
+CREATE TABLE IF NOT EXISTS memora.chunks (
+    user_id Uuid,
+    hash Text,
+    size Bigint,
+    created_at Timestamp,
+    used_at Timestamp,
+    PRIMARY KEY (user_id, hash)
+);
+CREATE TABLE IF NOT EXISTS memora.snapshots (
+    user_id Uuid,
+    id Uuid,
+    name Text,
+    status Text,
+    created_at Timestamp,
+    completed_at Timestamp,
+    files Bigint,
+    size Bigint,
+    device_id Uuid,
+    PRIMARY KEY (user_id, id)
+);
+CREATE TABLE IF NOT EXISTS memora.snapshot_files (
+    user_id Uuid,
+    snapshot_id Uuid,
+    file_id Uuid,
+    name Text,
+    directory Text,
+    file_type Text,
+    created_at Timestamp,
+    modified_at Timestamp,
+    checksum Text,
+    size Bigint,
+    key_id Text,
+    chunked Boolean,
+    codec Text,
+    metadata Text,
+    object Text,
+    PRIMARY KEY ((user_id, snapshot_id), file_id)
+);
+CREATE TABLE IF NOT EXISTS memora.snapshot_objects (
+    user_id Uuid,
+    object Text,
+    snapshot_id Uuid,
+    PRIMARY KEY ((user_id, object), snapshot_id)
+);
+CREATE TABLE IF NOT EXISTS memora.devices (
+    user_id Uuid,
+    id Uuid,
+    name Text,
+    created_at Timestamp,
+    revoked_at Timestamp,
+    PRIMARY KEY (user_id, id)
+);
 CREATE TABLE IF NOT EXISTS memora.users (
     id Uuid,
     email Text,
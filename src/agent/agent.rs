@@ -1,357 +1,1193 @@
+use crate::agent::api::ApiClient;
+use crate::agent::chunks::ChunkedUploads;
+use crate::agent::compress::{Compression, UploadSource};
+use crate::agent::config::{AgentConfig, BandwidthOverride};
+use crate::agent::control::ControlState;
+use crate::agent::crypto::{encrypted_size, ContentSealer, FileKey, Keyring};
+use crate::agent::ignore::IgnoreRules;
+use crate::agent::index::{file_digest, modified_time, IndexEntry};
+use crate::agent::metadata::{FileMetadata, SymlinkPolicy};
+use crate::agent::multipart::MultipartUploads;
+use crate::agent::plan::{Planner, Step};
+use crate::agent::progress::{self, Progress};
+use crate::agent::queue::{Operation, RetryQueue};
+use crate::agent::remote::RemoteAgent;
+use crate::agent::roots::Roots;
+use crate::agent::service::StateLock;
+use crate::agent::throttle::{BandwidthSettings, Throttle};
+use crate::agent::transfer::{encrypted_body, file_body};
+use crate::agent::verify::VerifyOptions;
 use crate::schema::file::{
-    FileCreateRequest, FileResponse, FileStatus, FileType, FileUpdateRequest,
+    FileCompleteRequest, FileResponse, FileStatus, FileType, FileUpdateRequest,
 };
-use charybdis::types::Uuid;
 
-use fjall::{Config, PartitionHandle};
+use fjall::{Config, Keyspace, PartitionHandle, PersistMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use reqwest::header::CONTENT_LENGTH;
 use reqwest::Client;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::sync::Semaphore;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, Semaphore};
 use tokio::task::{self, spawn_blocking};
+use tokio::time::Instant;
+
+// How long the watcher waits for more events before syncing a burst
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+// Upper bound on how long a continuous burst of events is coalesced
+const WATCH_MAX_DELAY: Duration = Duration::from_secs(5);
+// How often the config file is checked for new bandwidth settings
+const BANDWIDTH_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct Agent {
-    token: String,
-    scan_dir: PathBuf,
+    pub(super) roots: Roots,
     scan_interval: u64,
+    pub(super) rescan_interval: u64,
+    pub(super) two_way: bool,
 
-    db: PartitionHandle,
+    pub(super) db: PartitionHandle,
+    keyspace: Keyspace,
 
     semaphore: Arc<Semaphore>,
+    workers: usize,
+
+    // Directory holding the state database, scratch files go there too
+    pub(super) state_dir: PathBuf,
 
     // Reqwest HTTP client
-    client: Arc<Client>,
+    pub(super) client: Arc<Client>,
+
+    // Client for the memora server API
+    pub(super) api: Arc<ApiClient>,
+
+    // Failed operations waiting to be retried
+    pub(super) queue: RetryQueue,
+
+    // Resumable uploads of large files
+    multipart: MultipartUploads,
+
+    // Deduplicated uploads in content-defined chunks
+    chunked: ChunkedUploads,
+
+    // Compression of the files that benefit from it
+    compression: Compression,
+
+    // Rules for paths that are never synced
+    pub(super) ignore: IgnoreRules,
+
+    // Works out what a sync changes, from the local tree and the index
+    pub(super) planner: Planner,
+
+    // Delete files from the server once they become ignored
+    pub(super) remove_ignored: bool,
+
+    // Whether links are synced as what they point to or as links
+    pub(super) symlinks: SymlinkPolicy,
+
+    // Bandwidth limit shared by all transfers
+    pub(super) throttle: Throttle,
+
+    // Scheduled verification against the server and where its report goes
+    pub(super) verify_interval: Option<u64>,
+    pub(super) verify_options: VerifyOptions,
+    pub(super) verify_report: PathBuf,
+
+    // Config file the bandwidth settings are reloaded from when it changes,
+    // the command line settings are applied on top of it
+    config_path: Option<PathBuf>,
+    config_modified: Mutex<Option<SystemTime>>,
+    bandwidth_override: BandwidthOverride,
+
+    // Where pulling the server's changes got to, and when the server was last
+    // listed in full to find deletions
+    pub(super) pull_state: PartitionHandle,
+    pub(super) last_full_pull: Mutex<Option<Instant>>,
+
+    // Held while the local tree is synced, scheduled verifications wait for it
+    // so that their repairs don't race the scanner or watcher
+    pub(super) sync_lock: tokio::sync::Mutex<()>,
+
+    // Pause, scan requests and activity reported through the control API
+    pub(super) control: ControlState,
+    pub(super) control_socket: PathBuf,
+
+    // Bytes moved by the transfers in progress
+    pub(super) progress: Progress,
+
+    // Keeps other agents away from the state database, released last
+    _lock: StateLock,
 }
 
 impl Agent {
-    pub fn new(token: String, scan_dir: PathBuf) -> Self {
-        // Create a semaphore to limit the number of concurrent workers
-        let max_workers = 4; // Set the number of workers
-        let semaphore = Arc::new(Semaphore::new(max_workers));
-        let client = Arc::new(Client::new()); // Shared HTTP client for uploads
+    pub async fn new(config: AgentConfig) -> Result<Self, std::io::Error> {
+        let RemoteAgent {
+            roots,
+            keyring,
+            semaphore,
+            client,
+            api,
+            throttle,
+            progress,
+        } = RemoteAgent::new(&config)?;
+
+        let lock = StateLock::acquire(&config.state_dir)?;
+        let keyspace = Config::new(&config.state_dir)
+            .open()
+            .map_err(std::io::Error::other)?;
+        if let (true, Some(keyring)) = (config.encrypt, &keyring) {
+            let keys = keyspace.open_partition("keys", Default::default()).unwrap();
+            Self::activate_key(&keys, &api, keyring).await?;
+        }
 
-        let keyspace = Config::default().open().unwrap();
         let db = keyspace
             .open_partition("tasks", Default::default())
             .unwrap();
+        let pull_state = keyspace.open_partition("pull", Default::default()).unwrap();
+        let queue = RetryQueue::new(
+            keyspace
+                .open_partition("queue", Default::default())
+                .unwrap(),
+        );
+        let multipart = MultipartUploads::new(
+            keyspace
+                .open_partition("multipart", Default::default())
+                .unwrap(),
+            config.multipart_threshold,
+            config.multipart_part_size,
+            throttle.clone(),
+        );
 
-        Self {
-            token,
-            scan_dir,
-            scan_interval: 5,
+        let chunked =
+            ChunkedUploads::new(config.chunking, config.chunk_threshold, throttle.clone());
+        let compression =
+            Compression::new(config.compress, config.compression_level, &config.state_dir);
+        // copies left behind by an agent that didn't stop cleanly
+        compression.remove_leftovers();
+
+        let ignore = IgnoreRules::new(&roots.dirs(), &config.exclude)?;
+        let planner = Planner {
+            roots: roots.clone(),
+            ignore: ignore.clone(),
+            symlinks: config.symlinks,
+            remove_ignored: config.remove_ignored,
+            db: db.clone(),
+            queue: queue.clone(),
+        };
+        let control_socket = config.control_socket();
+        let verify_report = config.verify_report();
+
+        Ok(Self {
+            roots,
+            scan_interval: config.scan_interval,
+            rescan_interval: config.rescan_interval,
+            two_way: config.two_way,
             db,
+            keyspace,
             semaphore,
+            workers: config.workers,
+            state_dir: config.state_dir.clone(),
             client,
-        }
+            api,
+            queue,
+            multipart,
+            chunked,
+            compression,
+            ignore,
+            planner,
+            remove_ignored: config.remove_ignored,
+            symlinks: config.symlinks,
+            throttle,
+            verify_interval: config.verify_interval,
+            verify_options: VerifyOptions {
+                deep: config.verify_deep,
+                repair: config.verify_repair,
+            },
+            verify_report,
+            config_modified: Mutex::new(config.path.as_deref().and_then(modified_at)),
+            pull_state,
+            last_full_pull: Mutex::new(None),
+            sync_lock: tokio::sync::Mutex::new(()),
+            control: ControlState::new(),
+            control_socket,
+            progress,
+            config_path: config.path,
+            bandwidth_override: config.bandwidth_override,
+            _lock: lock,
+        })
+    }
+
+    // activate_key picks the key uploads are encrypted with: the one used before,
+    // else the one other devices already use on the server, else a new one
+    async fn activate_key(
+        keys: &PartitionHandle,
+        api: &ApiClient,
+        keyring: &Keyring,
+    ) -> Result<(), std::io::Error> {
+        let keys_clone = keys.clone();
+        let stored = spawn_blocking(move || keys_clone.get("active"))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)?
+            .map(|key_id| String::from_utf8_lossy(&key_id).to_string());
+
+        let key_id = match stored {
+            Some(key_id) => Some(key_id),
+            None => {
+                let mut last_id = None;
+                loop {
+                    let page = api.list_files(last_id, 100).await?;
+                    last_id = page.last_id;
+
+                    let found = page
+                        .files
+                        .into_iter()
+                        .chain(page.unreadable)
+                        .find_map(|file| file.key_id);
+                    if found.is_some() || last_id.is_none() {
+                        break found;
+                    }
+                }
+            }
+        };
+
+        let key = keyring.activate(key_id.as_deref())?;
+        log::info!("Encrypting uploads with key: {}", key.id());
+
+        let keys_clone = keys.clone();
+        let key_id = key.id().to_string();
+        spawn_blocking(move || keys_clone.insert("active", key_id))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
     }
 
     // run_scanner is periodically scans a file system for changes
     pub async fn run_scanner(&self) {
-        println!("Running scanner with token: {}", self.token);
+        for root in self.roots.iter() {
+            log::info!(
+                "Running scanner on: {} -> {}",
+                root.local.to_string_lossy(),
+                root.prefix
+            );
+        }
 
         // timer to run scanner every self.scan_interval seconds
         let mut interval =
             tokio::time::interval(std::time::Duration::from_secs(self.scan_interval));
 
         loop {
-            interval.tick().await;
-            println!("Scanner tick");
-            self.scan_dir().await.unwrap();
+            tokio::select! {
+                _ = interval.tick() => log::debug!("Scanner tick"),
+                _ = self.control.scan_requested() => log::info!("Scan requested"),
+                _ = self.control.stopped() => break,
+            }
+            let _sync = self.sync_lock.lock().await;
+            if self.two_way {
+                if let Err(e) = self.pull_changes().await {
+                    log::error!("Error pulling changes: {}", e);
+                }
+            }
+            if let Err(e) = self.drain_queue().await {
+                log::error!("Error retrying queued operations: {}", e);
+            }
+            if let Err(e) = self.scan_roots().await {
+                if !self.control.is_stopping() {
+                    log::error!("Error scanning: {}", e);
+                }
+            }
         }
     }
 
-    pub async fn scan_dir(&self) -> Result<(), std::io::Error> {
-        let mut stack = vec![self.scan_dir.clone()]; // Stack to manage directories to visit
-        let mut tasks = Vec::new();
+    // run_watcher syncs paths reported by filesystem events as they happen
+    // and falls back to a full rescan every self.rescan_interval seconds
+    pub async fn run_watcher(&self) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let mut watcher = match notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                log::error!("Error creating watcher: {}", e);
+                return;
+            }
+        };
 
-        // let path = Path::new(self.scan_dir.as_path());
+        for root in self.roots.iter() {
+            if let Err(e) = watcher.watch(&root.local, RecursiveMode::Recursive) {
+                log::error!("Error watching {}: {}", root.local.to_string_lossy(), e);
+                return;
+            }
 
-        // Iterate while there are directories to process
-        while let Some(dir) = stack.pop() {
-            for entry in fs::read_dir(&dir)? {
-                let entry = entry?;
-                let path = entry.path();
+            log::info!(
+                "Watching: {} -> {}",
+                root.local.to_string_lossy(),
+                root.prefix
+            );
+        }
 
-                if path.is_dir() {
-                    // Add the subdirectory to the stack for later processing
-                    stack.push(path.clone());
+        // the first tick completes immediately and performs the initial scan
+        let mut rescan =
+            tokio::time::interval(std::time::Duration::from_secs(self.rescan_interval));
+        // server-side changes can't be watched, they are polled every self.scan_interval seconds
+        let mut pull = tokio::time::interval(std::time::Duration::from_secs(self.scan_interval));
+        // queued operations are retried once due, without waiting for the next rescan
+        let mut retry = tokio::time::interval(std::time::Duration::from_secs(self.scan_interval));
 
-                    if !Self::should_upload(self.db.clone(), &path).await {
-                        println!("Skip creating a directory: {}", path.to_string_lossy());
-                        continue;
+        loop {
+            tokio::select! {
+                _ = self.control.stopped() => break,
+                _ = pull.tick(), if self.two_way => {
+                    let _sync = self.sync_lock.lock().await;
+                    if let Err(e) = self.pull_changes().await {
+                        log::error!("Error pulling changes: {}", e);
                     }
+                }
+                _ = retry.tick() => {
+                    let _sync = self.sync_lock.lock().await;
+                    if let Err(e) = self.drain_queue().await {
+                        log::error!("Error retrying queued operations: {}", e);
+                    }
+                }
+                _ = rescan.tick() => {
+                    let _sync = self.sync_lock.lock().await;
+                    log::info!("Full rescan");
+                    if let Err(e) = self.scan_roots().await {
+                        log::error!("Error scanning: {}", e);
+                    }
+                }
+                _ = self.control.scan_requested() => {
+                    let _sync = self.sync_lock.lock().await;
+                    log::info!("Scan requested");
+                    if let Err(e) = self.scan_roots().await {
+                        log::error!("Error scanning: {}", e);
+                    }
+                }
+                Some(event) = rx.recv() => {
+                    let mut batch = EventBatch::default();
+                    batch.add(event);
 
-                    Self::create_file(
-                        self.token.clone(),
-                        &path,
-                        FileType::DIRECTORY,
-                        self.client.clone(),
-                    )
-                    .await?;
-
-                    Self::register_upload(
-                        self.db.clone(),
-                        &path,
-                        FileResponse {
-                            id: Uuid::new_v4(),
-                            name: path.file_name().unwrap().to_string_lossy().to_string(),
-                            directory: path.parent().unwrap().to_string_lossy().to_string(),
-                            file_type: "DIRECTORY".to_owned(),
-                            status: "CLOSED".to_owned(),
-                            presigned_url: None,
-                            upload_presigned_url: None,
-                            created_at: chrono::Utc::now(),
-                            modified_at: chrono::Utc::now(),
-                        },
-                    )
-                    .await?;
-                } else if path.is_file() {
-                    if !Self::should_upload(self.db.clone(), &path).await {
-                        println!("Skip creating a file: {}", path.to_string_lossy());
-                        continue;
+                    // Coalesce a burst of events until it settles down
+                    let deadline = Instant::now() + WATCH_MAX_DELAY;
+                    while Instant::now() < deadline {
+                        match tokio::time::timeout(WATCH_DEBOUNCE, rx.recv()).await {
+                            Ok(Some(event)) => batch.add(event),
+                            _ => break,
+                        }
                     }
 
-                    // Upload the file in parallel, limited by the semaphore
-                    let path_clone = path.to_path_buf();
-                    let permit = self.semaphore.clone().acquire_owned().await.unwrap();
-                    let client_clone = self.client.clone();
-                    let token_clone = self.token.clone();
-                    let db_clone = self.db.clone();
-
-                    let task = task::spawn(async move {
-                        Self::upload_file(db_clone, token_clone, &path_clone, client_clone).await;
-                        drop(permit); // Release the semaphore permit
-                    });
-                    tasks.push(task);
+                    let _sync = self.sync_lock.lock().await;
+                    let result = if batch.rescan {
+                        log::warn!("Watch events lost, running full rescan");
+                        self.scan_roots().await
+                    } else if batch.paths.is_empty() {
+                        Ok(())
+                    } else {
+                        self.sync_paths(batch.paths.into_iter().collect()).await
+                    };
+
+                    if let Err(e) = result {
+                        log::error!("Error syncing changes: {}", e);
+                    }
                 }
             }
         }
+    }
 
-        // Await all tasks
-        // join_all(tasks).await;
-        for task in tasks {
-            task.await.unwrap(); // Wait for all tasks to complete
+    // stop makes the loops return, uploads already started finish
+    pub fn stop(&self) {
+        self.control.stop();
+    }
+
+    // close waits for the uploads still in progress and writes the state
+    // database to disk
+    pub async fn close(&self) -> Result<(), std::io::Error> {
+        let _permits = self
+            .semaphore
+            .acquire_many(self.workers as u32)
+            .await
+            .map_err(std::io::Error::other)?;
+
+        let keyspace = self.keyspace.clone();
+        spawn_blocking(move || keyspace.persist(PersistMode::SyncAll))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+
+    // progress returns the progress of the agent's uploads and downloads
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    // run_bandwidth_reload applies the bandwidth settings of the config file
+    // while the agent runs, independent of how long a scan takes
+    pub async fn run_bandwidth_reload(&self) {
+        if self.config_path.is_none() {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(BANDWIDTH_RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.reload_bandwidth(),
+                _ = self.control.stopped() => break,
+            }
         }
-        Ok(())
     }
 
-    async fn create_file(
-        token: String,
-        path: &Path,
-        file_type: FileType,
-        client: Arc<Client>,
-    ) -> Result<FileResponse, std::io::Error> {
-        let data = FileCreateRequest {
-            name: path.file_name().unwrap().to_string_lossy().to_string(),
-            directory: path.parent().unwrap().to_string_lossy().to_string(),
-            file_type,
-            status: FileStatus::OPEN,
+    // reload_bandwidth applies the bandwidth settings of the config file once it changed
+    fn reload_bandwidth(&self) {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => return,
         };
 
-        println!("Creating: {:?}", data);
+        let modified = modified_at(path);
+        {
+            let mut config_modified = self.config_modified.lock().unwrap();
+            if *config_modified == modified {
+                return;
+            }
+            *config_modified = modified;
+        }
+
+        let settings = AgentConfig::from_file(path).and_then(|mut config| {
+            self.bandwidth_override.apply(&mut config);
+            BandwidthSettings::parse(config.bandwidth_limit, &config.bandwidth_schedule)
+        });
+        match settings {
+            Ok(settings) if settings != self.throttle.settings() => {
+                log::info!("Bandwidth settings reloaded: {:?}", settings);
+                self.throttle.set(settings);
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Error reloading {}: {}", path.to_string_lossy(), e),
+        }
+    }
+
+    // drain_queue retries the queued operations that are due
+    pub async fn drain_queue(&self) -> Result<(), std::io::Error> {
+        let mut paths = Vec::new();
 
-        let res = client
-            .post("http://localhost:8000/v1/files")
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("bearer {}", token))
-            .json(&data)
-            .send()
-            .await;
+        for entry in self.queue.due().await? {
+            // Nothing left to retry for files that vanished before ever being synced
+            let indexed = Self::lookup(self.db.clone(), &entry.path).await?.is_some();
+            if !entry.path.exists() && !indexed {
+                self.queue.remove(&entry.path).await?;
+                continue;
+            }
 
-        match res {
-            Ok(response) if response.status().is_success() => {
-                println!("Created: {}", path.file_name().unwrap().to_string_lossy());
+            // Nor for paths that got ignored in the meantime
+            let ignored = self.ignore.is_ignored(&entry.path, entry.path.is_dir());
+            if ignored && !(self.remove_ignored && indexed) {
+                self.queue.remove(&entry.path).await?;
+                continue;
+            }
+            paths.push(entry.path);
+        }
 
-                match response.json::<FileResponse>().await {
-                    Ok(file) => Ok(file),
-                    Err(e) => {
-                        eprintln!("Error parsing response: {}", e);
-                        Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            e.to_string(),
-                        ))
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Retrying {} queued operations", paths.len());
+        self.sync_paths(paths).await
+    }
+
+    // scan_roots syncs all synced directories
+    pub async fn scan_roots(&self) -> Result<(), std::io::Error> {
+        self.control.wait_resumed().await;
+        self.control.start_scan();
+        let result = self.sync_paths(self.roots.dirs()).await;
+        self.control.finish_scan(result.as_ref().err());
+
+        result
+    }
+
+    // sync_paths brings the server in line with the given paths and everything below them
+    pub async fn sync_paths(&self, paths: Vec<PathBuf>) -> Result<(), std::io::Error> {
+        self.control.wait_resumed().await;
+        let roots = self.collapse_paths(paths);
+        let walk = self.planner.walk(&roots).await?;
+        let mut tasks = Vec::new();
+
+        for step in walk.steps {
+            // The removals are left for the next pass
+            if self.control.is_stopping() {
+                for task in tasks {
+                    if let Err(e) = task.await {
+                        log::error!("Upload task failed: {}", e);
                     }
                 }
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "The agent is stopping",
+                ));
+            }
+
+            let result = match step {
+                Step::Directory {
+                    path,
+                    metadata,
+                    attributes,
+                    entry,
+                } => {
+                    self.sync_directory(&path, &metadata, &attributes, entry)
+                        .await
+                }
+                Step::Link {
+                    path,
+                    metadata,
+                    attributes,
+                    entry,
+                } => {
+                    self.sync_symlink(&path, &metadata, &attributes, entry)
+                        .await
+                }
+                Step::Attributes {
+                    path,
+                    attributes,
+                    entry,
+                } => self.sync_attributes(&path, &attributes, entry).await,
+                Step::Modified { path, entry } => {
+                    tasks.push(self.spawn_upload(path, Some(entry)).await);
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                log::error!("Error updating the retry queue: {}", e);
             }
-            Ok(response) => {
-                eprintln!(
-                    "Failed to create {}: HTTP {}",
-                    path.file_name().unwrap().to_string_lossy(),
-                    response.status()
-                );
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to create file",
-                ))
+        }
+
+        let mut removed = walk.removed;
+        let mut pending = self.control.pending(walk.created.len());
+        for path in walk.created {
+            pending.take();
+
+            // A new file with the same content as a removed one is a move
+            if let Some(index) = Planner::find_moved(&path, &removed).await? {
+                let (old_path, entry) = removed.swap_remove(index);
+                if let Err(e) = self.move_file(&old_path, entry, &path).await {
+                    self.queue
+                        .record_failure(&old_path, Operation::Move, &e)
+                        .await?;
+                    self.queue
+                        .record_failure(&path, Operation::Move, &e)
+                        .await?;
+                } else {
+                    self.queue.remove(&old_path).await?;
+                    self.queue.remove(&path).await?;
+                }
+                continue;
+            }
+
+            tasks.push(self.spawn_upload(path, None).await);
+        }
+
+        // Wait for all tasks to complete
+        for task in tasks {
+            if let Err(e) = task.await {
+                log::error!("Upload task failed: {}", e);
+            }
+        }
+
+        for (path, entry) in removed {
+            match self.remove_file(&path, entry).await {
+                Ok(()) => self.queue.remove(&path).await?,
+                Err(e) => {
+                    self.queue
+                        .record_failure(&path, Operation::Delete, &e)
+                        .await?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // sync_directory creates a directory on the server or updates its attributes
+    async fn sync_directory(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        attributes: &FileMetadata,
+        entry: Option<IndexEntry>,
+    ) -> Result<(), std::io::Error> {
+        let result = match entry {
+            Some(entry) => {
+                Self::update_metadata(&self.api, path, entry.file, FileType::DIRECTORY, attributes)
+                    .await
+            }
+            None => {
+                self.api
+                    .create_file(path, FileType::DIRECTORY, Some(attributes))
+                    .await
+            }
+        };
+
+        match result {
+            Ok(file) => {
+                Self::register_upload(self.db.clone(), path, IndexEntry::new(file, metadata, None))
+                    .await?;
+                self.queue.remove(path).await
             }
             Err(e) => {
-                eprintln!(
-                    "Error creating {}: {}",
-                    path.file_name().unwrap().to_string_lossy(),
-                    e
-                );
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                ))
+                self.queue
+                    .record_failure(path, Operation::CreateDirectory, &e)
+                    .await
             }
         }
     }
 
-    async fn update_file(
-        token: String,
-        file_id: Uuid,
-        data: FileUpdateRequest,
-        client: Arc<Client>,
-    ) -> Result<FileResponse, std::io::Error> {
-        let res = client
-            .put(format!("http://localhost:8000/v1/files/{}", file_id))
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("bearer {}", token))
-            .json(&data)
-            .send()
-            .await;
-
-        match res {
-            Ok(response) if response.status().is_success() => {
-                println!("Updated: {:?}", data);
-
-                match response.json::<FileResponse>().await {
-                    Ok(file) => Ok(file),
-                    Err(e) => {
-                        eprintln!("Error parsing response: {}", e);
-                        Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            e.to_string(),
-                        ))
-                    }
+    // sync_symlink stores a link as a link, its target is kept in the metadata.
+    // A path synced as what the link pointed to before is replaced.
+    async fn sync_symlink(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        attributes: &FileMetadata,
+        entry: Option<IndexEntry>,
+    ) -> Result<(), std::io::Error> {
+        let result = match entry {
+            Some(entry) if entry.file.file_type == FileType::SYMLINK.to_string() => {
+                Self::update_metadata(&self.api, path, entry.file, FileType::SYMLINK, attributes)
+                    .await
+            }
+            Some(entry) => match self.api.delete_file(entry.file.id).await {
+                Ok(()) => {
+                    self.api
+                        .create_file(path, FileType::SYMLINK, Some(attributes))
+                        .await
                 }
+                Err(e) => Err(e),
+            },
+            None => {
+                self.api
+                    .create_file(path, FileType::SYMLINK, Some(attributes))
+                    .await
             }
-            Ok(response) => {
-                eprintln!("Failed to update {:?}: HTTP {}", data, response.status());
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to update file",
-                ))
+        };
+
+        match result {
+            Ok(file) => {
+                Self::register_upload(self.db.clone(), path, IndexEntry::new(file, metadata, None))
+                    .await?;
+                self.queue.remove(path).await
             }
-            Err(e) => {
-                eprintln!("Error updating {:?}: {}", data, e);
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                ))
+            Err(e) => self.queue.record_failure(path, Operation::Upload, &e).await,
+        }
+    }
+
+    // sync_attributes updates the attributes of a file whose content is unchanged
+    async fn sync_attributes(
+        &self,
+        path: &Path,
+        attributes: &FileMetadata,
+        entry: IndexEntry,
+    ) -> Result<(), std::io::Error> {
+        let result = Self::update_metadata(
+            &self.api,
+            path,
+            entry.file.clone(),
+            FileType::FILE,
+            attributes,
+        )
+        .await;
+
+        match result {
+            Ok(file) => {
+                Self::register_upload(self.db.clone(), path, IndexEntry { file, ..entry }).await?;
+                self.queue.remove(path).await
             }
+            Err(e) => self.queue.record_failure(path, Operation::Upload, &e).await,
         }
     }
 
-    // Function to upload a file to the server
+    // update_metadata replaces the attributes stored with a file whose content is
+    // unchanged. Files synced before prefixes existed move to their prefix.
+    async fn update_metadata(
+        api: &ApiClient,
+        path: &Path,
+        file: FileResponse,
+        file_type: FileType,
+        attributes: &FileMetadata,
+    ) -> Result<FileResponse, std::io::Error> {
+        log::info!("Updating metadata: {}", file.name);
+
+        // directories are created open and stay that way
+        let status = match file_type {
+            FileType::DIRECTORY => FileStatus::OPEN,
+            _ => FileStatus::CLOSED,
+        };
+
+        api.update_file(
+            file.id,
+            FileUpdateRequest {
+                name: file.name,
+                directory: api.remote_directory(path)?,
+                file_type,
+                status,
+                created_at: file.created_at,
+                modified_at: file.modified_at,
+                checksum: file.checksum,
+                key_id: file.key_id,
+                metadata: Some(attributes.to_json()),
+            },
+        )
+        .await
+    }
+
+    // collapse_paths drops paths outside the roots and paths nested in another one
+    fn collapse_paths(&self, mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        paths.sort();
+
+        let mut roots: Vec<PathBuf> = Vec::new();
+        for path in paths {
+            if !self.roots.contains(&path) {
+                continue;
+            }
+            if roots.iter().any(|root| path.starts_with(root)) {
+                continue;
+            }
+            roots.push(path);
+        }
+
+        roots
+    }
+
+    // spawn_upload uploads the file in parallel, limited by the semaphore
+    pub(super) async fn spawn_upload(
+        &self,
+        path: PathBuf,
+        previous: Option<IndexEntry>,
+    ) -> task::JoinHandle<()> {
+        let waiting = self.control.pending(1);
+        self.control.wait_resumed().await;
+        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+        drop(waiting);
+        self.control.upload_started(&path);
+        let client_clone = self.client.clone();
+        let api_clone = self.api.clone();
+        let db_clone = self.db.clone();
+        let queue_clone = self.queue.clone();
+        let multipart_clone = self.multipart.clone();
+        let chunked_clone = self.chunked.clone();
+        let compression_clone = self.compression.clone();
+        let throttle_clone = self.throttle.clone();
+        let control_clone = self.control.clone();
+        let transfer = self.progress.start(
+            &path.file_name().unwrap().to_string_lossy(),
+            fs::metadata(&path).map_or(0, |metadata| metadata.len()),
+        );
+
+        task::spawn(async move {
+            let upload = Self::upload_file(
+                db_clone,
+                api_clone,
+                &path,
+                client_clone,
+                multipart_clone,
+                chunked_clone,
+                compression_clone,
+                throttle_clone,
+                previous,
+            );
+
+            let result = match transfer.scope(upload).await {
+                Ok(()) => queue_clone.remove(&path).await,
+                Err(e) => {
+                    log::error!("Error syncing {}: {}", path.to_string_lossy(), e);
+                    queue_clone
+                        .record_failure(&path, Operation::Upload, &e)
+                        .await
+                }
+            };
+            if let Err(e) = result {
+                log::error!("Error updating retry queue: {}", e);
+            }
+            control_clone.upload_finished(&path);
+            drop(permit); // Release the semaphore permit
+        })
+    }
+
+    // indexed_entries returns all index entries at or under the roots
+    pub(super) async fn indexed_entries(
+        &self,
+        roots: &[PathBuf],
+    ) -> Result<Vec<(PathBuf, IndexEntry)>, std::io::Error> {
+        self.planner.indexed_entries(roots).await
+    }
+
+    // move_file renames the server-side file instead of uploading it again
+    async fn move_file(
+        &self,
+        old_path: &Path,
+        entry: IndexEntry,
+        path: &Path,
+    ) -> Result<(), std::io::Error> {
+        log::info!(
+            "Moving: {} -> {}",
+            old_path.to_string_lossy(),
+            path.to_string_lossy()
+        );
+
+        let metadata = fs::metadata(path)?;
+        let key_id = entry.file.key_id.clone();
+        let checksum = entry
+            .hash
+            .as_deref()
+            .map(|hash| self.api.checksum(key_id.as_deref(), hash))
+            .transpose()?;
+        let file = self
+            .api
+            .update_file(
+                entry.file.id,
+                FileUpdateRequest {
+                    name: path.file_name().unwrap().to_string_lossy().to_string(),
+                    directory: self.api.remote_directory(path)?,
+                    file_type: FileType::FILE,
+                    status: FileStatus::CLOSED,
+                    created_at: entry.file.created_at,
+                    modified_at: modified_time(&metadata),
+                    checksum,
+                    key_id,
+                    metadata: Some(FileMetadata::capture(path, &metadata)?.to_json()),
+                },
+            )
+            .await?;
+
+        Self::register_upload(
+            self.db.clone(),
+            path,
+            IndexEntry::new(file, &metadata, entry.hash),
+        )
+        .await?;
+        Self::unregister_upload(self.db.clone(), old_path).await
+    }
+
+    // remove_file deletes a file or directory that no longer exists locally
+    async fn remove_file(&self, path: &Path, entry: IndexEntry) -> Result<(), std::io::Error> {
+        log::info!("Deleting: {}", path.to_string_lossy());
+
+        self.api.delete_file(entry.file.id).await?;
+        Self::unregister_upload(self.db.clone(), path).await
+    }
+
+    // Function to upload a new or modified file to the server
+    #[allow(clippy::too_many_arguments)]
     async fn upload_file(
         db: PartitionHandle,
-        token: String,
+        api: Arc<ApiClient>,
         path: &Path,
         client: Arc<Client>,
+        multipart: MultipartUploads,
+        chunked: ChunkedUploads,
+        compression: Compression,
+        throttle: Throttle,
+        previous: Option<IndexEntry>,
     ) -> Result<(), std::io::Error> {
         let file_name = path.file_name().unwrap().to_string_lossy();
-        println!("Uploading: {}", file_name);
-        let file: FileResponse =
-            Self::create_file(token.clone(), &path, FileType::FILE, client.clone()).await?;
-        let file_clone = file.clone();
+        let metadata = fs::metadata(path)?;
+        let mtime = modified_time(&metadata);
+        let attributes = FileMetadata::capture(path, &metadata)?;
+
+        let path_clone = path.to_path_buf();
+        let hash = spawn_blocking(move || file_digest(&path_clone))
+            .await
+            .expect("join failed")?;
+
+        if let Some(entry) = &previous {
+            if entry.hash.as_deref() == Some(hash.as_str()) {
+                // Only the metadata changed, there is nothing to upload
+                log::debug!("Content unchanged: {}", file_name);
+                let file = match attributes.matches(entry.file.metadata.as_deref()) {
+                    true => entry.file.clone(),
+                    false => {
+                        Self::update_metadata(
+                            &api,
+                            path,
+                            entry.file.clone(),
+                            FileType::FILE,
+                            &attributes,
+                        )
+                        .await?
+                    }
+                };
+                return Self::register_upload(
+                    db,
+                    path,
+                    IndexEntry::new(file, &metadata, Some(hash)),
+                )
+                .await;
+            }
+        }
+
+        // Chunks are deduplicated by their plaintext hash, so encrypted files are not chunked
+        let use_chunks = chunked.applies(metadata.len()) && !api.encrypts();
+
+        let path_clone = path.to_path_buf();
+        let compression_clone = compression.clone();
+        let size = metadata.len();
+        let codec = spawn_blocking(move || compression_clone.codec(&path_clone, size))
+            .await
+            .expect("join failed")?;
+
+        // Chunks are compressed one by one, whole uploads from a compressed copy. The
+        // copy has the content as it was while compressing, which is what gets recorded.
+        let (source, hash) = match codec {
+            Some(_) if !use_chunks => {
+                let path_clone = path.to_path_buf();
+                let compression_clone = compression.clone();
+                let (source, compressed_hash) =
+                    spawn_blocking(move || compression_clone.compress_file(&path_clone))
+                        .await
+                        .expect("join failed")?;
+                if compressed_hash != hash {
+                    log::debug!("Changed while compressing: {}", file_name);
+                }
+                (source, compressed_hash)
+            }
+            _ => (UploadSource::plain(path, metadata.len()), hash),
+        };
+
+        // Large files go up in parts, an interrupted upload continues where it stopped
+        let use_multipart = !use_chunks && multipart.applies(source.size);
+        let resumed = if use_multipart {
+            multipart
+                .resume(&api, path, &metadata, &hash, &source)
+                .await?
+        } else {
+            None
+        };
+
+        let file: FileResponse = match (&resumed, previous) {
+            (Some(state), _) => {
+                log::info!("Resuming upload: {}", file_name);
+                state.file.clone()
+            }
+            (None, Some(entry)) => {
+                log::info!("Uploading modified: {}", file_name);
+                api.update_file(
+                    entry.file.id,
+                    FileUpdateRequest {
+                        name: entry.file.name,
+                        directory: api.remote_directory(path)?,
+                        file_type: FileType::FILE,
+                        status: FileStatus::OPEN,
+                        created_at: entry.file.created_at,
+                        modified_at: mtime,
+                        checksum: None,
+                        key_id: entry.file.key_id,
+                        metadata: Some(attributes.to_json()),
+                    },
+                )
+                .await?
+            }
+            (None, None) => {
+                log::info!("Uploading: {}", file_name);
+                let file = api
+                    .create_file(path, FileType::FILE, Some(&attributes))
+                    .await?;
+                Self::register_upload(db.clone(), path, IndexEntry::pending(file.clone())).await?;
+                file
+            }
+        };
+
+        // The server tells which key the file is encrypted with, if any
+        let key = match &file.key_id {
+            Some(key_id) => Some(api.key(key_id)?),
+            None => None,
+        };
+
+        if !use_chunks || key.is_some() {
+            progress::resize(match key {
+                Some(_) => encrypted_size(source.size),
+                None => source.size,
+            });
+        }
+
+        let file = if use_chunks && key.is_none() {
+            let compression = codec.map(|_| compression);
+            chunked
+                .upload(&api, &client, path, &file, &hash, mtime, compression)
+                .await?
+        } else {
+            let size = if use_multipart {
+                multipart
+                    .upload(
+                        &api, &client, path, &source, &file, &metadata, &hash, key, resumed,
+                    )
+                    .await?
+            } else {
+                let url = file.upload_presigned_url.clone();
+                Self::put_file(&client, &throttle, path, &source, url, key).await?
+            };
+
+            log::info!("Uploaded: {}", file_name);
+
+            api.complete_upload(
+                file.id,
+                &FileCompleteRequest {
+                    modified_at: mtime,
+                    checksum: Some(api.checksum(file.key_id.as_deref(), &hash)?),
+                    size: size as i64,
+                    codec: source.codec.map(|codec| codec.name().to_string()),
+                },
+            )
+            .await?
+        };
+
+        Self::register_upload(
+            db.clone(),
+            path,
+            IndexEntry::new(file, &metadata, Some(hash)),
+        )
+        .await
+    }
+
+    // put_file streams the content of the file from the source, encrypted when a key
+    // is given, to the presigned upload URL in one request and returns the number of bytes sent
+    async fn put_file(
+        client: &Client,
+        throttle: &Throttle,
+        path: &Path,
+        source: &UploadSource,
+        url: Option<String>,
+        key: Option<Arc<FileKey>>,
+    ) -> Result<u64, std::io::Error> {
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let size = source.size;
 
-        match file.upload_presigned_url {
+        match url {
             Some(url) => {
-                // Simulate a file upload by sending a POST request
+                // Stream the file content to the presigned URL
+                let (body, length) = match key {
+                    Some(key) => {
+                        let sealer = Arc::new(ContentSealer::new(key, size));
+                        let length = encrypted_size(size);
+                        let body =
+                            encrypted_body(&source.path, sealer, size, 0, length, throttle).await?;
+                        (body, length)
+                    }
+                    None => file_body(&source.path, throttle).await?,
+                };
                 let result = client
                     .put(url)
-                    .body(fs::read(path).unwrap_or_default()) // Read the file content
+                    .header(CONTENT_LENGTH, length)
+                    .body(body)
                     .send()
                     .await;
 
                 match result {
-                    Ok(response) if response.status().is_success() => {
-                        println!("Uploaded: {}", file_name);
-
-                        Self::update_file(
-                            token,
-                            file.id,
-                            FileUpdateRequest {
-                                name: file.name,
-                                directory: file.directory,
-                                file_type: FileType::FILE,
-                                status: FileStatus::CLOSED,
-                                modified_at: file.modified_at,
-                                created_at: file.created_at,
-                            },
-                            client.clone(),
-                        )
-                        .await?;
-
-                        Self::register_upload(db.clone(), path, file_clone).await
-                    }
+                    Ok(response) if response.status().is_success() => Ok(length),
                     Ok(response) => {
-                        eprintln!("Failed to upload {}: HTTP {}", file_name, response.status());
-                        Ok(())
+                        log::error!("Failed to upload {}: HTTP {}", file_name, response.status());
+                        Err(std::io::Error::other(format!(
+                            "Failed to upload: HTTP {}",
+                            response.status()
+                        )))
                     }
                     Err(e) => {
-                        eprintln!("Error uploading {}: {}", file_name, e);
-                        Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                        log::error!("Error uploading {}: {}", file_name, e);
+                        Err(std::io::Error::other(e))
                     }
                 }
             }
             None => {
-                eprintln!("Error: No upload URL found for {}", file_name);
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No upload URL found",
-                ))
+                log::error!("Error: No upload URL found for {}", file_name);
+                Err(std::io::Error::other("No upload URL found"))
             }
         }
     }
 
-    async fn should_upload(db: PartitionHandle, path: &Path) -> bool {
+    // lookup returns the index entry recorded for the path, if any
+    pub(super) async fn lookup(
+        db: PartitionHandle,
+        path: &Path,
+    ) -> Result<Option<IndexEntry>, std::io::Error> {
         let path_clone = path.to_path_buf();
         let db_clone = db.clone();
 
         let item = spawn_blocking(move || db_clone.get(path_clone.to_string_lossy().as_bytes()))
             .await
             .expect("join failed")
-            .unwrap();
+            .map_err(std::io::Error::other)?;
 
         match item {
-            Some(item) => {
-                let file: Result<FileResponse, serde_json::Error> = serde_json::from_slice(&item);
-                match file {
-                    Ok(_) => {
-                        return false;
-                    }
-                    Err(e) => {
-                        eprintln!("Error parsing file: {}", e);
-                        return false;
-                    }
+            Some(item) => match IndexEntry::from_slice(&item) {
+                Ok(entry) => Ok(Some(entry)),
+                Err(e) => {
+                    log::error!("Error parsing file: {}", e);
+                    Ok(None)
                 }
-            }
-            None => {
-                return true;
-            }
+            },
+            None => Ok(None),
         }
     }
 
-    async fn register_upload(
+    pub(super) async fn register_upload(
         db: PartitionHandle,
         path: &Path,
-        file: FileResponse,
+        entry: IndexEntry,
     ) -> Result<(), std::io::Error> {
         let path_clone = path.to_path_buf();
         let db_clone = db.clone();
 
-        spawn_blocking(move || {
-            db_clone
-                .insert(path_clone, serde_json::to_string(&file).unwrap())
-                .unwrap()
-        })
-        .await
-        .expect("join failed");
+        let value = serde_json::to_string(&entry)?;
 
-        Ok(())
+        spawn_blocking(move || db_clone.insert(path_clone, value))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+
+    pub(super) async fn unregister_upload(
+        db: PartitionHandle,
+        path: &Path,
+    ) -> Result<(), std::io::Error> {
+        let path_clone = path.to_path_buf();
+        let db_clone = db.clone();
+
+        spawn_blocking(move || db_clone.remove(path_clone))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+}
+
+// modified_at returns the modification time of a file, if it can be read
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Changes collected while walking the file system
+// Paths touched by a burst of filesystem events
+#[derive(Default)]
+struct EventBatch {
+    paths: HashSet<PathBuf>,
+    rescan: bool,
+}
+
+impl EventBatch {
+    fn add(&mut self, event: notify::Result<notify::Event>) {
+        match event {
+            Ok(event) => {
+                if event.need_rescan() {
+                    self.rescan = true;
+                }
+                if !matches!(event.kind, EventKind::Access(_)) {
+                    self.paths.extend(event.paths);
+                }
+            }
+            Err(e) => {
+                log::error!("Watch error: {}", e);
+                self.rescan = true;
+            }
+        }
     }
 }
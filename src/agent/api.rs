@@ -0,0 +1,628 @@
+use crate::schema::file::{
+    FileCompleteRequest, FileCreateRequest, FileResponse, FileStatus, FileType, FileUpdateRequest,
+};
+use charybdis::types::Uuid;
+
+use crate::agent::crypto::{FileKey, Keyring};
+use crate::agent::metadata::FileMetadata;
+use crate::agent::roots::Roots;
+use crate::model::file::File;
+use crate::schema::chunk::{ChunksRequest, ChunksResponse, ManifestRequest, ManifestResponse};
+use crate::schema::file::{
+    FilesResponse, MultipartCompleteRequest, MultipartPartsRequest, MultipartPartsResponse,
+    MultipartUploadResponse,
+};
+use crate::schema::snapshot::{
+    SnapshotCreateRequest, SnapshotFilesRequest, SnapshotFilesResponse, SnapshotResponse,
+    SnapshotsResponse,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::sync::Arc;
+
+// FilePage is a page of a file listing. Rows that can't be decrypted are logged
+// and kept apart as stored, the next page starts after the last row either way.
+pub struct FilePage {
+    pub files: Vec<File>,
+    pub unreadable: Vec<File>,
+    pub last_id: Option<Uuid>,
+    pub last_updated_at: Option<DateTime<Utc>>,
+    pub is_last: bool,
+}
+
+// ApiClient talks to the memora server on behalf of the agent. Local paths are
+// sent relative to the prefix of their root. With a keyring, names are encrypted
+// before they are sent and decrypted in the responses.
+pub struct ApiClient {
+    client: Client,
+    server_url: String,
+    token: String,
+    keyring: Option<Arc<Keyring>>,
+    roots: Roots,
+}
+
+impl ApiClient {
+    pub fn new(
+        client: Client,
+        server_url: &str,
+        token: String,
+        keyring: Option<Arc<Keyring>>,
+        roots: Roots,
+    ) -> Self {
+        Self {
+            client,
+            server_url: server_url.trim_end_matches('/').to_string(),
+            token,
+            keyring,
+            roots,
+        }
+    }
+
+    // remote_directory returns the server-side directory of a local path
+    pub fn remote_directory(&self, path: &Path) -> Result<String, std::io::Error> {
+        self.roots.remote_directory(path)
+    }
+
+    // key returns the key a file was encrypted with
+    pub fn key(&self, key_id: &str) -> Result<Arc<FileKey>, std::io::Error> {
+        match &self.keyring {
+            Some(keyring) => keyring.key(key_id),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "The file is encrypted, a passphrase is required",
+            )),
+        }
+    }
+
+    // checksum converts a content hash into the checksum stored for a file with the given key
+    pub fn checksum(&self, key_id: Option<&str>, hash: &str) -> Result<String, std::io::Error> {
+        match key_id {
+            Some(key_id) => Ok(self.key(key_id)?.checksum(hash)),
+            None => Ok(hash.to_string()),
+        }
+    }
+
+    // encrypts reports whether new uploads are encrypted
+    pub fn encrypts(&self) -> bool {
+        self.active_key().is_some()
+    }
+
+    fn active_key(&self) -> Option<Arc<FileKey>> {
+        self.keyring.as_ref().and_then(|keyring| keyring.active())
+    }
+
+    // seal_update encrypts the names of an update. A re-opened file takes the
+    // key new uploads use, otherwise the key the file already has is kept.
+    fn seal_update(&self, data: FileUpdateRequest) -> Result<FileUpdateRequest, std::io::Error> {
+        let key = match data.status {
+            FileStatus::OPEN => self.active_key(),
+            FileStatus::CLOSED => match &data.key_id {
+                Some(key_id) => Some(self.key(key_id)?),
+                None => None,
+            },
+        };
+
+        Ok(match key {
+            Some(key) => FileUpdateRequest {
+                name: key.encrypt_path(&data.name),
+                directory: key.encrypt_path(&data.directory),
+                key_id: Some(key.id().to_string()),
+                metadata: data
+                    .metadata
+                    .as_deref()
+                    .map(|metadata| key.encrypt_metadata(metadata)),
+                ..data
+            },
+            None => FileUpdateRequest {
+                key_id: None,
+                ..data
+            },
+        })
+    }
+
+    // open_response decrypts the names of a file returned by the server
+    fn open_response(&self, file: FileResponse) -> Result<FileResponse, std::io::Error> {
+        match &file.key_id {
+            Some(key_id) => {
+                let key = self.key(key_id)?;
+                Ok(FileResponse {
+                    name: key.decrypt_path(&file.name)?,
+                    directory: key.decrypt_path(&file.directory)?,
+                    metadata: file
+                        .metadata
+                        .as_deref()
+                        .map(|metadata| key.decrypt_metadata(metadata))
+                        .transpose()?,
+                    ..file
+                })
+            }
+            None => Ok(file),
+        }
+    }
+
+    fn open_file(&self, file: File) -> Result<File, std::io::Error> {
+        match &file.key_id {
+            Some(key_id) => {
+                let key = self.key(key_id)?;
+                Ok(File {
+                    name: key.decrypt_path(&file.name)?,
+                    directory: key.decrypt_path(&file.directory)?,
+                    metadata: file
+                        .metadata
+                        .as_deref()
+                        .map(|metadata| key.decrypt_metadata(metadata))
+                        .transpose()?,
+                    ..file
+                })
+            }
+            None => Ok(file),
+        }
+    }
+
+    // open_page decrypts a page of listed files
+    fn open_page(&self, objects: Vec<File>, limit: i32) -> FilePage {
+        let mut page = FilePage {
+            files: Vec::new(),
+            unreadable: Vec::new(),
+            last_id: objects.last().map(|file| file.id),
+            last_updated_at: objects.last().and_then(|file| file.updated_at),
+            is_last: objects.len() < limit as usize,
+        };
+
+        for file in objects {
+            match self.open_file(file.clone()) {
+                Ok(file) => page.files.push(file),
+                Err(e) => {
+                    log::warn!("Skipping unreadable file {}: {}", file.id, e);
+                    page.unreadable.push(file);
+                }
+            }
+        }
+
+        page
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server_url, path)
+    }
+
+    // create_file records a new file with its metadata. Files are open until their
+    // content is uploaded, links have no content and are closed right away.
+    pub async fn create_file(
+        &self,
+        path: &Path,
+        file_type: FileType,
+        metadata: Option<&FileMetadata>,
+    ) -> Result<FileResponse, std::io::Error> {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let directory = self.remote_directory(path)?;
+        let metadata = metadata.map(|metadata| metadata.to_json());
+        let status = match file_type {
+            FileType::SYMLINK => FileStatus::CLOSED,
+            _ => FileStatus::OPEN,
+        };
+        let data = match self.active_key() {
+            Some(key) => FileCreateRequest {
+                name: key.encrypt_path(&name),
+                directory: key.encrypt_path(&directory),
+                file_type,
+                status,
+                key_id: Some(key.id().to_string()),
+                metadata: metadata.map(|metadata| key.encrypt_metadata(&metadata)),
+            },
+            None => FileCreateRequest {
+                name,
+                directory,
+                file_type,
+                status,
+                key_id: None,
+                metadata,
+            },
+        };
+
+        log::debug!("Creating: {:?}", data);
+
+        let res = self
+            .client
+            .post(self.url("/v1/files"))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("bearer {}", self.token))
+            .json(&data)
+            .send()
+            .await;
+
+        match res {
+            Ok(response) if response.status().is_success() => {
+                log::info!("Created: {}", path.file_name().unwrap().to_string_lossy());
+
+                match response.json::<FileResponse>().await {
+                    Ok(file) => self.open_response(file),
+                    Err(e) => {
+                        log::error!("Error parsing response: {}", e);
+                        Err(std::io::Error::other(e.to_string()))
+                    }
+                }
+            }
+            Ok(response) => {
+                log::error!(
+                    "Failed to create {}: HTTP {}",
+                    path.file_name().unwrap().to_string_lossy(),
+                    response.status()
+                );
+                Err(std::io::Error::other("Failed to create file"))
+            }
+            Err(e) => {
+                log::error!(
+                    "Error creating {}: {}",
+                    path.file_name().unwrap().to_string_lossy(),
+                    e
+                );
+                Err(std::io::Error::other(e.to_string()))
+            }
+        }
+    }
+
+    pub async fn update_file(
+        &self,
+        file_id: Uuid,
+        data: FileUpdateRequest,
+    ) -> Result<FileResponse, std::io::Error> {
+        let data = self.seal_update(data)?;
+        let res = self
+            .client
+            .put(self.url(&format!("/v1/files/{}", file_id)))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("bearer {}", self.token))
+            .json(&data)
+            .send()
+            .await;
+
+        match res {
+            Ok(response) if response.status().is_success() => {
+                log::debug!("Updated: {:?}", data);
+
+                match response.json::<FileResponse>().await {
+                    Ok(file) => self.open_response(file),
+                    Err(e) => {
+                        log::error!("Error parsing response: {}", e);
+                        Err(std::io::Error::other(e.to_string()))
+                    }
+                }
+            }
+            Ok(response) => {
+                log::error!("Failed to update {:?}: HTTP {}", data, response.status());
+                Err(std::io::Error::other("Failed to update file"))
+            }
+            Err(e) => {
+                log::error!("Error updating {:?}: {}", data, e);
+                Err(std::io::Error::other(e.to_string()))
+            }
+        }
+    }
+
+    pub async fn delete_file(&self, file_id: Uuid) -> Result<(), std::io::Error> {
+        let res = self
+            .client
+            .delete(self.url(&format!("/v1/files/{}", file_id)))
+            .header("Authorization", format!("bearer {}", self.token))
+            .send()
+            .await;
+
+        match res {
+            Ok(response) if response.status().is_success() => {
+                log::info!("Deleted: {}", file_id);
+                Ok(())
+            }
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => {
+                // Already gone on the server side
+                Ok(())
+            }
+            Ok(response) => {
+                log::error!("Failed to delete {}: HTTP {}", file_id, response.status());
+                Err(std::io::Error::other("Failed to delete file"))
+            }
+            Err(e) => {
+                log::error!("Error deleting {}: {}", file_id, e);
+                Err(std::io::Error::other(e.to_string()))
+            }
+        }
+    }
+
+    // list_files returns a page of the user's files after last_id
+    pub async fn list_files(
+        &self,
+        last_id: Option<Uuid>,
+        limit: i32,
+    ) -> Result<FilePage, std::io::Error> {
+        let mut request = self
+            .client
+            .get(self.url("/v1/files"))
+            .query(&[("limit", limit.to_string())]);
+        if let Some(last_id) = last_id {
+            request = request.query(&[("last_id", last_id.to_string())]);
+        }
+
+        let files: FilesResponse = self.send_json(request, "list files").await?;
+        Ok(self.open_page(files.objects, limit))
+    }
+
+    // list_changed_files returns a page of the files changed on the server after
+    // `updated_after`, oldest change first. The next page continues from the
+    // `updated_at` and id of the last file.
+    pub async fn list_changed_files(
+        &self,
+        updated_after: DateTime<Utc>,
+        last_id: Option<Uuid>,
+        limit: i32,
+    ) -> Result<FilePage, std::io::Error> {
+        let mut request = self.client.get(self.url("/v1/files")).query(&[
+            ("limit", limit.to_string()),
+            (
+                "updated_after",
+                updated_after.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ),
+        ]);
+        if let Some(last_id) = last_id {
+            request = request.query(&[("last_id", last_id.to_string())]);
+        }
+
+        let files: FilesResponse = self.send_json(request, "list changed files").await?;
+        Ok(self.open_page(files.objects, limit))
+    }
+
+    pub async fn get_file(&self, file_id: Uuid) -> Result<FileResponse, std::io::Error> {
+        let request = self.client.get(self.url(&format!("/v1/files/{}", file_id)));
+
+        let file = self.send_json(request, &format!("get {}", file_id)).await?;
+        self.open_response(file)
+    }
+
+    // complete_upload asks the server to check the uploaded object and close the file
+    pub async fn complete_upload(
+        &self,
+        file_id: Uuid,
+        data: &FileCompleteRequest,
+    ) -> Result<FileResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/files/{}/complete", file_id)))
+            .json(data);
+
+        let file = self.send_json(request, "complete upload").await?;
+        self.open_response(file)
+    }
+
+    // find_missing_chunks returns the chunks the server does not have, with upload URLs
+    pub async fn find_missing_chunks(
+        &self,
+        data: &ChunksRequest,
+    ) -> Result<ChunksResponse, std::io::Error> {
+        let request = self.client.post(self.url("/v1/chunks/missing")).json(data);
+
+        self.send_json(request, "find missing chunks").await
+    }
+
+    // commit_manifest closes the file with the chunks its content is made of
+    pub async fn commit_manifest(
+        &self,
+        file_id: Uuid,
+        data: &ManifestRequest,
+    ) -> Result<FileResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/files/{}/manifest", file_id)))
+            .json(data);
+
+        let file = self.send_json(request, "commit manifest").await?;
+        self.open_response(file)
+    }
+
+    pub async fn get_manifest(&self, file_id: Uuid) -> Result<ManifestResponse, std::io::Error> {
+        let request = self
+            .client
+            .get(self.url(&format!("/v1/files/{}/manifest", file_id)));
+
+        self.send_json(request, &format!("get manifest of {}", file_id))
+            .await
+    }
+
+    pub async fn create_multipart_upload(
+        &self,
+        file_id: Uuid,
+    ) -> Result<MultipartUploadResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/files/{}/multipart", file_id)));
+
+        self.send_json(request, "start multipart upload").await
+    }
+
+    pub async fn presign_multipart_parts(
+        &self,
+        file_id: Uuid,
+        data: &MultipartPartsRequest,
+    ) -> Result<MultipartPartsResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/files/{}/multipart/parts", file_id)))
+            .json(data);
+
+        self.send_json(request, "presign multipart parts").await
+    }
+
+    pub async fn complete_multipart_upload(
+        &self,
+        file_id: Uuid,
+        data: &MultipartCompleteRequest,
+    ) -> Result<(), std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/files/{}/multipart/complete", file_id)))
+            .json(data);
+
+        self.send_json::<serde_json::Value>(request, "complete multipart upload")
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn abort_multipart_upload(
+        &self,
+        file_id: Uuid,
+        upload_id: &str,
+    ) -> Result<(), std::io::Error> {
+        let request = self
+            .client
+            .delete(self.url(&format!("/v1/files/{}/multipart", file_id)))
+            .query(&[("upload_id", upload_id)]);
+
+        self.send_json::<serde_json::Value>(request, "abort multipart upload")
+            .await
+            .map(|_| ())
+    }
+
+    // create_snapshot starts a snapshot, it is listed as incomplete until completed
+    pub async fn create_snapshot(&self, name: &str) -> Result<SnapshotResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url("/v1/snapshots"))
+            .json(&SnapshotCreateRequest {
+                name: name.to_string(),
+            });
+
+        self.send_json(request, "create snapshot").await
+    }
+
+    // add_snapshot_files captures the current version of the files in the snapshot
+    pub async fn add_snapshot_files(
+        &self,
+        snapshot_id: Uuid,
+        file_ids: Vec<Uuid>,
+    ) -> Result<SnapshotFilesResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/snapshots/{}/files", snapshot_id)))
+            .json(&SnapshotFilesRequest { file_ids });
+
+        self.send_json(request, "add snapshot files").await
+    }
+
+    pub async fn complete_snapshot(
+        &self,
+        snapshot_id: Uuid,
+    ) -> Result<SnapshotResponse, std::io::Error> {
+        let request = self
+            .client
+            .post(self.url(&format!("/v1/snapshots/{}/complete", snapshot_id)));
+
+        self.send_json(request, "complete snapshot").await
+    }
+
+    pub async fn list_snapshots(&self) -> Result<Vec<SnapshotResponse>, std::io::Error> {
+        let request = self.client.get(self.url("/v1/snapshots"));
+
+        let snapshots: SnapshotsResponse = self.send_json(request, "list snapshots").await?;
+        Ok(snapshots.objects)
+    }
+
+    // list_snapshot_files returns a page of the files captured by the snapshot after last_id
+    pub async fn list_snapshot_files(
+        &self,
+        snapshot_id: Uuid,
+        last_id: Option<Uuid>,
+        limit: i32,
+    ) -> Result<FilePage, std::io::Error> {
+        let mut request = self
+            .client
+            .get(self.url(&format!("/v1/snapshots/{}/files", snapshot_id)))
+            .query(&[("limit", limit.to_string())]);
+        if let Some(last_id) = last_id {
+            request = request.query(&[("last_id", last_id.to_string())]);
+        }
+
+        let files: FilesResponse = self.send_json(request, "list snapshot files").await?;
+        Ok(self.open_page(files.objects, limit))
+    }
+
+    pub async fn get_snapshot_file(
+        &self,
+        snapshot_id: Uuid,
+        file_id: Uuid,
+    ) -> Result<FileResponse, std::io::Error> {
+        let request = self
+            .client
+            .get(self.url(&format!("/v1/snapshots/{}/files/{}", snapshot_id, file_id)));
+
+        let file = self
+            .send_json(
+                request,
+                &format!("get {} of snapshot {}", file_id, snapshot_id),
+            )
+            .await?;
+        self.open_response(file)
+    }
+
+    pub async fn get_snapshot_manifest(
+        &self,
+        snapshot_id: Uuid,
+        file_id: Uuid,
+    ) -> Result<ManifestResponse, std::io::Error> {
+        let request = self.client.get(self.url(&format!(
+            "/v1/snapshots/{}/files/{}/manifest",
+            snapshot_id, file_id
+        )));
+
+        self.send_json(
+            request,
+            &format!("get manifest of {} in snapshot {}", file_id, snapshot_id),
+        )
+        .await
+    }
+
+    pub async fn delete_snapshot(&self, snapshot_id: Uuid) -> Result<(), std::io::Error> {
+        let request = self
+            .client
+            .delete(self.url(&format!("/v1/snapshots/{}", snapshot_id)));
+
+        self.send_json::<serde_json::Value>(request, &format!("delete snapshot {}", snapshot_id))
+            .await
+            .map(|_| ())
+    }
+
+    // send_json authenticates the request and decodes a JSON response body
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+        action: &str,
+    ) -> Result<T, std::io::Error> {
+        let res = request
+            .header("Authorization", format!("bearer {}", self.token))
+            .send()
+            .await;
+
+        match res {
+            Ok(response) if response.status().is_success() => {
+                response.json::<T>().await.map_err(|e| {
+                    log::error!("Error parsing response: {}", e);
+                    std::io::Error::other(e.to_string())
+                })
+            }
+            Ok(response) => {
+                log::error!("Failed to {}: HTTP {}", action, response.status());
+                let kind = match response.status() {
+                    reqwest::StatusCode::NOT_FOUND => std::io::ErrorKind::NotFound,
+                    _ => std::io::ErrorKind::Other,
+                };
+                Err(std::io::Error::new(
+                    kind,
+                    format!("Failed to {}: HTTP {}", action, response.status()),
+                ))
+            }
+            Err(e) => {
+                log::error!("Error trying to {}: {}", action, e);
+                Err(std::io::Error::other(e.to_string()))
+            }
+        }
+    }
+}
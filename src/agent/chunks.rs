@@ -0,0 +1,303 @@
+use crate::agent::api::ApiClient;
+use crate::agent::compress::{Codec, Compression, Decompressor};
+use crate::agent::progress;
+use crate::agent::restore::Version;
+use crate::agent::throttle::Throttle;
+use crate::agent::transfer::buffer_body;
+use crate::schema::chunk::{ChunkRef, ChunksRequest, ManifestRequest};
+use crate::schema::file::FileResponse;
+
+use charybdis::types::Uuid;
+use chrono::{DateTime, Utc};
+use fastcdc::v2020::StreamCDC;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio::task::spawn_blocking;
+
+// Bounds of the content-defined chunks. Cut points depend on the content around
+// them, so an edit only changes the chunks it touches.
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+// Chunks looked up on the server per request
+const LOOKUP_BATCH: usize = 100;
+
+// A chunk of a local file, hash and size are of the chunk as it is stored
+#[derive(Debug, Clone)]
+struct FileChunk {
+    hash: String,
+    offset: u64,
+    length: u64,
+    size: u64,
+}
+
+/// Deduplicated uploads: files are split into content-defined chunks, only the
+/// chunks the server does not have yet are sent, followed by a manifest.
+#[derive(Clone)]
+pub struct ChunkedUploads {
+    enabled: bool,
+    threshold: u64,
+    throttle: Throttle,
+}
+
+impl ChunkedUploads {
+    pub fn new(enabled: bool, threshold: u64, throttle: Throttle) -> Self {
+        Self {
+            enabled,
+            threshold,
+            throttle,
+        }
+    }
+
+    // applies reports whether a file of this size is uploaded in chunks
+    pub fn applies(&self, size: u64) -> bool {
+        self.enabled && size >= self.threshold
+    }
+
+    // upload sends the missing chunks of the file, each compressed on its own when
+    // a compression is given, and closes the file with its manifest. An
+    // interrupted upload continues with the chunks the server is still missing.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload(
+        &self,
+        api: &ApiClient,
+        client: &Client,
+        path: &Path,
+        file: &FileResponse,
+        hash: &str,
+        mtime: DateTime<Utc>,
+        compression: Option<Compression>,
+    ) -> Result<FileResponse, std::io::Error> {
+        let file_name = path.file_name().unwrap().to_string_lossy();
+
+        let path_clone = path.to_path_buf();
+        let compression_clone = compression.clone();
+        let (chunks, digest) =
+            spawn_blocking(move || split_file(&path_clone, compression_clone.as_ref()))
+                .await
+                .expect("join failed")?;
+        if digest != hash {
+            return Err(std::io::Error::other("File changed during the upload"));
+        }
+
+        let mut seen = HashSet::new();
+        let unique: Vec<&FileChunk> = chunks
+            .iter()
+            .filter(|chunk| seen.insert(chunk.hash.as_str()))
+            .collect();
+        progress::resize(unique.iter().map(|chunk| chunk.size).sum());
+
+        let mut sent = 0;
+        for batch in unique.chunks(LOOKUP_BATCH) {
+            let missing = api
+                .find_missing_chunks(&ChunksRequest {
+                    chunks: batch.iter().map(|chunk| chunk_ref(chunk)).collect(),
+                })
+                .await?
+                .missing;
+            progress::skip(
+                batch
+                    .iter()
+                    .filter(|chunk| !missing.iter().any(|missing| missing.hash == chunk.hash))
+                    .map(|chunk| chunk.size)
+                    .sum(),
+            );
+
+            for presigned in missing {
+                let chunk = batch
+                    .iter()
+                    .find(|chunk| chunk.hash == presigned.hash)
+                    .ok_or_else(|| std::io::Error::other("Unexpected chunk in response"))?;
+
+                self.put_chunk(client, path, chunk, compression.clone(), presigned.url)
+                    .await?;
+                sent += 1;
+            }
+        }
+
+        log::info!(
+            "Uploaded {} of {} chunks: {}",
+            sent,
+            unique.len(),
+            file_name
+        );
+
+        api.commit_manifest(
+            file.id,
+            &ManifestRequest {
+                modified_at: mtime,
+                checksum: Some(hash.to_string()),
+                chunks: chunks.iter().map(chunk_ref).collect(),
+                codec: compression.map(|_| Codec::Zstd.name().to_string()),
+            },
+        )
+        .await
+    }
+
+    // put_chunk reads a chunk back from the file and uploads it. Its content is
+    // checked first, a chunk stored under the wrong hash would corrupt every file using it.
+    async fn put_chunk(
+        &self,
+        client: &Client,
+        path: &Path,
+        chunk: &FileChunk,
+        compression: Option<Compression>,
+        url: String,
+    ) -> Result<(), std::io::Error> {
+        let path_clone = path.to_path_buf();
+        let chunk_clone = chunk.clone();
+        let data =
+            spawn_blocking(move || read_chunk(&path_clone, &chunk_clone, compression.as_ref()))
+                .await
+                .expect("join failed")?;
+
+        let response = client
+            .put(url)
+            .header(CONTENT_LENGTH, data.len())
+            .body(buffer_body(data, &self.throttle))
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if !response.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "Failed to upload chunk {}: HTTP {}",
+                chunk.hash,
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// download_chunks writes the content of a chunked file by fetching its chunks
+// in manifest order, every chunk is checked against its hash
+pub async fn download_chunks(
+    api: &ApiClient,
+    client: &Client,
+    throttle: &Throttle,
+    file_id: Uuid,
+    version: Version,
+    mut decompressor: Option<Decompressor>,
+    out: &mut tokio::fs::File,
+) -> Result<(), std::io::Error> {
+    let manifest = match version {
+        Version::Current => api.get_manifest(file_id).await?,
+        Version::Snapshot(snapshot_id) => api.get_snapshot_manifest(snapshot_id, file_id).await?,
+    };
+
+    for chunk in manifest.chunks {
+        let mut response = client
+            .get(chunk.url)
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if !response.status().is_success() {
+            let kind = match response.status() {
+                reqwest::StatusCode::NOT_FOUND => std::io::ErrorKind::NotFound,
+                _ => std::io::ErrorKind::Other,
+            };
+            return Err(std::io::Error::new(
+                kind,
+                format!(
+                    "Failed to download chunk {}: HTTP {}",
+                    chunk.hash,
+                    response.status()
+                ),
+            ));
+        }
+
+        let mut data = Vec::with_capacity(chunk.size.max(0) as usize);
+        while let Some(piece) = response.chunk().await.map_err(std::io::Error::other)? {
+            throttle.acquire(piece.len()).await;
+            progress::record(piece.len() as u64);
+            data.extend_from_slice(&piece);
+        }
+
+        if data.len() as i64 != chunk.size || hex::encode(Sha256::digest(&data)) != chunk.hash {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk {} is corrupted", chunk.hash),
+            ));
+        }
+
+        match decompressor.as_mut() {
+            Some(decompressor) => out.write_all(&decompressor.push(&data)?).await?,
+            None => out.write_all(&data).await?,
+        }
+    }
+
+    match decompressor {
+        Some(decompressor) => decompressor.finish(),
+        None => Ok(()),
+    }
+}
+
+// split_file cuts the file into content-defined chunks, returning them with the
+// hex encoded SHA-256 of the whole file. Chunks are hashed the way they are
+// stored, compressed ones only deduplicate against other compressed chunks.
+fn split_file(
+    path: &Path,
+    compression: Option<&Compression>,
+) -> Result<(Vec<FileChunk>, String), std::io::Error> {
+    let file = std::fs::File::open(path)?;
+    let mut digest = Sha256::new();
+    let mut chunks = Vec::new();
+
+    for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk?;
+        digest.update(&chunk.data);
+
+        let stored = match compression {
+            Some(compression) => compression.compress(&chunk.data)?,
+            None => chunk.data,
+        };
+        chunks.push(FileChunk {
+            hash: hex::encode(Sha256::digest(&stored)),
+            offset: chunk.offset,
+            length: chunk.length as u64,
+            size: stored.len() as u64,
+        });
+    }
+
+    Ok((chunks, hex::encode(digest.finalize())))
+}
+
+// read_chunk reads a chunk of the file as it is stored and checks that it still
+// has the same content
+fn read_chunk(
+    path: &Path,
+    chunk: &FileChunk,
+    compression: Option<&Compression>,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(chunk.offset))?;
+
+    let mut data = vec![0u8; chunk.length as usize];
+    file.read_exact(&mut data)
+        .map_err(|e| std::io::Error::new(e.kind(), "File changed during the upload"))?;
+
+    if let Some(compression) = compression {
+        data = compression.compress(&data)?;
+    }
+
+    if hex::encode(Sha256::digest(&data)) != chunk.hash {
+        return Err(std::io::Error::other("File changed during the upload"));
+    }
+
+    Ok(data)
+}
+
+fn chunk_ref(chunk: &FileChunk) -> ChunkRef {
+    ChunkRef {
+        hash: chunk.hash.clone(),
+        size: chunk.size as i64,
+    }
+}
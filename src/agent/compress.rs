@@ -0,0 +1,249 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use zstd::stream::raw::{Decoder, Operation};
+
+// Extensions of formats that are compressed already, compressing them again only costs time
+const COMPRESSED_EXTENSIONS: [&str; 36] = [
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic",
+    "jar", "jpeg", "jpg", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "png",
+    "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst", "lz4",
+];
+// Extensions of formats that are known to compress well
+const COMPRESSIBLE_EXTENSIONS: [&str; 16] = [
+    "csv", "htm", "html", "js", "json", "jsonl", "log", "md", "ndjson", "sql", "svg", "tsv", "txt",
+    "xml", "yaml", "yml",
+];
+// Smaller files are sent as they are, the savings don't pay for the extra work
+const MIN_COMPRESS_SIZE: u64 = 4096;
+// Bytes read from the start of other files to check how well they compress
+const SAMPLE_SIZE: u64 = 64 * 1024;
+// The sample has to shrink to at most this fraction of its size
+const SAMPLE_RATIO: f64 = 0.9;
+// Size of the buffer decompressed content is produced in
+const DECOMPRESS_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Codec the content of a file is stored with on the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd,
+}
+
+impl Codec {
+    // name is the codec as recorded on the server
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    // parse reads a codec recorded on the server, `None` for uncompressed content
+    pub fn parse(name: Option<&str>) -> Result<Option<Self>, std::io::Error> {
+        match name {
+            None => Ok(None),
+            Some("zstd") => Ok(Some(Codec::Zstd)),
+            Some(name) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Unsupported codec: {}", name),
+            )),
+        }
+    }
+}
+
+/// Transparent zstd compression of the files that benefit from it.
+///
+/// Compression is deterministic for a given level, so chunks and parts of the
+/// same content compress to the same bytes every time. Compressed copies of
+/// whole files are written to the state directory.
+#[derive(Clone)]
+pub struct Compression {
+    enabled: bool,
+    level: i32,
+    state_dir: PathBuf,
+}
+
+impl Compression {
+    pub fn new(enabled: bool, level: i32, state_dir: &Path) -> Self {
+        Self {
+            enabled,
+            level,
+            state_dir: state_dir.to_path_buf(),
+        }
+    }
+
+    // codec picks the codec for a file: known media and archives are skipped, known
+    // text formats are compressed and anything else if a sample of it compresses well
+    pub fn codec(&self, path: &Path, size: u64) -> Result<Option<Codec>, std::io::Error> {
+        if !self.enabled || size < MIN_COMPRESS_SIZE {
+            return Ok(None);
+        }
+
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if let Some(extension) = extension.as_deref() {
+            if COMPRESSED_EXTENSIONS.contains(&extension) {
+                return Ok(None);
+            }
+            if COMPRESSIBLE_EXTENSIONS.contains(&extension) {
+                return Ok(Some(Codec::Zstd));
+            }
+        }
+
+        let mut sample = Vec::new();
+        fs::File::open(path)?
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut sample)?;
+        let compressed = self.compress(&sample)?;
+
+        if (compressed.len() as f64) <= sample.len() as f64 * SAMPLE_RATIO {
+            Ok(Some(Codec::Zstd))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // compress compresses a buffer into a single zstd frame
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        zstd::bulk::compress(data, self.level)
+    }
+
+    // remove_leftovers deletes the compressed copies left in the state directory
+    pub fn remove_leftovers(&self) {
+        let entries = match fs::read_dir(&self.state_dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with("compress-") && name.ends_with(".zst") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    // compress_file writes a compressed copy of the file to a temporary file in
+    // the state directory. The content is hashed as it is read, the digest
+    // returned is the one of the content in the copy.
+    pub fn compress_file(&self, path: &Path) -> Result<(UploadSource, String), std::io::Error> {
+        let temporary = self
+            .state_dir
+            .join(format!("compress-{}.zst", rand::random::<u64>()));
+
+        // the source takes care of removing the file, also when compressing fails
+        let mut source = UploadSource {
+            path: temporary,
+            size: 0,
+            codec: Some(Codec::Zstd),
+            level: Some(self.level),
+            temporary: true,
+        };
+
+        let mut input = DigestReader {
+            inner: fs::File::open(path)?,
+            hasher: Sha256::new(),
+        };
+        let output = fs::File::create(&source.path)?;
+        zstd::stream::copy_encode(&mut input, output, self.level)?;
+        source.size = fs::metadata(&source.path)?.len();
+
+        Ok((source, hex::encode(input.hasher.finalize())))
+    }
+}
+
+// DigestReader hashes what is read through it
+struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.hasher.update(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+/// The content uploaded for a file: the file itself or a compressed copy of it.
+pub struct UploadSource {
+    pub path: PathBuf,
+    pub size: u64,
+    pub codec: Option<Codec>,
+    pub level: Option<i32>,
+    temporary: bool,
+}
+
+impl UploadSource {
+    // plain uploads the file as it is
+    pub fn plain(path: &Path, size: u64) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            size,
+            codec: None,
+            level: None,
+            temporary: false,
+        }
+    }
+}
+
+impl Drop for UploadSource {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Streaming zstd decompression of downloaded content, which may consist of
+/// several frames, e.g. one per chunk.
+pub struct Decompressor {
+    decoder: Decoder<'static>,
+    buffer: Vec<u8>,
+    // whether the input seen so far ends with a complete frame
+    complete: bool,
+}
+
+impl Decompressor {
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            decoder: Decoder::new()?,
+            buffer: vec![0u8; DECOMPRESS_BUFFER_SIZE],
+            complete: false,
+        })
+    }
+
+    // push decompresses the next piece of content
+    pub fn push(&mut self, mut data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut out = Vec::new();
+
+        loop {
+            let status = self.decoder.run_on_buffers(data, &mut self.buffer)?;
+            out.extend_from_slice(&self.buffer[..status.bytes_written]);
+            data = &data[status.bytes_read..];
+            if status.bytes_read > 0 {
+                self.complete = status.remaining == 0;
+            }
+
+            // the buffer being filled up means more output may be pending
+            if data.is_empty() && status.bytes_written < self.buffer.len() {
+                return Ok(out);
+            }
+        }
+    }
+
+    // finish checks that the content did not end in the middle of a frame
+    pub fn finish(self) -> Result<(), std::io::Error> {
+        if !self.complete {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The compressed content is truncated",
+            ));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use crate::agent::logging::LogFormat;
+use crate::agent::metadata::SymlinkPolicy;
+use crate::agent::roots::{Roots, SyncRoot};
+use crate::agent::snapshot::RetentionPolicy;
+
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Agent settings, read from an optional TOML file and overridden by
+/// command-line arguments.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Base URL of the memora server, e.g. `https://memora.example.com`
+    pub server_url: String,
+    /// Token for authentication
+    pub token: Option<String>,
+    /// Directory that is synced to the server when no roots are given, under a
+    /// prefix named after it
+    pub dir: PathBuf,
+    /// Directories synced to prefixes on the server, `LOCAL=PREFIX` such as `~/Photos=/photos`
+    pub roots: Vec<String>,
+    /// Directory holding the agent's fjall state database
+    pub state_dir: PathBuf,
+    /// Seconds between scans in polling mode
+    pub scan_interval: u64,
+    /// Seconds between full rescans in watch mode
+    pub rescan_interval: u64,
+    /// Number of concurrent upload workers
+    pub workers: usize,
+    /// Sync changes as filesystem events arrive instead of polling
+    pub watch: bool,
+    /// Also pull changes made on the server by other devices
+    pub two_way: bool,
+    /// Files of at least this many bytes are uploaded in parts
+    pub multipart_threshold: u64,
+    /// Size in bytes of each part of a multipart upload
+    pub multipart_part_size: u64,
+    /// Split files into content-defined chunks and only upload the chunks the server lacks
+    pub chunking: bool,
+    /// Files of at least this many bytes are uploaded in chunks
+    pub chunk_threshold: u64,
+    /// Compress files that benefit from it with zstd before they are uploaded
+    pub compress: bool,
+    /// zstd compression level, from 1 (fastest) to 22 (smallest)
+    pub compression_level: i32,
+    /// Gitignore-style patterns excluded everywhere, in addition to `.memoraignore` files
+    pub exclude: Vec<String>,
+    /// Delete files from the server when they become ignored
+    pub remove_ignored: bool,
+    /// Whether symbolic links are followed or stored as links, `follow` or `store`
+    pub symlinks: SymlinkPolicy,
+    /// Encrypt file content and names before they leave the machine
+    pub encrypt: bool,
+    /// File holding the encryption passphrase, `MEMORA_PASSPHRASE` takes precedence
+    pub passphrase_file: Option<PathBuf>,
+    /// Upload and download limit in bytes per second shared by all workers, unlimited if unset
+    pub bandwidth_limit: Option<u64>,
+    /// Time windows overriding the limit, `HH:MM-HH:MM` for full speed or `HH:MM-HH:MM=BYTES`
+    pub bandwidth_schedule: Vec<String>,
+    /// Lowest level of the agent's log records: error, warn, info, debug or trace
+    pub log_level: String,
+    /// Format of the log lines, `text` or `json`
+    pub log_format: LogFormat,
+    /// Show the progress of transfers when running in an interactive terminal
+    pub progress: bool,
+    /// Seconds between scheduled verifications against the server, none if unset
+    pub verify_interval: Option<u64>,
+    /// Scheduled verifications also download the stored content and check it
+    pub verify_deep: bool,
+    /// Scheduled verifications upload the files with a problem again
+    pub verify_repair: bool,
+    /// Where verification reports are written [default: `<state_dir>/verify-report.json`]
+    pub verify_report: Option<PathBuf>,
+    /// Unix socket of the local control API [default: `<state_dir>/agent.sock`]
+    pub control_socket: Option<PathBuf>,
+    /// Name of the snapshots recorded by backups [default: the hostname]
+    pub backup_name: Option<String>,
+    /// Number of most recent snapshots kept by backups, every snapshot is kept
+    /// unless one of the `keep_*` settings is set
+    pub keep_last: usize,
+    /// Number of days the newest snapshot is kept of
+    pub keep_daily: usize,
+    /// Number of weeks the newest snapshot is kept of
+    pub keep_weekly: usize,
+    /// Number of months the newest snapshot is kept of
+    pub keep_monthly: usize,
+    /// Config file the settings were read from
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Bandwidth settings given on the command line, they win over the config
+    /// file also when it is reloaded
+    #[serde(skip)]
+    pub bandwidth_override: BandwidthOverride,
+}
+
+/// Bandwidth settings overriding those of the config file.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthOverride {
+    pub limit: Option<u64>,
+    pub schedule: Vec<String>,
+}
+
+impl BandwidthOverride {
+    pub fn apply(&self, config: &mut AgentConfig) {
+        if let Some(limit) = self.limit {
+            config.bandwidth_limit = Some(limit);
+        }
+        if !self.schedule.is_empty() {
+            config.bandwidth_schedule = self.schedule.clone();
+        }
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            server_url: "http://localhost:8000".to_string(),
+            token: None,
+            dir: PathBuf::from("./data"),
+            state_dir: PathBuf::from(".fjall_data"),
+            scan_interval: 5,
+            rescan_interval: 3600,
+            workers: 4,
+            watch: false,
+            two_way: false,
+            multipart_threshold: 64 * 1024 * 1024,
+            multipart_part_size: 16 * 1024 * 1024,
+            chunking: true,
+            chunk_threshold: 1024 * 1024,
+            compress: false,
+            compression_level: 3,
+            roots: Vec::new(),
+            exclude: Vec::new(),
+            remove_ignored: false,
+            symlinks: SymlinkPolicy::Follow,
+            encrypt: false,
+            passphrase_file: None,
+            bandwidth_limit: None,
+            bandwidth_schedule: Vec::new(),
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            progress: true,
+            verify_interval: None,
+            verify_deep: false,
+            verify_repair: false,
+            verify_report: None,
+            control_socket: None,
+            backup_name: None,
+            keep_last: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            path: None,
+            bandwidth_override: BandwidthOverride::default(),
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Load settings from a TOML file, missing keys fall back to defaults.
+    pub fn from_file(path: &Path) -> Result<Self, std::io::Error> {
+        let content = fs::read_to_string(path)?;
+
+        let config: Self = toml::from_str(&content).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid config file {}: {}", path.to_string_lossy(), e),
+            )
+        })?;
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            ..config
+        })
+    }
+
+    /// Check the settings that can't be used as they are, e.g. intervals of zero.
+    pub fn validate(&self) -> Result<(), std::io::Error> {
+        let invalid = |message: &str| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message.to_string(),
+            ))
+        };
+
+        if self.workers == 0 {
+            return invalid("At least one worker is required");
+        }
+        if self.scan_interval == 0 {
+            return invalid("scan_interval has to be at least one second");
+        }
+        if self.rescan_interval == 0 {
+            return invalid("rescan_interval has to be at least one second");
+        }
+        if self.verify_interval == Some(0) {
+            return invalid("verify_interval has to be at least one second");
+        }
+
+        Ok(())
+    }
+
+    /// The directories to sync with their prefixes on the server.
+    pub fn roots(&self) -> Result<Roots, std::io::Error> {
+        if self.roots.is_empty() {
+            return Roots::new(vec![SyncRoot::from_dir(&self.dir)]);
+        }
+
+        Roots::new(
+            self.roots
+                .iter()
+                .map(|root| root.parse())
+                .collect::<Result<_, _>>()?,
+        )
+    }
+
+    /// The Unix socket the control API listens on.
+    pub fn control_socket(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("agent.sock"))
+    }
+
+    /// The file verification reports are written to.
+    pub fn verify_report(&self) -> PathBuf {
+        self.verify_report
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("verify-report.json"))
+    }
+
+    /// The name of the snapshots recorded by backups.
+    pub fn backup_name(&self) -> Result<String, std::io::Error> {
+        match &self.backup_name {
+            Some(name) => Ok(name.clone()),
+            None => Ok(hostname::get()?.to_string_lossy().to_string()),
+        }
+    }
+
+    /// The snapshots of its name a backup keeps.
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            last: self.keep_last,
+            daily: self.keep_daily,
+            weekly: self.keep_weekly,
+            monthly: self.keep_monthly,
+        }
+    }
+
+    /// Values that must never show up in the logs.
+    pub fn secrets(&self) -> Vec<String> {
+        let mut secrets: Vec<String> = self.token.iter().cloned().collect();
+        if let Ok(Some(passphrase)) = self.passphrase() {
+            secrets.push(passphrase);
+        }
+
+        secrets
+    }
+
+    /// The encryption passphrase from `MEMORA_PASSPHRASE` or the passphrase file, if any.
+    pub fn passphrase(&self) -> Result<Option<String>, std::io::Error> {
+        if let Ok(passphrase) = std::env::var("MEMORA_PASSPHRASE") {
+            return Ok(Some(passphrase));
+        }
+
+        match &self.passphrase_file {
+            Some(path) => {
+                let content = fs::read_to_string(path)?;
+                Ok(Some(content.trim_end_matches(['\r', '\n']).to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+}
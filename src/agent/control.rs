@@ -0,0 +1,439 @@
+use crate::agent::agent::Agent;
+use crate::agent::queue::{QueueEntry, RetryQueue};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{watch, Notify};
+use tokio::task;
+
+// Upper bound on the size of a request line
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+/// Operations of the local control API.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Report what the agent is doing
+    Status,
+    /// Stop starting new scans and uploads, transfers in progress finish
+    Pause,
+    /// Continue after a pause
+    Resume,
+    /// Scan the synced directory right away
+    Scan,
+}
+
+/// A request sent to the agent, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+}
+
+/// The agent's answer to every request, its status after running the command.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlResponse {
+    pub status: Option<AgentStatus>,
+    pub error: Option<String>,
+}
+
+/// An upload that holds a worker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InFlightUpload {
+    pub path: PathBuf,
+    pub started_at: DateTime<Utc>,
+}
+
+/// What a running agent is doing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentStatus {
+    pub paused: bool,
+    pub scanning: bool,
+    /// Changed files found by a scan that wait for a worker
+    pub pending_uploads: usize,
+    pub in_flight: Vec<InFlightUpload>,
+    /// Failed operations waiting to be retried, with their last error
+    pub retry_queue: Vec<QueueEntry>,
+    pub last_scan_started_at: Option<DateTime<Utc>>,
+    pub last_scan_finished_at: Option<DateTime<Utc>>,
+    pub last_scan_error: Option<String>,
+}
+
+impl AgentStatus {
+    // queue_depth counts the operations that are not done yet
+    pub fn queue_depth(&self) -> usize {
+        self.pending_uploads + self.in_flight.len() + self.retry_queue.len()
+    }
+}
+
+impl fmt::Display for AgentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match (self.paused, self.scanning) {
+            (true, _) => "paused",
+            (false, true) => "scanning",
+            (false, false) => "idle",
+        };
+        writeln!(f, "State:           {}", state)?;
+
+        match (self.last_scan_started_at, self.last_scan_finished_at) {
+            (_, Some(finished)) => writeln!(
+                f,
+                "Last scan:       {}",
+                finished.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+            )?,
+            (Some(_), None) => writeln!(f, "Last scan:       running")?,
+            (None, None) => writeln!(f, "Last scan:       never")?,
+        }
+        if let Some(error) = &self.last_scan_error {
+            writeln!(f, "Last scan error: {}", error)?;
+        }
+
+        writeln!(f, "Queue depth:     {}", self.queue_depth())?;
+        writeln!(f, "Pending uploads: {}", self.pending_uploads)?;
+
+        writeln!(f, "In flight:       {}", self.in_flight.len())?;
+        let now = Utc::now();
+        for upload in &self.in_flight {
+            writeln!(
+                f,
+                "  {} ({}s)",
+                upload.path.to_string_lossy(),
+                (now - upload.started_at).num_seconds().max(0)
+            )?;
+        }
+
+        writeln!(f, "Retry queue:     {}", self.retry_queue.len())?;
+        for entry in &self.retry_queue {
+            writeln!(
+                f,
+                "  {:?} {} (attempt {}, next at {}): {}",
+                entry.operation,
+                entry.path.to_string_lossy(),
+                entry.attempts,
+                entry.next_attempt.with_timezone(&Local).format("%H:%M:%S"),
+                entry.last_error
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// State shared between the agent's loops and the control API.
+#[derive(Clone)]
+pub struct ControlState {
+    inner: Arc<ControlInner>,
+}
+
+struct ControlInner {
+    status: Mutex<StatusState>,
+    paused: watch::Sender<bool>,
+    stopping: watch::Sender<bool>,
+    scan: Notify,
+}
+
+#[derive(Default)]
+struct StatusState {
+    scanning: bool,
+    pending_uploads: usize,
+    in_flight: BTreeMap<PathBuf, DateTime<Utc>>,
+    last_scan_started_at: Option<DateTime<Utc>>,
+    last_scan_finished_at: Option<DateTime<Utc>>,
+    last_scan_error: Option<String>,
+}
+
+impl ControlState {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(ControlInner {
+                status: Mutex::new(StatusState::default()),
+                paused: watch::Sender::new(false),
+                stopping: watch::Sender::new(false),
+                scan: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn pause(&self) {
+        if !self.inner.paused.send_replace(true) {
+            log::info!("Paused");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.inner.paused.send_replace(false) {
+            log::info!("Resumed");
+        }
+    }
+
+    // wait_resumed returns once the agent is not paused or is stopping
+    pub async fn wait_resumed(&self) {
+        let mut paused = self.inner.paused.subscribe();
+        tokio::select! {
+            _ = paused.wait_for(|paused| !*paused) => {}
+            _ = self.stopped() => {}
+        }
+    }
+
+    // stop asks the loops to return, uploads already started finish
+    pub fn stop(&self) {
+        if !self.inner.stopping.send_replace(true) {
+            log::info!("Stopping, waiting for uploads in progress");
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.inner.stopping.borrow()
+    }
+
+    // stopped completes once the agent is stopping
+    pub async fn stopped(&self) {
+        let mut stopping = self.inner.stopping.subscribe();
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+
+    pub fn request_scan(&self) {
+        self.inner.scan.notify_one();
+    }
+
+    // scan_requested completes when a scan was asked for through the control API
+    pub async fn scan_requested(&self) {
+        self.inner.scan.notified().await;
+    }
+
+    pub fn start_scan(&self) {
+        let mut status = self.inner.status.lock().unwrap();
+        status.scanning = true;
+        status.last_scan_started_at = Some(Utc::now());
+    }
+
+    pub fn finish_scan(&self, error: Option<&std::io::Error>) {
+        let mut status = self.inner.status.lock().unwrap();
+        status.scanning = false;
+        status.last_scan_finished_at = Some(Utc::now());
+        status.last_scan_error = error.map(|e| e.to_string());
+    }
+
+    // pending counts uploads waiting for a worker until the returned guard is used up or dropped
+    pub fn pending(&self, count: usize) -> PendingUploads {
+        self.inner.status.lock().unwrap().pending_uploads += count;
+
+        PendingUploads {
+            control: self.clone(),
+            remaining: count,
+        }
+    }
+
+    pub fn upload_started(&self, path: &Path) {
+        let mut status = self.inner.status.lock().unwrap();
+        status.in_flight.insert(path.to_path_buf(), Utc::now());
+    }
+
+    pub fn upload_finished(&self, path: &Path) {
+        self.inner.status.lock().unwrap().in_flight.remove(path);
+    }
+
+    // status returns a snapshot of the agent's activity
+    pub fn status(&self, retry_queue: Vec<QueueEntry>) -> AgentStatus {
+        let status = self.inner.status.lock().unwrap();
+
+        AgentStatus {
+            paused: *self.inner.paused.borrow(),
+            scanning: status.scanning,
+            pending_uploads: status.pending_uploads,
+            in_flight: status
+                .in_flight
+                .iter()
+                .map(|(path, started_at)| InFlightUpload {
+                    path: path.clone(),
+                    started_at: *started_at,
+                })
+                .collect(),
+            retry_queue,
+            last_scan_started_at: status.last_scan_started_at,
+            last_scan_finished_at: status.last_scan_finished_at,
+            last_scan_error: status.last_scan_error.clone(),
+        }
+    }
+
+    fn release_pending(&self, count: usize) {
+        let mut status = self.inner.status.lock().unwrap();
+        status.pending_uploads = status.pending_uploads.saturating_sub(count);
+    }
+}
+
+impl Default for ControlState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Uploads counted as pending, the ones not taken are released on drop.
+pub struct PendingUploads {
+    control: ControlState,
+    remaining: usize,
+}
+
+impl PendingUploads {
+    // take marks one of the uploads as no longer waiting
+    pub fn take(&mut self) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.control.release_pending(1);
+        }
+    }
+}
+
+impl Drop for PendingUploads {
+    fn drop(&mut self) {
+        self.control.release_pending(self.remaining);
+    }
+}
+
+impl Agent {
+    // run_control serves the local control API on the agent's Unix socket
+    pub async fn run_control(&self) {
+        let listener = match bind(&self.control_socket).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!(
+                    "Error opening control socket {}: {}",
+                    self.control_socket.to_string_lossy(),
+                    e
+                );
+                return;
+            }
+        };
+
+        log::info!(
+            "Control API listening on: {}",
+            self.control_socket.to_string_lossy()
+        );
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.control.stopped() => break,
+            };
+
+            match accepted {
+                Ok((stream, _)) => {
+                    let control = self.control.clone();
+                    let queue = self.queue.clone();
+
+                    task::spawn(async move {
+                        if let Err(e) = handle_connection(stream, control, queue).await {
+                            log::error!("Error handling control request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Error accepting control connection: {}", e),
+            }
+        }
+
+        // the next agent finds no stale socket
+        let _ = std::fs::remove_file(&self.control_socket);
+    }
+}
+
+// request sends a command to the agent listening on the socket and returns its status
+pub async fn request(
+    socket: &Path,
+    command: ControlCommand,
+) -> Result<AgentStatus, std::io::Error> {
+    let mut stream = UnixStream::connect(socket).await.map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "No agent is listening on {}: {}",
+                socket.to_string_lossy(),
+                e
+            ),
+        )
+    })?;
+
+    let mut line = serde_json::to_vec(&ControlRequest { command })?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).await?;
+    let response: ControlResponse = serde_json::from_str(&response)?;
+
+    match (response.status, response.error) {
+        (_, Some(error)) => Err(std::io::Error::other(error)),
+        (Some(status), None) => Ok(status),
+        (None, None) => Err(std::io::Error::other("Empty response from the agent")),
+    }
+}
+
+// bind opens the control socket, replacing a stale one left behind by an agent
+// that did not shut down cleanly. Only the owner may connect.
+async fn bind(path: &Path) -> Result<UnixListener, std::io::Error> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "Another agent is running",
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+// handle_connection answers a single request
+async fn handle_connection(
+    stream: UnixStream,
+    control: ControlState,
+    queue: RetryQueue,
+) -> Result<(), std::io::Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_SIZE))
+        .read_line(&mut line)
+        .await?;
+
+    let response = match serde_json::from_str::<ControlRequest>(&line) {
+        Ok(request) => {
+            match request.command {
+                ControlCommand::Status => {}
+                ControlCommand::Pause => control.pause(),
+                ControlCommand::Resume => control.resume(),
+                ControlCommand::Scan => control.request_scan(),
+            }
+
+            match queue.entries().await {
+                Ok(entries) => ControlResponse {
+                    status: Some(control.status(entries)),
+                    error: None,
+                },
+                Err(e) => ControlResponse {
+                    status: None,
+                    error: Some(format!("Error reading the retry queue: {}", e)),
+                },
+            }
+        }
+        Err(e) => ControlResponse {
+            status: None,
+            error: Some(format!("Invalid request: {}", e)),
+        },
+    };
+
+    let mut out = serde_json::to_vec(&response)?;
+    out.push(b'\n');
+    writer.write_all(&out).await
+}
@@ -0,0 +1,500 @@
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Version tag of the key id format
+const KEY_ID_VERSION: &str = "v1";
+const SALT_LEN: usize = 16;
+const CHECK_LEN: usize = 8;
+
+// Magic bytes starting every encrypted object, followed by the nonce prefix
+const MAGIC: &[u8; 8] = b"MEMORA\x00\x01";
+const NONCE_PREFIX_LEN: usize = 19;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: u64 = 16;
+
+/// Length of the header in front of the encrypted chunks.
+pub const HEADER_LEN: u64 = (MAGIC.len() + NONCE_PREFIX_LEN) as u64;
+/// Plaintext bytes per encrypted chunk.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+/// Length of a full chunk once encrypted.
+pub const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE + TAG_LEN;
+
+/// Keys derived from the passphrase and one salt.
+///
+/// The server only ever sees the key id, which carries the salt and a short
+/// check value so that a wrong passphrase is detected before decrypting.
+pub struct FileKey {
+    id: String,
+    content: XChaCha20Poly1305,
+    names: XChaCha20Poly1305,
+    name_nonces: [u8; 32],
+    checksums: [u8; 32],
+}
+
+impl FileKey {
+    fn derive(passphrase: &str, salt: &[u8]) -> Result<Self, std::io::Error> {
+        let mut master = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut master)
+            .map_err(|e| std::io::Error::other(format!("Failed to derive key: {}", e)))?;
+
+        let hkdf = Hkdf::<Sha256>::new(None, &master);
+        let expand = |info: &[u8], out: &mut [u8]| {
+            hkdf.expand(info, out)
+                .expect("output length is valid for HKDF-SHA256")
+        };
+
+        let mut content = [0u8; 32];
+        let mut names = [0u8; 32];
+        let mut name_nonces = [0u8; 32];
+        let mut checksums = [0u8; 32];
+        let mut check = [0u8; CHECK_LEN];
+        expand(b"memora content", &mut content);
+        expand(b"memora names", &mut names);
+        expand(b"memora name nonces", &mut name_nonces);
+        expand(b"memora checksums", &mut checksums);
+        expand(b"memora key check", &mut check);
+
+        Ok(Self {
+            id: format!(
+                "{}.{}.{}",
+                KEY_ID_VERSION,
+                URL_SAFE_NO_PAD.encode(salt),
+                hex::encode(check)
+            ),
+            content: XChaCha20Poly1305::new(&content.into()),
+            names: XChaCha20Poly1305::new(&names.into()),
+            name_nonces,
+            checksums,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // encrypt_path encrypts every component of a `/` separated path. The same
+    // name always encrypts the same way, so paths keep their structure on the server.
+    pub fn encrypt_path(&self, path: &str) -> String {
+        path.split('/')
+            .map(|component| match component {
+                "" => String::new(),
+                component => self.encrypt_name(component),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    pub fn decrypt_path(&self, path: &str) -> Result<String, std::io::Error> {
+        path.split('/')
+            .map(|component| match component {
+                "" => Ok(String::new()),
+                component => self.decrypt_name(component),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|components| components.join("/"))
+    }
+
+    // encrypt_name uses a nonce derived from the name itself (synthetic IV)
+    fn encrypt_name(&self, name: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.name_nonces)
+            .expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let nonce = XNonce::from_slice(&digest[..NONCE_LEN]);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.names
+                .encrypt(nonce, name.as_bytes())
+                .expect("encrypting a name can't fail"),
+        );
+
+        URL_SAFE_NO_PAD.encode(sealed)
+    }
+
+    fn decrypt_name(&self, name: &str) -> Result<String, std::io::Error> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Can't decrypt name {}", name),
+            )
+        };
+
+        let sealed = URL_SAFE_NO_PAD.decode(name).map_err(|_| invalid())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .names
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| invalid())?;
+
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+
+    // encrypt_metadata seals the attributes stored with a file under a random nonce
+    pub fn encrypt_metadata(&self, metadata: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.names
+                .encrypt(XNonce::from_slice(&nonce), metadata.as_bytes())
+                .expect("encrypting metadata can't fail"),
+        );
+
+        URL_SAFE_NO_PAD.encode(sealed)
+    }
+
+    pub fn decrypt_metadata(&self, metadata: &str) -> Result<String, std::io::Error> {
+        let invalid =
+            || std::io::Error::new(std::io::ErrorKind::InvalidData, "Can't decrypt metadata");
+
+        let sealed = URL_SAFE_NO_PAD.decode(metadata).map_err(|_| invalid())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .names
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| invalid())?;
+
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+
+    // checksum turns a content hash into one that can't be checked against guessed content
+    pub fn checksum(&self, hash: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.checksums)
+            .expect("HMAC accepts any key length");
+        mac.update(hash.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// The keys the agent can use, all derived from a single passphrase.
+///
+/// Uploads are encrypted with the active key; any other key id found on the
+/// server is derived on demand for decryption.
+pub struct Keyring {
+    passphrase: String,
+    active: OnceLock<Arc<FileKey>>,
+    keys: Mutex<HashMap<String, Arc<FileKey>>>,
+}
+
+impl Keyring {
+    pub fn new(passphrase: String) -> Self {
+        Self {
+            passphrase,
+            active: OnceLock::new(),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // activate makes the key with the given id, or a new one, the key used for
+    // uploads. Without it the keyring only decrypts.
+    pub fn activate(&self, key_id: Option<&str>) -> Result<Arc<FileKey>, std::io::Error> {
+        let key = match key_id {
+            Some(key_id) => self.key(key_id)?,
+            None => {
+                let mut salt = [0u8; SALT_LEN];
+                rand::thread_rng().fill_bytes(&mut salt);
+
+                let key = Arc::new(FileKey::derive(&self.passphrase, &salt)?);
+                self.keys
+                    .lock()
+                    .unwrap()
+                    .insert(key.id().to_string(), key.clone());
+                key
+            }
+        };
+
+        Ok(self.active.get_or_init(|| key).clone())
+    }
+
+    pub fn active(&self) -> Option<Arc<FileKey>> {
+        self.active.get().cloned()
+    }
+
+    // key returns the key with the given id, failing if the passphrase doesn't match it
+    pub fn key(&self, key_id: &str) -> Result<Arc<FileKey>, std::io::Error> {
+        if let Some(key) = self.keys.lock().unwrap().get(key_id) {
+            return Ok(key.clone());
+        }
+
+        let invalid = |message: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", message, key_id),
+            )
+        };
+
+        let salt = match key_id.split('.').collect::<Vec<_>>()[..] {
+            [KEY_ID_VERSION, salt, _] => URL_SAFE_NO_PAD
+                .decode(salt)
+                .map_err(|_| invalid("Invalid key id"))?,
+            _ => return Err(invalid("Unsupported key id")),
+        };
+
+        let key = Arc::new(FileKey::derive(&self.passphrase, &salt)?);
+        if key.id() != key_id {
+            return Err(invalid("The passphrase does not match the key"));
+        }
+
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(key_id.to_string(), key.clone());
+        Ok(key)
+    }
+}
+
+// chunk_count returns the number of chunks of a file, an empty file still has one
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+/// Size of a file of `size` bytes once encrypted.
+pub fn encrypted_size(size: u64) -> u64 {
+    HEADER_LEN + chunk_count(size) * TAG_LEN + size
+}
+
+/// Encrypts file content as a sequence of independently sealed chunks.
+///
+/// Every chunk nonce is the random prefix followed by the chunk index and a
+/// flag marking the last chunk, so chunks can't be reordered or cut off.
+pub struct ContentSealer {
+    key: Arc<FileKey>,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    chunks: u64,
+}
+
+impl ContentSealer {
+    pub fn new(key: Arc<FileKey>, size: u64) -> Self {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        rand::thread_rng().fill_bytes(&mut prefix);
+
+        Self {
+            key,
+            prefix,
+            chunks: chunk_count(size),
+        }
+    }
+
+    // resume recreates the sealer of an interrupted upload from its saved nonce prefix
+    pub fn resume(key: Arc<FileKey>, size: u64, prefix: &str) -> Result<Self, std::io::Error> {
+        let prefix = hex::decode(prefix)
+            .ok()
+            .and_then(|prefix| prefix.try_into().ok())
+            .ok_or_else(|| std::io::Error::other("Invalid nonce prefix"))?;
+
+        Ok(Self {
+            key,
+            prefix,
+            chunks: chunk_count(size),
+        })
+    }
+
+    pub fn prefix(&self) -> String {
+        hex::encode(self.prefix)
+    }
+
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&self.prefix);
+        header
+    }
+
+    pub fn seal(&self, index: u64, plaintext: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = chunk_nonce(&self.prefix, index, index + 1 == self.chunks)?;
+
+        self.key
+            .content
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| std::io::Error::other("Failed to encrypt chunk"))
+    }
+}
+
+/// Decrypts content written by `ContentSealer` as it arrives.
+pub struct ContentOpener {
+    key: Arc<FileKey>,
+    prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl ContentOpener {
+    pub fn new(key: Arc<FileKey>) -> Self {
+        Self {
+            key,
+            prefix: None,
+            buffer: Vec::new(),
+            index: 0,
+        }
+    }
+
+    // push takes the next piece of ciphertext and returns the plaintext it completes
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        self.buffer.extend_from_slice(data);
+
+        if self.prefix.is_none() {
+            if (self.buffer.len() as u64) < HEADER_LEN {
+                return Ok(Vec::new());
+            }
+            if &self.buffer[..MAGIC.len()] != MAGIC {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Not an encrypted file",
+                ));
+            }
+
+            let header: Vec<u8> = self.buffer.drain(..HEADER_LEN as usize).collect();
+            self.prefix = Some(header[MAGIC.len()..].try_into().unwrap());
+        }
+
+        // A full chunk is only the last one if nothing follows it, so it's
+        // kept until more data arrives or the stream ends
+        let mut plaintext = Vec::new();
+        while self.buffer.len() as u64 > SEALED_CHUNK_SIZE {
+            let chunk: Vec<u8> = self.buffer.drain(..SEALED_CHUNK_SIZE as usize).collect();
+            plaintext.extend(self.open(&chunk, false)?);
+        }
+
+        Ok(plaintext)
+    }
+
+    // finish opens the last chunk, failing if the content was cut short
+    pub fn finish(mut self) -> Result<Vec<u8>, std::io::Error> {
+        if self.prefix.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Encrypted file is truncated",
+            ));
+        }
+
+        let chunk = std::mem::take(&mut self.buffer);
+        self.open(&chunk, true)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = chunk_nonce(self.prefix.as_ref().unwrap(), self.index, last)?;
+        self.index += 1;
+
+        self.key.content.decrypt(&nonce, chunk).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Encrypted file is corrupted or truncated",
+            )
+        })
+    }
+}
+
+fn chunk_nonce(
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    index: u64,
+    last: bool,
+) -> Result<XNonce, std::io::Error> {
+    let index = u32::try_from(index).map_err(|_| std::io::Error::other("File is too large"))?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+
+    Ok(nonce.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // seal encrypts the content the way uploads do, header first
+    fn seal(key: &Arc<FileKey>, content: &[u8]) -> Vec<u8> {
+        let sealer = ContentSealer::new(key.clone(), content.len() as u64);
+        let mut sealed = sealer.header();
+        for index in 0..sealer.chunks() {
+            let start = (index * CHUNK_SIZE) as usize;
+            let end = (start + CHUNK_SIZE as usize).min(content.len());
+            sealed.extend(sealer.seal(index, &content[start..end]).unwrap());
+        }
+        sealed
+    }
+
+    // open decrypts the content in pieces of the given size, as downloads arrive
+    fn open(key: &Arc<FileKey>, sealed: &[u8], piece: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut opener = ContentOpener::new(key.clone());
+        let mut content = Vec::new();
+        for data in sealed.chunks(piece) {
+            content.extend(opener.push(data)?);
+        }
+        content.extend(opener.finish()?);
+        Ok(content)
+    }
+
+    #[test]
+    fn sealed_content_opens_to_the_original() {
+        let key = Keyring::new("secret".to_string()).activate(None).unwrap();
+        let content: Vec<u8> = (0..2 * CHUNK_SIZE + 100).map(|i| i as u8).collect();
+
+        let sealed = seal(&key, &content);
+
+        assert_eq!(sealed.len() as u64, encrypted_size(content.len() as u64));
+        assert_eq!(open(&key, &sealed, 1000).unwrap(), content);
+        assert_eq!(open(&key, &seal(&key, b""), 7).unwrap(), b"");
+    }
+
+    #[test]
+    fn content_does_not_open_with_another_key() {
+        let key = Keyring::new("secret".to_string()).activate(None).unwrap();
+        let other = Keyring::new("secret".to_string()).activate(None).unwrap();
+
+        let sealed = seal(&key, b"some content");
+
+        let error = open(&other, &sealed, 1000).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn key_is_refused_with_a_wrong_passphrase() {
+        let key = Keyring::new("secret".to_string()).activate(None).unwrap();
+
+        let error = Keyring::new("wrong".to_string())
+            .key(key.id())
+            .err()
+            .unwrap();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(Keyring::new("secret".to_string()).key(key.id()).is_ok());
+    }
+
+    #[test]
+    fn names_encrypt_the_same_way_every_time() {
+        let key = Keyring::new("secret".to_string()).activate(None).unwrap();
+
+        let path = key.encrypt_path("/photos/2026/beach.jpg");
+
+        assert_eq!(path, key.encrypt_path("/photos/2026/beach.jpg"));
+        assert_ne!(path, key.encrypt_path("/photos/2026/beach.png"));
+        assert!(path.starts_with('/'));
+        assert_eq!(path.split('/').count(), 4);
+        assert_eq!(key.decrypt_path(&path).unwrap(), "/photos/2026/beach.jpg");
+    }
+}
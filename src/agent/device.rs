@@ -0,0 +1,245 @@
+use crate::schema::device::{
+    DeviceCreateRequest, DeviceCreateResponse, DeviceResponse, DevicesResponse,
+};
+use crate::schema::user::{LoginUserRequest, LoginUserResponse};
+use charybdis::types::Uuid;
+
+use chrono::{DateTime, Utc};
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+// DeviceCredential is the credential of the device this agent is registered as,
+// kept in the state directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceCredential {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub token: String,
+}
+
+impl DeviceCredential {
+    fn path(state_dir: &Path) -> PathBuf {
+        state_dir.join("device.json")
+    }
+
+    // load reads the credential stored in the state directory, an agent that
+    // never logged in has none
+    pub fn load(state_dir: &Path) -> Result<Option<Self>, std::io::Error> {
+        let path = Self::path(state_dir);
+        match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).map(Some).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid device credential {}: {}",
+                        path.to_string_lossy(),
+                        e
+                    ),
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // save stores the credential readable by the owner only
+    pub fn save(&self, state_dir: &Path) -> Result<(), std::io::Error> {
+        fs::create_dir_all(state_dir)?;
+
+        let path = Self::path(state_dir);
+        let partial = path.with_extension("tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&partial)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&partial, path)
+    }
+
+    pub fn remove(state_dir: &Path) -> Result<(), std::io::Error> {
+        match fs::remove_file(Self::path(state_dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+// DeviceClient registers, lists and revokes the devices of a user
+pub struct DeviceClient {
+    client: Client,
+    server_url: String,
+}
+
+impl DeviceClient {
+    pub fn new(server_url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            server_url: server_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server_url, path)
+    }
+
+    // login exchanges the user's email and password for a login token
+    pub async fn login(&self, email: &str, password: &str) -> Result<String, std::io::Error> {
+        let response = self
+            .client
+            .post(self.url("/v1/auth/login"))
+            .json(&LoginUserRequest {
+                email: email.to_string(),
+                password: password.to_string(),
+            })
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        let login: LoginUserResponse = parse(response, "Login failed").await?;
+        Ok(login.token)
+    }
+
+    // register adds this machine as a named device, the user's login token is required
+    pub async fn register(
+        &self,
+        token: &str,
+        name: &str,
+    ) -> Result<DeviceCredential, std::io::Error> {
+        let response = self
+            .client
+            .post(self.url("/v1/devices"))
+            .header("Authorization", format!("bearer {}", token))
+            .json(&DeviceCreateRequest {
+                name: name.to_string(),
+            })
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        let created: DeviceCreateResponse =
+            parse(response, "Failed to register the device").await?;
+        Ok(DeviceCredential {
+            id: created.device.id,
+            name: created.device.name,
+            created_at: created.device.created_at,
+            token: created.token,
+        })
+    }
+
+    pub async fn list(&self, token: &str) -> Result<Vec<DeviceResponse>, std::io::Error> {
+        let response = self
+            .client
+            .get(self.url("/v1/devices"))
+            .header("Authorization", format!("bearer {}", token))
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        let devices: DevicesResponse = parse(response, "Failed to list devices").await?;
+        Ok(devices.objects)
+    }
+
+    pub async fn revoke(
+        &self,
+        token: &str,
+        device_id: Uuid,
+    ) -> Result<DeviceResponse, std::io::Error> {
+        let response = self
+            .client
+            .delete(self.url(&format!("/v1/devices/{}", device_id)))
+            .header("Authorization", format!("bearer {}", token))
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        parse(response, "Failed to revoke the device").await
+    }
+}
+
+// parse decodes a successful response, failures carry the server's message
+async fn parse<T: DeserializeOwned>(response: Response, action: &str) -> Result<T, std::io::Error> {
+    let status = response.status();
+    if status.is_success() {
+        return response
+            .json::<T>()
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()));
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorBody {
+        message: String,
+    }
+    let message = match response.json::<ErrorBody>().await {
+        Ok(body) => format!("{}: {}", action, body.message),
+        Err(_) => format!("{}: HTTP {}", action, status),
+    };
+
+    let kind = match status.as_u16() {
+        401 | 403 => std::io::ErrorKind::PermissionDenied,
+        404 => std::io::ErrorKind::NotFound,
+        _ => std::io::ErrorKind::Other,
+    };
+    Err(std::io::Error::new(kind, message))
+}
+
+// read_password returns `MEMORA_PASSWORD` or asks for the password on the
+// terminal without echoing it
+pub fn read_password(prompt: &str) -> Result<String, std::io::Error> {
+    if let Ok(password) = std::env::var("MEMORA_PASSWORD") {
+        return Ok(password);
+    }
+
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+
+    let echo = EchoGuard::disable();
+    let mut password = String::new();
+    let read = std::io::stdin().lock().read_line(&mut password);
+    drop(echo);
+    eprintln!();
+
+    read?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// EchoGuard turns off the echo of the terminal on stdin until it is dropped,
+// input that isn't a terminal is left alone
+struct EchoGuard {
+    previous: Option<libc::termios>,
+}
+
+impl EchoGuard {
+    fn disable() -> Self {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+            return Self { previous: None };
+        }
+
+        let previous = unsafe { termios.assume_init() };
+        let mut silent = previous;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) };
+
+        Self {
+            previous: Some(previous),
+        }
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = &self.previous {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, previous) };
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Name of the per-directory ignore files
+pub const IGNORE_FILE_NAME: &str = ".memoraignore";
+
+// Patterns that are always ignored, e.g. downloads of the two-way sync in progress
+const BUILTIN_PATTERNS: [&str; 1] = [".*.memora-part"];
+
+/// Ignore rules of the synced trees: the global exclude patterns plus the
+/// `.memoraignore` files found in their directories, with gitignore semantics.
+///
+/// Patterns in deeper `.memoraignore` files take precedence over the ones
+/// above them, the global patterns come last.
+#[derive(Clone)]
+pub struct IgnoreRules {
+    // synced directories with the global patterns anchored at them
+    roots: Arc<Vec<(PathBuf, Gitignore)>>,
+    // parsed `.memoraignore` files by directory, dropped by `reload`
+    directories: Arc<Mutex<HashMap<PathBuf, Arc<Gitignore>>>>,
+}
+
+impl IgnoreRules {
+    pub fn new(roots: &[PathBuf], patterns: &[String]) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            roots: Arc::new(
+                roots
+                    .iter()
+                    .map(|root| Ok((root.clone(), global_rules(root, patterns)?)))
+                    .collect::<Result<_, std::io::Error>>()?,
+            ),
+            directories: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    // reload forgets the parsed `.memoraignore` files so that edits to them are picked up
+    pub fn reload(&self) {
+        self.directories.lock().unwrap().clear();
+    }
+
+    // is_ignored reports whether the path or one of its parent directories is ignored
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let (root, global) = match self.roots.iter().find(|(root, _)| path.starts_with(root)) {
+            Some((root, global)) => (root, global),
+            None => return false,
+        };
+        if path == root {
+            return false;
+        }
+
+        let mut directories: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|directory| directory.starts_with(root))
+            .collect();
+        directories.reverse();
+
+        // A path inside an ignored directory can't be re-included
+        for (depth, directory) in directories.iter().enumerate().skip(1) {
+            if self.matched(directory, true, &directories[..depth], global) {
+                return true;
+            }
+        }
+
+        self.matched(path, is_dir, &directories, global)
+    }
+
+    // matched checks a single path against the rules of the given directories and
+    // the global patterns, the deepest directory with a matching pattern decides
+    fn matched(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        directories: &[&Path],
+        global: &Gitignore,
+    ) -> bool {
+        for directory in directories.iter().rev() {
+            match self.directory_rules(directory).matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+
+        global.matched(path, is_dir).is_ignore()
+    }
+
+    fn directory_rules(&self, directory: &Path) -> Arc<Gitignore> {
+        let mut directories = self.directories.lock().unwrap();
+
+        directories
+            .entry(directory.to_path_buf())
+            .or_insert_with(|| {
+                let file = directory.join(IGNORE_FILE_NAME);
+                if !file.is_file() {
+                    return Arc::new(Gitignore::empty());
+                }
+
+                let (rules, error) = Gitignore::new(&file);
+                if let Some(e) = error {
+                    log::error!("Error reading {}: {}", file.to_string_lossy(), e);
+                }
+                Arc::new(rules)
+            })
+            .clone()
+    }
+}
+
+// global_rules builds the exclude patterns, anchored at the synced directory
+fn global_rules(root: &Path, patterns: &[String]) -> Result<Gitignore, std::io::Error> {
+    let mut builder = GitignoreBuilder::new(root);
+
+    for pattern in BUILTIN_PATTERNS
+        .iter()
+        .copied()
+        .chain(patterns.iter().map(String::as_str))
+    {
+        builder.add_line(None, pattern).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid exclude pattern {}: {}", pattern, e),
+            )
+        })?;
+    }
+
+    builder.build().map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // tree creates a temporary synced directory with the given `.memoraignore` files
+    fn tree(ignore_files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("ignore-{}", rand::random::<u64>()));
+        for (directory, patterns) in ignore_files {
+            let directory = root.join(directory);
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(directory.join(IGNORE_FILE_NAME), patterns).unwrap();
+        }
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    // rules builds the ignore rules of a single root with the given global patterns
+    fn rules(root: &Path, patterns: &[&str]) -> IgnoreRules {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        IgnoreRules::new(&[root.to_path_buf()], &patterns).unwrap()
+    }
+
+    #[test]
+    fn negated_patterns_re_include_files() {
+        let root = tree(&[]);
+        let rules = rules(&root, &["*.log", "!keep.log"]);
+
+        assert!(rules.is_ignored(&root.join("logs/debug.log"), false));
+        assert!(!rules.is_ignored(&root.join("logs/keep.log"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn files_in_ignored_directories_are_ignored() {
+        let root = tree(&[]);
+        let rules = rules(&root, &["build/", "!build/keep.txt"]);
+
+        assert!(rules.is_ignored(&root.join("build"), true));
+        assert!(rules.is_ignored(&root.join("build/out/app"), false));
+        // a negation can't re-include a file below an ignored directory
+        assert!(rules.is_ignored(&root.join("build/keep.txt"), false));
+        assert!(!rules.is_ignored(&root.join("src/build"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deeper_ignore_files_take_precedence() {
+        let root = tree(&[("", "*.tmp\n"), ("projects", "!draft.tmp\n")]);
+        let rules = rules(&root, &["*.bak"]);
+
+        assert!(rules.is_ignored(&root.join("scratch.tmp"), false));
+        assert!(rules.is_ignored(&root.join("projects/other.tmp"), false));
+        assert!(!rules.is_ignored(&root.join("projects/draft.tmp"), false));
+        assert!(rules.is_ignored(&root.join("projects/report.bak"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ignore_files_in_ignored_directories_are_not_consulted() {
+        let root = tree(&[("", "cache/\n"), ("cache", "!*\n")]);
+        let rules = rules(&root, &[]);
+
+        assert!(rules.is_ignored(&root.join("cache/data.bin"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reload_picks_up_edited_ignore_files() {
+        let root = tree(&[("", "*.tmp\n")]);
+        let rules = rules(&root, &[]);
+        assert!(rules.is_ignored(&root.join("scratch.tmp"), false));
+
+        fs::write(root.join(IGNORE_FILE_NAME), "").unwrap();
+        assert!(rules.is_ignored(&root.join("scratch.tmp"), false));
+
+        rules.reload();
+        assert!(!rules.is_ignored(&root.join("scratch.tmp"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_the_roots_are_not_ignored() {
+        let root = tree(&[]);
+        let rules = rules(&root, &["*"]);
+
+        assert!(!rules.is_ignored(Path::new("/elsewhere/file.txt"), false));
+        assert!(!rules.is_ignored(&root, true));
+        assert!(rules.is_ignored(&root.join("file.txt"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::schema::file::FileResponse;
+
+// IndexEntry is the record stored in the fjall `tasks` partition for every
+// synced path. Besides the server-side file it keeps the size, modification
+// time and content digest observed at upload time, so the scanner can tell
+// whether a file changed since it was last sent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    pub file: FileResponse,
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+    pub hash: Option<String>,
+}
+
+impl IndexEntry {
+    pub fn new(file: FileResponse, metadata: &fs::Metadata, hash: Option<String>) -> Self {
+        IndexEntry {
+            file,
+            size: metadata.len(),
+            mtime: modified_time(metadata),
+            hash,
+        }
+    }
+
+    // pending records a file created on the server whose content was not sent
+    // yet, a retry finds it stale and reopens the row instead of creating another
+    pub fn pending(file: FileResponse) -> Self {
+        IndexEntry {
+            file,
+            size: 0,
+            mtime: DateTime::<Utc>::UNIX_EPOCH,
+            hash: None,
+        }
+    }
+
+    // from_slice decodes a stored record. Older agents stored a bare
+    // `FileResponse`; such records are upgraded with an empty size, mtime and
+    // hash so that the file gets re-checked and updated in place instead of
+    // being created a second time.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        match serde_json::from_slice::<IndexEntry>(bytes) {
+            Ok(entry) => Ok(entry),
+            Err(_) => Ok(IndexEntry::pending(serde_json::from_slice(bytes)?)),
+        }
+    }
+
+    // is_stale returns true if size or mtime differ from what was recorded
+    pub fn is_stale(&self, metadata: &fs::Metadata) -> bool {
+        self.size != metadata.len() || self.mtime != modified_time(metadata)
+    }
+}
+
+pub fn modified_time(metadata: &fs::Metadata) -> DateTime<Utc> {
+    metadata
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .unwrap_or(DateTime::<Utc>::UNIX_EPOCH)
+}
+
+// file_digest computes a hex encoded SHA-256 digest of the file content
+pub fn file_digest(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,195 @@
+use crate::agent::progress::Progress;
+
+use chrono::Utc;
+use env_logger::Target;
+use log::LevelFilter;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How often the progress lines are redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// Secrets shorter than this are not redacted, they would mangle unrelated text
+const MIN_SECRET_LEN: usize = 4;
+
+/// Format of the agent's log lines.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid log format {:?}, expected text or json", value),
+            )),
+        }
+    }
+}
+
+/// The agent's stderr. In an interactive terminal it keeps the progress of the
+/// running transfers drawn below the log lines.
+#[derive(Clone, Default)]
+pub struct Console {
+    state: Arc<Mutex<ConsoleState>>,
+}
+
+#[derive(Default)]
+struct ConsoleState {
+    progress: Option<Progress>,
+    // progress lines currently on screen
+    drawn: usize,
+    // the redraw task is running
+    redrawing: bool,
+}
+
+impl Console {
+    // show_progress redraws the progress of the transfers until the process exits.
+    // Calling it again, e.g. for a restarted agent, swaps the progress shown.
+    pub fn show_progress(&self, progress: Progress) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.progress = Some(progress);
+            if state.redrawing {
+                return;
+            }
+            state.redrawing = true;
+        }
+
+        let console = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            loop {
+                interval.tick().await;
+                console.write(&[]);
+            }
+        });
+    }
+
+    // write prints log output above the progress lines
+    fn write(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let mut out = Vec::new();
+
+        if state.drawn > 0 {
+            // move to the start of the first progress line and clear everything below
+            write!(out, "\x1b[{}F\x1b[J", state.drawn).unwrap();
+        }
+        out.extend_from_slice(data);
+
+        state.drawn = 0;
+        if let Some(progress) = &state.progress {
+            for line in progress.lines() {
+                writeln!(out, "{}", line).unwrap();
+                state.drawn += 1;
+            }
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(&out);
+        let _ = stderr.flush();
+    }
+}
+
+// ConsoleWriter hands the formatted log records to the console
+struct ConsoleWriter(Console);
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.write(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// init sets up the logger: records of the agent at `level` and above, warnings
+// of its dependencies, `RUST_LOG` adds its own filters on top. The secrets are
+// redacted from every line.
+pub fn init(
+    level: &str,
+    format: LogFormat,
+    secrets: Vec<String>,
+) -> Result<Console, std::io::Error> {
+    let level = LevelFilter::from_str(level).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid log level {:?}, expected error, warn, info, debug or trace",
+                level
+            ),
+        )
+    })?;
+    let secrets: Vec<String> = secrets
+        .into_iter()
+        .filter(|secret| secret.len() >= MIN_SECRET_LEN)
+        .collect();
+
+    let console = Console::default();
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Warn);
+    builder.filter_module("memora", level);
+    builder.filter_module("agent", level);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    builder.format(move |buf, record| {
+        let message = redact(record.args().to_string(), &secrets);
+
+        match format {
+            LogFormat::Text => writeln!(
+                buf,
+                "{} {:<5} {}",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                record.level(),
+                message
+            ),
+            LogFormat::Json => writeln!(
+                buf,
+                "{}",
+                json!({
+                    "timestamp": Utc::now(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": message,
+                })
+            ),
+        }
+    });
+    builder.target(Target::Pipe(Box::new(ConsoleWriter(console.clone()))));
+    builder.try_init().map_err(std::io::Error::other)?;
+
+    Ok(console)
+}
+
+// is_interactive reports whether progress can be drawn on stderr
+pub fn is_interactive() -> bool {
+    std::io::stderr().is_terminal()
+}
+
+fn redact(mut message: String, secrets: &[String]) -> String {
+    for secret in secrets {
+        if message.contains(secret.as_str()) {
+            message = message.replace(secret.as_str(), "[redacted]");
+        }
+    }
+
+    message
+}
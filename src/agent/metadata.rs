@@ -0,0 +1,297 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+
+/// How symbolic links in the synced directories are treated.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Sync whatever the link points to as if it was in its place
+    #[default]
+    Follow,
+    /// Store the link itself, restores recreate it
+    Store,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "store" => Ok(SymlinkPolicy::Store),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid symlink policy {:?}, expected follow or store",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// POSIX attributes of a synced path, captured at scan time and stored with
+/// the file on the server so that restores can apply them again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileMetadata {
+    /// Permission bits, including setuid, setgid and sticky
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified_at: DateTime<Utc>,
+    pub accessed_at: DateTime<Utc>,
+    /// Target of a stored symbolic link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+    /// Extended attributes, with base64 encoded values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    // capture reads the attributes of the path. Metadata read without following
+    // a link describes the link itself, its target is recorded.
+    pub fn capture(path: &Path, metadata: &fs::Metadata) -> Result<Self, std::io::Error> {
+        let is_symlink = metadata.file_type().is_symlink();
+        let symlink_target = match is_symlink {
+            true => Some(fs::read_link(path)?.to_string_lossy().to_string()),
+            false => None,
+        };
+
+        Ok(Self {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            modified_at: timestamp(metadata.mtime(), metadata.mtime_nsec()),
+            accessed_at: timestamp(metadata.atime(), metadata.atime_nsec()),
+            symlink_target,
+            xattrs: xattr::list(path, !is_symlink)?
+                .into_iter()
+                .map(|(name, value)| (name, STANDARD.encode(value)))
+                .collect(),
+        })
+    }
+
+    // parse decodes the metadata stored on the server, files synced before it
+    // was recorded have none
+    pub fn parse(value: Option<&str>) -> Option<Self> {
+        match serde_json::from_str(value?) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                log::warn!("Ignoring invalid file metadata: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("metadata serializes")
+    }
+
+    // matches reports whether the metadata stored on the server has the same
+    // attributes. Times are left out, the index tracks them and reads change atime.
+    pub fn matches(&self, stored: Option<&str>) -> bool {
+        match Self::parse(stored) {
+            Some(stored) => {
+                self.mode == stored.mode
+                    && self.uid == stored.uid
+                    && self.gid == stored.gid
+                    && self.symlink_target == stored.symlink_target
+                    && self.xattrs == stored.xattrs
+            }
+            None => false,
+        }
+    }
+
+    // apply sets the attributes on the path, a link is changed itself and not
+    // its target. Owners and extended attributes the process may not set are skipped.
+    pub fn apply(&self, path: &Path) -> Result<(), std::io::Error> {
+        let is_symlink = self.symlink_target.is_some();
+
+        // before the mode, changing the owner clears the setuid and setgid bits
+        if let Err(e) = std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid)) {
+            if e.kind() != std::io::ErrorKind::PermissionDenied {
+                return Err(e);
+            }
+            log::debug!("Keeping the owner of {}: {}", path.to_string_lossy(), e);
+        }
+
+        // the mode of a link is not used, setting it would change the target
+        if !is_symlink {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        }
+
+        for (name, value) in &self.xattrs {
+            let value = STANDARD.decode(value).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid value of extended attribute {}", name),
+                )
+            })?;
+
+            if let Err(e) = xattr::set(path, name, &value, !is_symlink) {
+                log::warn!(
+                    "Can't set extended attribute {} on {}: {}",
+                    name,
+                    path.to_string_lossy(),
+                    e
+                );
+            }
+        }
+
+        filetime::set_symlink_file_times(
+            path,
+            file_time(&self.accessed_at),
+            file_time(&self.modified_at),
+        )
+    }
+}
+
+fn timestamp(seconds: i64, nanoseconds: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(seconds, nanoseconds as u32).unwrap_or(DateTime::<Utc>::UNIX_EPOCH)
+}
+
+fn file_time(time: &DateTime<Utc>) -> FileTime {
+    FileTime::from_unix_time(time.timestamp(), time.timestamp_subsec_nanos())
+}
+
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::collections::BTreeMap;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    // list reads the extended attributes of the path, of a link itself unless
+    // `follow` is set. Filesystems without them have none.
+    pub fn list(path: &Path, follow: bool) -> Result<BTreeMap<String, Vec<u8>>, std::io::Error> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+
+        let names = match read(|buf, size| unsafe {
+            match follow {
+                true => libc::listxattr(path.as_ptr(), buf.cast(), size),
+                false => libc::llistxattr(path.as_ptr(), buf.cast(), size),
+            }
+        }) {
+            Ok(names) => names,
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut attributes = BTreeMap::new();
+        for name in names
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+        {
+            // names that aren't valid UTF-8 can't be stored in the JSON metadata
+            let Ok(text) = std::str::from_utf8(name) else {
+                continue;
+            };
+            let c_name = c_string(name)?;
+
+            let value = match read(|buf, size| unsafe {
+                match follow {
+                    true => libc::getxattr(path.as_ptr(), c_name.as_ptr(), buf.cast(), size),
+                    false => libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buf.cast(), size),
+                }
+            }) {
+                Ok(value) => value,
+                // removed since it was listed
+                Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+                Err(e) => return Err(e),
+            };
+            attributes.insert(text.to_string(), value);
+        }
+
+        Ok(attributes)
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8], follow: bool) -> Result<(), std::io::Error> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+
+        let result = unsafe {
+            match follow {
+                true => libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                ),
+                false => libc::lsetxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                ),
+            }
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    // read asks for the size of a list or value first, then reads it. A value
+    // that grew in between is read again.
+    fn read(mut call: impl FnMut(*mut u8, usize) -> isize) -> Result<Vec<u8>, std::io::Error> {
+        loop {
+            let size = call(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let mut buf = vec![0u8; size as usize];
+            let read = call(buf.as_mut_ptr(), buf.len());
+            if read >= 0 {
+                buf.truncate(read as usize);
+                return Ok(buf);
+            }
+
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    fn c_string(bytes: &[u8]) -> Result<CString, std::io::Error> {
+        CString::new(bytes).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path contains a NUL byte")
+        })
+    }
+}
+
+// Extended attributes are only read and written on Linux, elsewhere files have none
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    pub fn list(_path: &Path, _follow: bool) -> Result<BTreeMap<String, Vec<u8>>, std::io::Error> {
+        Ok(BTreeMap::new())
+    }
+
+    pub fn set(
+        _path: &Path,
+        _name: &str,
+        _value: &[u8],
+        _follow: bool,
+    ) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Extended attributes are not supported on this platform",
+        ))
+    }
+}
@@ -1 +1,26 @@
 pub mod agent;
+pub mod api;
+pub mod chunks;
+pub mod compress;
+pub mod config;
+pub mod control;
+pub mod crypto;
+pub mod device;
+pub mod ignore;
+pub mod index;
+pub mod logging;
+pub mod metadata;
+pub mod multipart;
+pub mod plan;
+pub mod progress;
+pub mod queue;
+pub mod reindex;
+pub mod remote;
+pub mod restore;
+pub mod roots;
+pub mod service;
+pub mod snapshot;
+pub mod sync;
+pub mod throttle;
+pub mod transfer;
+pub mod verify;
@@ -0,0 +1,317 @@
+use crate::agent::api::ApiClient;
+use crate::agent::compress::UploadSource;
+use crate::agent::crypto::{encrypted_size, ContentSealer, FileKey};
+use crate::agent::index::modified_time;
+use crate::agent::progress;
+use crate::agent::throttle::Throttle;
+use crate::agent::transfer::{encrypted_body, file_part_body};
+use crate::schema::file::{
+    CompletedPart, FileResponse, MultipartCompleteRequest, MultipartPartsRequest,
+};
+
+use chrono::{DateTime, Utc};
+use fjall::PartitionHandle;
+use reqwest::header::{CONTENT_LENGTH, ETAG};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+
+// S3 limits: parts are at least 5 MiB, except the last one, and at most 10000 per upload
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_PARTS: u64 = 10000;
+// Parts presigned per request, the URLs expire so they are fetched as the upload goes
+const PRESIGN_BATCH: usize = 10;
+
+/// Progress of a multipart upload, persisted after every part so an
+/// interrupted upload can resume where it stopped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultipartState {
+    pub file: FileResponse,
+    pub upload_id: String,
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+    pub hash: String,
+    pub part_size: u64,
+    pub parts: Vec<CompletedPart>,
+    // nonce prefix of the encrypted content, parts sent again must encrypt the same way
+    #[serde(default)]
+    pub nonce: Option<String>,
+    // parts of compressed content are cut from the compressed copy of the file
+    #[serde(default)]
+    pub codec: Option<String>,
+    // and at this level, another one compresses the same content to other bytes
+    #[serde(default)]
+    pub level: Option<i32>,
+}
+
+/// Multipart uploads of large files, tracked in the fjall `multipart` partition.
+#[derive(Clone)]
+pub struct MultipartUploads {
+    db: PartitionHandle,
+    threshold: u64,
+    part_size: u64,
+    throttle: Throttle,
+}
+
+impl MultipartUploads {
+    pub fn new(db: PartitionHandle, threshold: u64, part_size: u64, throttle: Throttle) -> Self {
+        Self {
+            db,
+            threshold,
+            part_size: part_size.max(MIN_PART_SIZE),
+            throttle,
+        }
+    }
+
+    // applies reports whether a file of this size is uploaded in parts
+    pub fn applies(&self, size: u64) -> bool {
+        size >= self.threshold
+    }
+
+    // resume returns the unfinished upload of the path if the file did not
+    // change since it started. Uploads of an older version are aborted.
+    pub async fn resume(
+        &self,
+        api: &ApiClient,
+        path: &Path,
+        metadata: &fs::Metadata,
+        hash: &str,
+        source: &UploadSource,
+    ) -> Result<Option<MultipartState>, std::io::Error> {
+        let state = match self.load(path).await? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+
+        if state.size == metadata.len()
+            && state.mtime == modified_time(metadata)
+            && state.hash == hash
+            && state.codec.as_deref() == source.codec.map(|codec| codec.name())
+            && state.level == source.level
+        {
+            return Ok(Some(state));
+        }
+
+        if let Err(e) = api
+            .abort_multipart_upload(state.file.id, &state.upload_id)
+            .await
+        {
+            log::error!("Error aborting outdated upload: {}", e);
+        }
+        self.remove(path).await?;
+
+        Ok(None)
+    }
+
+    // upload sends the file's content from the source in parts, skipping the
+    // parts a resumed upload already has
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload(
+        &self,
+        api: &ApiClient,
+        client: &Client,
+        path: &Path,
+        source: &UploadSource,
+        file: &FileResponse,
+        metadata: &fs::Metadata,
+        hash: &str,
+        key: Option<Arc<FileKey>>,
+        resumed: Option<MultipartState>,
+    ) -> Result<u64, std::io::Error> {
+        let result = self
+            .upload_parts(
+                api, client, path, source, file, metadata, hash, key, resumed,
+            )
+            .await;
+
+        // The server cleaned up the upload, the next attempt starts over
+        if let Err(e) = &result {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                self.remove(path).await?;
+            }
+        }
+
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn upload_parts(
+        &self,
+        api: &ApiClient,
+        client: &Client,
+        path: &Path,
+        source: &UploadSource,
+        file: &FileResponse,
+        metadata: &fs::Metadata,
+        hash: &str,
+        key: Option<Arc<FileKey>>,
+        resumed: Option<MultipartState>,
+    ) -> Result<u64, std::io::Error> {
+        let size = source.size;
+        let upload_size = match key {
+            Some(_) => encrypted_size(size),
+            None => size,
+        };
+
+        let mut state = match resumed {
+            Some(state) => state,
+            None => {
+                let upload_id = api.create_multipart_upload(file.id).await?.upload_id;
+                let state = MultipartState {
+                    file: file.clone(),
+                    upload_id,
+                    size: metadata.len(),
+                    mtime: modified_time(metadata),
+                    hash: hash.to_string(),
+                    part_size: self.part_size.max(upload_size.div_ceil(MAX_PARTS)),
+                    parts: Vec::new(),
+                    nonce: key
+                        .as_ref()
+                        .map(|key| ContentSealer::new(key.clone(), size).prefix()),
+                    codec: source.codec.map(|codec| codec.name().to_string()),
+                    level: source.level,
+                };
+                self.save(path, &state).await?;
+                state
+            }
+        };
+
+        let sealer = match (key, &state.nonce) {
+            (Some(key), Some(prefix)) => Some(Arc::new(ContentSealer::resume(key, size, prefix)?)),
+            (Some(_), None) => {
+                return Err(std::io::Error::other(
+                    "Missing nonce for the encrypted upload",
+                ))
+            }
+            (None, _) => None,
+        };
+
+        let part_count = upload_size.div_ceil(state.part_size).max(1) as i32;
+        let missing: Vec<i32> = (1..=part_count)
+            .filter(|number| !state.parts.iter().any(|part| part.part_number == *number))
+            .collect();
+        // parts sent before the upload was resumed don't count towards its progress
+        progress::skip(
+            state
+                .parts
+                .iter()
+                .map(|part| {
+                    let offset = (part.part_number as u64 - 1) * state.part_size;
+                    state.part_size.min(upload_size.saturating_sub(offset))
+                })
+                .sum(),
+        );
+
+        for batch in missing.chunks(PRESIGN_BATCH) {
+            let presigned = api
+                .presign_multipart_parts(
+                    file.id,
+                    &MultipartPartsRequest {
+                        upload_id: state.upload_id.clone(),
+                        part_numbers: batch.to_vec(),
+                    },
+                )
+                .await?;
+
+            for part in presigned.parts {
+                let offset = (part.part_number as u64 - 1) * state.part_size;
+                let length = state.part_size.min(upload_size - offset);
+                let body = match &sealer {
+                    Some(sealer) => {
+                        let sealer = sealer.clone();
+                        encrypted_body(&source.path, sealer, size, offset, length, &self.throttle)
+                            .await?
+                    }
+                    None => file_part_body(&source.path, offset, length, &self.throttle).await?,
+                };
+
+                let response = client
+                    .put(part.url)
+                    .header(CONTENT_LENGTH, length)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(std::io::Error::other)?;
+
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Upload of part {} no longer exists", part.part_number),
+                    ));
+                }
+                if !response.status().is_success() {
+                    return Err(std::io::Error::other(format!(
+                        "Failed to upload part {}: HTTP {}",
+                        part.part_number,
+                        response.status()
+                    )));
+                }
+
+                let etag = response
+                    .headers()
+                    .get(ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .ok_or_else(|| std::io::Error::other("Missing ETag in part upload response"))?
+                    .to_string();
+
+                state.parts.push(CompletedPart {
+                    part_number: part.part_number,
+                    etag,
+                });
+                self.save(path, &state).await?;
+            }
+        }
+
+        state.parts.sort_by_key(|part| part.part_number);
+        api.complete_multipart_upload(
+            file.id,
+            &MultipartCompleteRequest {
+                upload_id: state.upload_id.clone(),
+                parts: state.parts,
+            },
+        )
+        .await?;
+
+        self.remove(path).await?;
+        Ok(upload_size)
+    }
+
+    async fn load(&self, path: &Path) -> Result<Option<MultipartState>, std::io::Error> {
+        let db_clone = self.db.clone();
+        let path_clone = path.to_path_buf();
+
+        let item = spawn_blocking(move || db_clone.get(path_clone.to_string_lossy().as_bytes()))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)?;
+
+        match item {
+            Some(item) => Ok(Some(serde_json::from_slice(&item)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn save(&self, path: &Path, state: &MultipartState) -> Result<(), std::io::Error> {
+        let db_clone = self.db.clone();
+        let path_clone = path.to_path_buf();
+        let value = serde_json::to_string(state)?;
+
+        spawn_blocking(move || db_clone.insert(path_clone, value))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+
+    async fn remove(&self, path: &Path) -> Result<(), std::io::Error> {
+        let db_clone = self.db.clone();
+        let path_clone = path.to_path_buf();
+
+        spawn_blocking(move || db_clone.remove(path_clone))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+}
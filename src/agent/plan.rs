@@ -0,0 +1,554 @@
+use crate::agent::agent::Agent;
+use crate::agent::config::AgentConfig;
+use crate::agent::ignore::IgnoreRules;
+use crate::agent::index::{file_digest, IndexEntry};
+use crate::agent::metadata::{FileMetadata, SymlinkPolicy};
+use crate::agent::progress::format_bytes;
+use crate::agent::queue::RetryQueue;
+use crate::agent::roots::Roots;
+use crate::schema::file::FileType;
+
+use fjall::{Config, Keyspace, PartitionHandle};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::task::spawn_blocking;
+
+// Files and directories of the state database, the rest of the state directory
+// isn't needed for a plan
+const STATE_DATABASE: [&str; 3] = ["journals", "partitions", "version"];
+
+/// What a sync would do to a path on the server.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Create,
+    Update,
+    Delete,
+    Rename,
+}
+
+/// A single change of a plan.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlannedChange {
+    pub action: PlanAction,
+    pub path: PathBuf,
+    /// Previous path of a renamed file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<PathBuf>,
+    pub file_type: String,
+    /// Bytes to upload, before compression and deduplication
+    pub size: u64,
+}
+
+/// The changes the next sync of the directory would make, worked out from the
+/// local tree and the index alone.
+#[derive(Serialize, Debug, Default)]
+pub struct Plan {
+    pub changes: Vec<PlannedChange>,
+}
+
+impl Plan {
+    // count returns the number of changes of the kind and the bytes they upload
+    pub fn count(&self, action: PlanAction) -> (usize, u64) {
+        self.changes
+            .iter()
+            .filter(|change| change.action == action)
+            .fold((0, 0), |(count, size), change| {
+                (count + 1, size + change.size)
+            })
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            match (&change.action, &change.from) {
+                (PlanAction::Rename, Some(from)) => writeln!(
+                    f,
+                    "rename  {} -> {}",
+                    from.to_string_lossy(),
+                    change.path.to_string_lossy()
+                )?,
+                (action, _) => writeln!(
+                    f,
+                    "{:<7} {}",
+                    format!("{:?}", action).to_lowercase(),
+                    change.path.to_string_lossy()
+                )?,
+            }
+        }
+
+        let (creates, created) = self.count(PlanAction::Create);
+        let (updates, updated) = self.count(PlanAction::Update);
+        let (deletes, _) = self.count(PlanAction::Delete);
+        let (renames, _) = self.count(PlanAction::Rename);
+
+        writeln!(
+            f,
+            "{} to create ({}), {} to update ({}), {} to delete, {} to rename",
+            creates,
+            format_bytes(created),
+            updates,
+            format_bytes(updated),
+            deletes,
+            renames
+        )
+    }
+}
+
+/// What a walk of the synced directories found to differ from the index. The
+/// sync carries it out, a plan only reports it.
+pub(super) struct Walk {
+    pub steps: Vec<Step>,
+    // files the index has no entry for, uploaded or matched with a removed file
+    pub created: Vec<PathBuf>,
+    // indexed paths that are gone from disk or became ignored
+    pub removed: Vec<(PathBuf, IndexEntry)>,
+}
+
+/// A change found on disk, in the order of the walk so that directories come
+/// before what they hold.
+pub(super) enum Step {
+    // a directory that is new or whose attributes changed
+    Directory {
+        path: PathBuf,
+        metadata: fs::Metadata,
+        attributes: FileMetadata,
+        entry: Option<IndexEntry>,
+    },
+    // a link stored as a link that is new or whose target or attributes changed
+    Link {
+        path: PathBuf,
+        metadata: fs::Metadata,
+        attributes: FileMetadata,
+        entry: Option<IndexEntry>,
+    },
+    // a file with the size and mtime last synced but other attributes
+    Attributes {
+        path: PathBuf,
+        attributes: FileMetadata,
+        entry: IndexEntry,
+    },
+    // a file whose size or mtime changed, its content may have
+    Modified {
+        path: PathBuf,
+        entry: IndexEntry,
+    },
+}
+
+/// Compares the synced directories with the index. It only reads the local
+/// tree and the state database, the agent uses it for every sync and
+/// `agent plan` for a dry run.
+#[derive(Clone)]
+pub struct Planner {
+    pub(super) roots: Roots,
+    pub(super) ignore: IgnoreRules,
+    pub(super) symlinks: SymlinkPolicy,
+    pub(super) remove_ignored: bool,
+    pub(super) db: PartitionHandle,
+    pub(super) queue: RetryQueue,
+}
+
+impl Planner {
+    // walk visits the given paths and everything below them. Paths waiting for a
+    // retry are left alone until it is due, paths that can't be read are logged
+    // and kept as they are.
+    pub(super) async fn walk(&self, paths: &[PathBuf]) -> Result<Walk, std::io::Error> {
+        let mut walk = Walk {
+            steps: Vec::new(),
+            created: Vec::new(),
+            removed: Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        let mut unreadable: Vec<PathBuf> = Vec::new();
+
+        // Pick up edits to the .memoraignore files
+        self.ignore.reload();
+        let mut stack = Vec::new(); // Stack to manage directories to visit
+
+        for root in paths {
+            if self.roots.is_root(root) || self.visit(root, &mut walk, &mut seen).await? {
+                stack.push(root.clone());
+            }
+        }
+
+        // Iterate while there are directories to process
+        while let Some(dir) = stack.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::error!("Error reading {}: {}", dir.to_string_lossy(), e);
+                    unreadable.push(dir);
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        log::error!("Error reading {}: {}", dir.to_string_lossy(), e);
+                        unreadable.push(dir.clone());
+                        continue;
+                    }
+                };
+
+                match self.visit(&path, &mut walk, &mut seen).await {
+                    // Add the subdirectory to the stack for later processing
+                    Ok(true) => stack.push(path),
+                    Ok(false) => {}
+                    // Gone since the directory was listed, it counts as removed
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound && !path.exists() => {
+                        seen.remove(&path);
+                    }
+                    Err(e) => {
+                        log::error!("Error reading {}: {}", path.to_string_lossy(), e);
+                        unreadable.push(path);
+                    }
+                }
+            }
+        }
+
+        // Entries that are still indexed but no longer exist on disk or became ignored.
+        // What is under a path that couldn't be read is left as it is.
+        walk.removed = self
+            .indexed_entries(paths)
+            .await?
+            .into_iter()
+            .filter(|(path, _)| !seen.contains(path))
+            .filter(|(path, _)| !unreadable.iter().any(|dir| path.starts_with(dir)))
+            .collect();
+
+        // Ignored paths stay on the server unless asked otherwise
+        if !self.remove_ignored {
+            walk.removed.retain(|(path, entry)| {
+                let is_dir = entry.file.file_type == FileType::DIRECTORY.to_string();
+                !self.ignore.is_ignored(path, is_dir)
+            });
+        }
+
+        // Removed paths waiting for a retry are left alone until they are due
+        let mut waiting = Vec::new();
+        for (index, (path, _)) in walk.removed.iter().enumerate() {
+            if self.queue.is_waiting(path).await {
+                waiting.push(index);
+            }
+        }
+        for index in waiting.into_iter().rev() {
+            walk.removed.swap_remove(index);
+        }
+
+        Ok(walk)
+    }
+
+    // visit compares a single path found on disk with the index and returns true
+    // for directories to descend into
+    async fn visit(
+        &self,
+        path: &Path,
+        walk: &mut Walk,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<bool, std::io::Error> {
+        // Stored links are never descended into, whatever they point to
+        let is_link = self.symlinks == SymlinkPolicy::Store && path.is_symlink();
+        let is_dir = !is_link && path.is_dir();
+
+        if (!is_link && !is_dir && !path.is_file()) || self.ignore.is_ignored(path, is_dir) {
+            log::debug!("Skip ignored: {}", path.to_string_lossy());
+            return Ok(false);
+        }
+
+        seen.insert(path.to_path_buf());
+        if self.queue.is_waiting(path).await {
+            return Ok(is_dir);
+        }
+
+        let entry = Agent::lookup(self.db.clone(), path).await?;
+        if is_link || is_dir {
+            let metadata = match is_link {
+                true => fs::symlink_metadata(path)?,
+                false => fs::metadata(path)?,
+            };
+            let attributes = FileMetadata::capture(path, &metadata)?;
+
+            let file_type = match is_link {
+                true => FileType::SYMLINK,
+                false => FileType::DIRECTORY,
+            };
+            let unchanged = entry.as_ref().is_some_and(|entry| {
+                entry.file.file_type == file_type.to_string()
+                    && attributes.matches(entry.file.metadata.as_deref())
+            });
+            if unchanged {
+                log::debug!("Skip unchanged: {}", path.to_string_lossy());
+            } else if is_link {
+                walk.steps.push(Step::Link {
+                    path: path.to_path_buf(),
+                    metadata,
+                    attributes,
+                    entry,
+                });
+            } else {
+                walk.steps.push(Step::Directory {
+                    path: path.to_path_buf(),
+                    metadata,
+                    attributes,
+                    entry,
+                });
+            }
+
+            return Ok(is_dir);
+        }
+
+        let metadata = fs::metadata(path)?;
+        match entry {
+            None => walk.created.push(path.to_path_buf()),
+            Some(entry) if !entry.is_stale(&metadata) => {
+                let attributes = FileMetadata::capture(path, &metadata)?;
+                if attributes.matches(entry.file.metadata.as_deref()) {
+                    log::debug!("Skip unchanged file: {}", path.to_string_lossy());
+                } else {
+                    walk.steps.push(Step::Attributes {
+                        path: path.to_path_buf(),
+                        attributes,
+                        entry,
+                    });
+                }
+            }
+            Some(entry) => walk.steps.push(Step::Modified {
+                path: path.to_path_buf(),
+                entry,
+            }),
+        }
+
+        Ok(false)
+    }
+
+    // indexed_entries returns all index entries at or under the roots
+    pub(super) async fn indexed_entries(
+        &self,
+        roots: &[PathBuf],
+    ) -> Result<Vec<(PathBuf, IndexEntry)>, std::io::Error> {
+        let db_clone = self.db.clone();
+        let roots = roots.to_vec();
+        let synced = self.roots.clone();
+
+        spawn_blocking(move || {
+            let mut entries = Vec::new();
+
+            for root in roots {
+                if !synced.is_root(&root) {
+                    if let Some(value) = db_clone
+                        .get(root.to_string_lossy().as_bytes())
+                        .map_err(std::io::Error::other)?
+                    {
+                        entries.push((root.clone(), IndexEntry::from_slice(&value)?));
+                    }
+                }
+
+                // join("") appends a trailing separator so sibling directories sharing
+                // the name prefix are not matched
+                let prefix = root.join("").to_string_lossy().to_string();
+                for item in db_clone.prefix(prefix.as_bytes()) {
+                    let (key, value) = item.map_err(std::io::Error::other)?;
+                    let path = PathBuf::from(String::from_utf8_lossy(&key).to_string());
+                    entries.push((path, IndexEntry::from_slice(&value)?));
+                }
+            }
+
+            Ok(entries)
+        })
+        .await
+        .expect("join failed")
+    }
+
+    // find_moved looks for a removed file with the same size and content hash
+    pub(super) async fn find_moved(
+        path: &Path,
+        removed: &[(PathBuf, IndexEntry)],
+    ) -> Result<Option<usize>, std::io::Error> {
+        let size = fs::metadata(path)?.len();
+        let candidates = removed
+            .iter()
+            .any(|(_, entry)| entry.hash.is_some() && entry.size == size);
+
+        // Only hash the new file if there is something it could match
+        if !candidates {
+            return Ok(None);
+        }
+
+        let path_clone = path.to_path_buf();
+        let hash = spawn_blocking(move || file_digest(&path_clone))
+            .await
+            .expect("join failed")?;
+
+        Ok(removed
+            .iter()
+            .position(|(_, entry)| entry.hash.as_deref() == Some(hash.as_str())))
+    }
+
+    // plan lists what the next sync of the synced directories would change on the
+    // server. Modified files are hashed to leave out the ones whose content did
+    // not change.
+    pub async fn plan(&self) -> Result<Plan, std::io::Error> {
+        let walk = self.walk(&self.roots.dirs()).await?;
+        let mut plan = Plan::default();
+        let mut removed = walk.removed;
+
+        for step in walk.steps {
+            let planned = match step {
+                Step::Directory { path, entry, .. } => {
+                    change(action(&entry), &path, FileType::DIRECTORY, 0)
+                }
+                Step::Link { path, entry, .. } => {
+                    change(action(&entry), &path, FileType::SYMLINK, 0)
+                }
+                Step::Attributes { path, .. } => {
+                    change(PlanAction::Update, &path, FileType::FILE, 0)
+                }
+                Step::Modified { path, entry } => {
+                    let metadata = fs::metadata(&path)?;
+                    let path_clone = path.clone();
+                    let hash = spawn_blocking(move || file_digest(&path_clone))
+                        .await
+                        .expect("join failed")?;
+
+                    // unchanged content with changed attributes only updates the metadata
+                    if entry.hash.as_deref() != Some(hash.as_str()) {
+                        change(PlanAction::Update, &path, FileType::FILE, metadata.len())
+                    } else if !FileMetadata::capture(&path, &metadata)?
+                        .matches(entry.file.metadata.as_deref())
+                    {
+                        change(PlanAction::Update, &path, FileType::FILE, 0)
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            plan.changes.push(planned);
+        }
+
+        for path in walk.created {
+            if let Some(index) = Self::find_moved(&path, &removed).await? {
+                let (old_path, _) = removed.swap_remove(index);
+                plan.changes.push(PlannedChange {
+                    from: Some(old_path),
+                    ..change(PlanAction::Rename, &path, FileType::FILE, 0)
+                });
+                continue;
+            }
+
+            let size = fs::metadata(&path)?.len();
+            plan.changes
+                .push(change(PlanAction::Create, &path, FileType::FILE, size));
+        }
+
+        for (path, entry) in removed {
+            plan.changes.push(PlannedChange {
+                action: PlanAction::Delete,
+                path,
+                from: None,
+                file_type: entry.file.file_type,
+                size: 0,
+            });
+        }
+
+        plan.changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(plan)
+    }
+}
+
+/// A copy of the state database to plan from. The agent syncing the directory
+/// may be running and holds the state directory, its database is copied and
+/// the copy is removed when this is dropped.
+pub struct StateCopy {
+    planner: Planner,
+    keyspace: Option<Keyspace>,
+    dir: PathBuf,
+}
+
+impl StateCopy {
+    pub fn open(config: &AgentConfig) -> Result<Self, std::io::Error> {
+        let roots = config.roots()?;
+        let dir = config
+            .state_dir
+            .join(format!("plan-{}", rand::random::<u64>()));
+
+        for name in STATE_DATABASE {
+            let from = config.state_dir.join(name);
+            if from.exists() {
+                copy_tree(&from, &dir.join(name))?;
+            }
+        }
+
+        let keyspace = Config::new(&dir).open().map_err(std::io::Error::other)?;
+        let planner = Planner {
+            ignore: IgnoreRules::new(&roots.dirs(), &config.exclude)?,
+            roots,
+            symlinks: config.symlinks,
+            remove_ignored: config.remove_ignored,
+            db: keyspace
+                .open_partition("tasks", Default::default())
+                .map_err(std::io::Error::other)?,
+            queue: RetryQueue::new(
+                keyspace
+                    .open_partition("queue", Default::default())
+                    .map_err(std::io::Error::other)?,
+            ),
+        };
+
+        Ok(Self {
+            planner,
+            keyspace: Some(keyspace),
+            dir,
+        })
+    }
+
+    pub async fn plan(&self) -> Result<Plan, std::io::Error> {
+        self.planner.plan().await
+    }
+}
+
+impl Drop for StateCopy {
+    fn drop(&mut self) {
+        drop(self.keyspace.take());
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// action is what a sync does with a path given its index entry
+fn action(entry: &Option<IndexEntry>) -> PlanAction {
+    match entry {
+        Some(_) => PlanAction::Update,
+        None => PlanAction::Create,
+    }
+}
+
+// copy_tree copies a directory with the files in it, or a single file
+fn copy_tree(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if !from.is_dir() {
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+fn change(action: PlanAction, path: &Path, file_type: FileType, size: u64) -> PlannedChange {
+    PlannedChange {
+        action,
+        path: path.to_path_buf(),
+        from: None,
+        file_type: file_type.to_string(),
+        size,
+    }
+}
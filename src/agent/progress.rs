@@ -0,0 +1,274 @@
+use futures::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Transfers listed one by one, the others are summed up
+const MAX_LISTED: usize = 5;
+// Longest file name shown, so that progress lines don't wrap
+const MAX_NAME_LEN: usize = 40;
+
+tokio::task_local! {
+    // the transfer the bytes moved by the current task are counted for
+    static CURRENT: FileProgress;
+}
+
+/// Bytes moved by the agent's uploads and downloads, per file and in total
+/// since the agent was last idle.
+#[derive(Clone, Default)]
+pub struct Progress {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    next_id: u64,
+    files: BTreeMap<u64, FileState>,
+    total: u64,
+    done: u64,
+    started: Option<Instant>,
+}
+
+struct FileState {
+    name: String,
+    size: u64,
+    done: u64,
+    started: Instant,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // start counts a transfer of `size` bytes until the returned handle is dropped
+    pub fn start(&self, name: &str, size: u64) -> FileProgress {
+        let mut state = self.state.lock().unwrap();
+        if state.files.is_empty() {
+            state.total = 0;
+            state.done = 0;
+            state.started = Some(Instant::now());
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.total += size;
+        state.files.insert(
+            id,
+            FileState {
+                name: name.to_string(),
+                size,
+                done: 0,
+                started: Instant::now(),
+            },
+        );
+
+        FileProgress {
+            handle: Arc::new(FileHandle {
+                progress: self.clone(),
+                id,
+            }),
+        }
+    }
+
+    // lines renders the transfers in progress, nothing while the agent is idle
+    pub fn lines(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let started = match state.started {
+            Some(started) if !state.files.is_empty() => started,
+            _ => return Vec::new(),
+        };
+
+        let mut lines: Vec<String> = state
+            .files
+            .values()
+            .take(MAX_LISTED)
+            .map(|file| {
+                format!(
+                    "  {}  {}",
+                    short_name(&file.name),
+                    progress_line(file.done.min(file.size), file.size, file.started)
+                )
+            })
+            .collect();
+        if state.files.len() > MAX_LISTED {
+            lines.push(format!("  and {} more", state.files.len() - MAX_LISTED));
+        }
+
+        lines.push(format!(
+            "Total ({} active)  {}",
+            state.files.len(),
+            progress_line(state.done.min(state.total), state.total, started)
+        ));
+
+        lines
+    }
+}
+
+/// A single transfer, counted in the progress as long as a clone of it is alive.
+#[derive(Clone)]
+pub struct FileProgress {
+    handle: Arc<FileHandle>,
+}
+
+struct FileHandle {
+    progress: Progress,
+    id: u64,
+}
+
+impl FileProgress {
+    // scope counts the bytes the future moves for this transfer
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    // current returns the transfer of the running task, if any
+    pub fn current() -> Option<FileProgress> {
+        CURRENT.try_with(|file| file.clone()).ok()
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.update(|state, file| {
+            file.done += bytes;
+            state.done += bytes;
+        });
+    }
+
+    // resize corrects the number of bytes to move, e.g. once the file is compressed
+    pub fn resize(&self, size: u64) {
+        self.update(|state, file| {
+            state.total = state.total - file.size + size;
+            file.size = size;
+        });
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ProgressState, &mut FileState)) {
+        let mut state = self.handle.progress.state.lock().unwrap();
+        if let Some(mut file) = state.files.remove(&self.handle.id) {
+            f(&mut state, &mut file);
+            state.files.insert(self.handle.id, file);
+        }
+    }
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        let mut state = self.progress.state.lock().unwrap();
+        if let Some(file) = state.files.remove(&self.id) {
+            // bytes that were never moved, e.g. after an error, don't count towards the total
+            state.total -= file.size - file.done.min(file.size);
+        }
+    }
+}
+
+// record counts bytes moved for the current task's transfer
+pub fn record(bytes: u64) {
+    if let Some(file) = FileProgress::current() {
+        file.add(bytes);
+    }
+}
+
+// resize sets the number of bytes the current task's transfer moves
+pub fn resize(size: u64) {
+    if let Some(file) = FileProgress::current() {
+        file.resize(size);
+    }
+}
+
+// skip takes bytes that don't have to be moved, e.g. parts uploaded before, off
+// the current task's transfer
+pub fn skip(bytes: u64) {
+    if let Some(file) = FileProgress::current() {
+        file.update(|state, file| {
+            let bytes = bytes.min(file.size);
+            file.size -= bytes;
+            state.total -= bytes;
+        });
+    }
+}
+
+// track counts the data of a request body for the transfer of the task creating
+// it, the body itself is sent from the HTTP client's connection task
+pub fn track<S, T, E>(stream: S) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+    T: AsRef<[u8]>,
+{
+    let file = FileProgress::current();
+
+    stream.inspect(move |item| {
+        if let (Some(file), Ok(data)) = (&file, item) {
+            file.add(data.as_ref().len() as u64);
+        }
+    })
+}
+
+// format_bytes renders a number of bytes with a binary unit
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// format_duration renders a duration in the two largest units
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+// progress_line renders the bytes moved, the throughput and the time left
+fn progress_line(done: u64, size: u64, started: Instant) -> String {
+    let elapsed = started.elapsed().as_secs_f64();
+    let rate = if elapsed > 0.0 {
+        done as f64 / elapsed
+    } else {
+        0.0
+    };
+
+    let eta = if rate > 0.0 {
+        format_duration(Duration::from_secs_f64((size - done) as f64 / rate))
+    } else {
+        "-".to_string()
+    };
+
+    format!(
+        "{} / {}  {}/s  ETA {}",
+        format_bytes(done),
+        format_bytes(size),
+        format_bytes(rate as u64),
+        eta
+    )
+}
+
+// short_name cuts long file names in the middle
+fn short_name(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    if chars.len() <= MAX_NAME_LEN {
+        return format!("{:<width$}", name, width = MAX_NAME_LEN);
+    }
+
+    let half = (MAX_NAME_LEN - 1) / 2;
+    format!(
+        "{}…{}",
+        chars[..half].iter().collect::<String>(),
+        chars[chars.len() - (MAX_NAME_LEN - 1 - half)..]
+            .iter()
+            .collect::<String>()
+    )
+}
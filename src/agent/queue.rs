@@ -0,0 +1,193 @@
+use chrono::{DateTime, Utc};
+use fjall::PartitionHandle;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+
+// Delay before the first retry, doubled on every further attempt
+const BACKOFF_BASE_SECS: f64 = 5.0;
+// Upper bound for the delay between two attempts
+const BACKOFF_MAX_SECS: f64 = 60.0 * 60.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Operation {
+    Upload,
+    CreateDirectory,
+    Delete,
+    Move,
+}
+
+// QueueEntry is a failed operation waiting to be retried
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueEntry {
+    pub path: PathBuf,
+    pub operation: Operation,
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: String,
+}
+
+// RetryQueue is the persistent queue of failed operations, stored in its own
+// fjall partition. Paths in the queue are skipped by the scanner until their next attempt is
+// due, the failed attempts are retried with jittered exponential backoff.
+// The first success after a failure brings all queued retries forward, the
+// failures were likely the server or the network being away.
+#[derive(Clone)]
+pub struct RetryQueue {
+    db: PartitionHandle,
+    failing: Arc<AtomicBool>,
+}
+
+impl RetryQueue {
+    pub fn new(db: PartitionHandle) -> Self {
+        Self {
+            db,
+            failing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // record_failure queues the path or pushes its next attempt further out
+    pub async fn record_failure(
+        &self,
+        path: &Path,
+        operation: Operation,
+        error: &std::io::Error,
+    ) -> Result<(), std::io::Error> {
+        let attempts = self.get(path).await?.map_or(0, |entry| entry.attempts) + 1;
+        let entry = QueueEntry {
+            path: path.to_path_buf(),
+            operation,
+            attempts,
+            next_attempt: Utc::now() + backoff(attempts),
+            last_error: error.to_string(),
+        };
+
+        log::warn!(
+            "Queued {:?} of {} for retry #{} at {}",
+            entry.operation,
+            path.to_string_lossy(),
+            attempts,
+            entry.next_attempt
+        );
+
+        self.failing.store(true, Ordering::Relaxed);
+        self.insert(&entry).await
+    }
+
+    // remove drops the path from the queue after a successful attempt
+    pub async fn remove(&self, path: &Path) -> Result<(), std::io::Error> {
+        let db_clone = self.db.clone();
+        let path_clone = path.to_path_buf();
+
+        spawn_blocking(move || db_clone.remove(path_clone))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)?;
+
+        if self.failing.swap(false, Ordering::Relaxed) {
+            self.bring_forward().await?;
+        }
+        Ok(())
+    }
+
+    // bring_forward makes the queued retries due now, their attempts are kept so
+    // another failure backs off further
+    async fn bring_forward(&self) -> Result<(), std::io::Error> {
+        let now = Utc::now();
+        let waiting: Vec<QueueEntry> = self
+            .entries()
+            .await?
+            .into_iter()
+            .filter(|entry| entry.next_attempt > now)
+            .collect();
+        if waiting.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Retrying {} queued operations early", waiting.len());
+        for entry in waiting {
+            self.insert(&QueueEntry {
+                next_attempt: now,
+                ..entry
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn insert(&self, entry: &QueueEntry) -> Result<(), std::io::Error> {
+        let db_clone = self.db.clone();
+        let path_clone = entry.path.clone();
+        let value = serde_json::to_string(entry)?;
+
+        spawn_blocking(move || db_clone.insert(path_clone, value))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+
+    // is_waiting reports whether the path is queued and not yet due
+    pub async fn is_waiting(&self, path: &Path) -> bool {
+        match self.get(path).await {
+            Ok(Some(entry)) => entry.next_attempt > Utc::now(),
+            _ => false,
+        }
+    }
+
+    // due returns the queued entries whose next attempt has come
+    pub async fn due(&self) -> Result<Vec<QueueEntry>, std::io::Error> {
+        let now = Utc::now();
+
+        Ok(self
+            .entries()
+            .await?
+            .into_iter()
+            .filter(|entry| entry.next_attempt <= now)
+            .collect())
+    }
+
+    pub async fn entries(&self) -> Result<Vec<QueueEntry>, std::io::Error> {
+        let db_clone = self.db.clone();
+
+        spawn_blocking(move || {
+            db_clone
+                .iter()
+                .map(|item| {
+                    let (_, value) = item.map_err(std::io::Error::other)?;
+                    Ok(serde_json::from_slice(&value)?)
+                })
+                .collect()
+        })
+        .await
+        .expect("join failed")
+    }
+
+    async fn get(&self, path: &Path) -> Result<Option<QueueEntry>, std::io::Error> {
+        let db_clone = self.db.clone();
+        let path_clone = path.to_path_buf();
+
+        let item = spawn_blocking(move || db_clone.get(path_clone.to_string_lossy().as_bytes()))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)?;
+
+        match item {
+            Some(item) => Ok(Some(serde_json::from_slice(&item)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// backoff returns the delay before the given attempt, randomized by ±50%
+// so that many failures don't retry in lockstep
+fn backoff(attempts: u32) -> chrono::Duration {
+    let exponent = attempts.saturating_sub(1).min(16) as i32;
+    let delay = (BACKOFF_BASE_SECS * 2f64.powi(exponent)).min(BACKOFF_MAX_SECS);
+    let jitter = rand::thread_rng().gen_range(0.5..1.5);
+
+    chrono::Duration::milliseconds((delay * jitter * 1000.0) as i64)
+}
@@ -0,0 +1,272 @@
+use crate::agent::agent::Agent;
+use crate::agent::index::{file_digest, IndexEntry};
+use crate::agent::metadata::SymlinkPolicy;
+use crate::model::file::File;
+use crate::schema::file::{FileResponse, FileStatus, FileType};
+use charybdis::types::Uuid;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::task::spawn_blocking;
+
+// Number of files requested per page when listing the server
+const PAGE_SIZE: i32 = 100;
+
+/// A server record for a path that already has one.
+#[derive(Serialize, Debug, Clone)]
+pub struct Duplicate {
+    pub path: PathBuf,
+    pub file_id: Uuid,
+}
+
+/// Result of rebuilding the index from the server.
+#[derive(Serialize, Debug, Default)]
+pub struct ReindexReport {
+    pub files: usize,
+    pub directories: usize,
+    pub links: usize,
+    /// Files whose content differs from their server record, the next scan uploads them over it
+    pub changed: Vec<PathBuf>,
+    /// Server records without a local path of the same type, they are left alone
+    pub server_only: usize,
+    /// Server records left out because the path already has one, e.g. created by
+    /// an agent that lost its index
+    pub duplicates: Vec<Duplicate>,
+}
+
+impl fmt::Display for ReindexReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.changed {
+            writeln!(f, "changed   {}", path.to_string_lossy())?;
+        }
+        for duplicate in &self.duplicates {
+            writeln!(
+                f,
+                "duplicate {}: {}",
+                duplicate.path.to_string_lossy(),
+                duplicate.file_id
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Indexed {} files, {} directories and {} links: {} changed locally, {} only on the server, {} duplicates",
+            self.files,
+            self.directories,
+            self.links,
+            self.changed.len(),
+            self.server_only,
+            self.duplicates.len()
+        )
+    }
+}
+
+// A server record picked for a local path
+struct Matched {
+    path: PathBuf,
+    entry: IndexEntry,
+    file_type: FileType,
+    changed: bool,
+}
+
+impl Agent {
+    // reindex rebuilds the index from the files on the server: every local path
+    // is matched to the server record with its directory and name, files with the
+    // one holding the same content. The server is only read, paths left without a
+    // record are created by the next scan.
+    pub async fn reindex(&self) -> Result<ReindexReport, std::io::Error> {
+        let mut records: HashMap<PathBuf, Vec<File>> = HashMap::new();
+        let mut report = ReindexReport::default();
+
+        let mut last_id = None;
+        loop {
+            let page = self.api.list_files(last_id, PAGE_SIZE).await?;
+            last_id = page.last_id;
+
+            for file in page.files {
+                match self.roots.local_path(&file.directory, &file.name) {
+                    Some(path) => records.entry(path).or_default().push(file),
+                    None => log::debug!("Skip file outside the prefixes: {}", file.id),
+                }
+            }
+
+            if page.is_last || last_id.is_none() {
+                break;
+            }
+        }
+
+        let mut matches = Vec::new();
+        for (path, mut files) in records {
+            // the most recently modified record wins among equals
+            files.sort_by_key(|file| std::cmp::Reverse(file.modified_at));
+            let count = files.len();
+
+            match self.match_path(&path, &files).await? {
+                Some((matched, file_id)) => {
+                    for file in files.iter().filter(|file| {
+                        file.id != file_id && file.file_type == matched.file_type.to_string()
+                    }) {
+                        report.duplicates.push(Duplicate {
+                            path: path.clone(),
+                            file_id: file.id,
+                        });
+                    }
+                    report.server_only += files
+                        .iter()
+                        .filter(|file| file.file_type != matched.file_type.to_string())
+                        .count();
+                    matches.push(matched);
+                }
+                None => report.server_only += count,
+            }
+        }
+
+        // the index is only replaced once the server was read completely
+        self.clear_index().await?;
+        for matched in matches {
+            match matched.file_type {
+                FileType::FILE => report.files += 1,
+                FileType::DIRECTORY => report.directories += 1,
+                FileType::SYMLINK => report.links += 1,
+            }
+            if matched.changed {
+                report.changed.push(matched.path.clone());
+            }
+
+            Self::register_upload(self.db.clone(), &matched.path, matched.entry).await?;
+        }
+
+        report.changed.sort();
+        report
+            .duplicates
+            .sort_by(|a, b| a.path.cmp(&b.path).then(a.file_id.cmp(&b.file_id)));
+        Ok(report)
+    }
+
+    // match_path picks the server record for a local path out of the records
+    // stored under its name, newest first. A file without a record holding the
+    // same content takes the newest one with a stale entry, so that the next
+    // scan uploads it in place.
+    async fn match_path(
+        &self,
+        path: &Path,
+        files: &[File],
+    ) -> Result<Option<(Matched, Uuid)>, std::io::Error> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let is_link = metadata.file_type().is_symlink();
+        let file_type = match (is_link, path.is_dir(), path.is_file()) {
+            (true, _, _) if self.symlinks == SymlinkPolicy::Store => FileType::SYMLINK,
+            (_, true, _) => FileType::DIRECTORY,
+            (_, _, true) => FileType::FILE,
+            _ => return Ok(None),
+        };
+
+        let is_dir = matches!(file_type, FileType::DIRECTORY);
+        if self.ignore.is_ignored(path, is_dir) {
+            return Ok(None);
+        }
+
+        let mut candidates = files
+            .iter()
+            .filter(|file| file.file_type == file_type.to_string())
+            .peekable();
+        let newest = match candidates.peek() {
+            Some(file) => (*file).clone(),
+            None => return Ok(None),
+        };
+
+        let matched = |file: File, entry: IndexEntry, changed: bool| {
+            Some((
+                Matched {
+                    path: path.to_path_buf(),
+                    entry,
+                    file_type: file_type.clone(),
+                    changed,
+                },
+                file.id,
+            ))
+        };
+
+        match file_type {
+            FileType::DIRECTORY => {
+                let entry = IndexEntry::new(
+                    FileResponse::from(newest.clone()),
+                    &fs::metadata(path)?,
+                    None,
+                );
+                Ok(matched(newest, entry, false))
+            }
+            FileType::SYMLINK => {
+                let entry = IndexEntry::new(FileResponse::from(newest.clone()), &metadata, None);
+                Ok(matched(newest, entry, false))
+            }
+            FileType::FILE => {
+                let path_clone = path.to_path_buf();
+                let hash = spawn_blocking(move || file_digest(&path_clone))
+                    .await
+                    .expect("join failed")?;
+
+                for file in candidates {
+                    if file.status != FileStatus::CLOSED.to_string() {
+                        continue;
+                    }
+                    let checksum = self.api.checksum(file.key_id.as_deref(), &hash)?;
+                    if file.checksum.as_deref() == Some(checksum.as_str()) {
+                        let entry = IndexEntry::new(
+                            FileResponse::from(file.clone()),
+                            &metadata,
+                            Some(hash),
+                        );
+                        return Ok(matched(file.clone(), entry, false));
+                    }
+                }
+
+                let entry = IndexEntry {
+                    file: FileResponse::from(newest.clone()),
+                    size: 0,
+                    mtime: DateTime::<Utc>::UNIX_EPOCH,
+                    hash: None,
+                };
+                Ok(matched(newest, entry, true))
+            }
+        }
+    }
+
+    // clear_index removes the entries of every path at or under the roots,
+    // whether they can be read or not
+    async fn clear_index(&self) -> Result<(), std::io::Error> {
+        let db_clone = self.db.clone();
+        let roots = self.roots.dirs();
+
+        spawn_blocking(move || {
+            for root in roots {
+                db_clone
+                    .remove(root.to_string_lossy().as_bytes())
+                    .map_err(std::io::Error::other)?;
+
+                let prefix = root.join("").to_string_lossy().to_string();
+                let keys = db_clone
+                    .prefix(prefix.as_bytes())
+                    .map(|item| item.map(|(key, _)| key))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(std::io::Error::other)?;
+                for key in keys {
+                    db_clone.remove(key).map_err(std::io::Error::other)?;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+        .expect("join failed")
+    }
+}
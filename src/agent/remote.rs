@@ -0,0 +1,101 @@
+use crate::agent::api::ApiClient;
+use crate::agent::config::AgentConfig;
+use crate::agent::crypto::Keyring;
+use crate::agent::progress::Progress;
+use crate::agent::restore::{restore_tree, Version};
+use crate::agent::roots::Roots;
+use crate::agent::throttle::{BandwidthSettings, Throttle};
+
+use reqwest::Client;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// RemoteAgent is the part of the agent that only talks to the server. It leaves
+// the state directory alone, so it can be used next to a running agent, e.g. to
+// restore.
+pub struct RemoteAgent {
+    pub(super) roots: Roots,
+    pub(super) keyring: Option<Arc<Keyring>>,
+
+    // Limits the number of concurrent workers
+    pub(super) semaphore: Arc<Semaphore>,
+
+    // Reqwest HTTP client
+    pub(super) client: Arc<Client>,
+
+    // Client for the memora server API
+    pub(super) api: Arc<ApiClient>,
+
+    pub(super) throttle: Throttle,
+
+    // Bytes moved by the transfers in progress
+    pub(super) progress: Progress,
+}
+
+impl RemoteAgent {
+    pub fn new(config: &AgentConfig) -> Result<Self, std::io::Error> {
+        let semaphore = Arc::new(Semaphore::new(config.workers));
+        let client = Arc::new(Client::new()); // Shared HTTP client for uploads
+        let throttle = Throttle::new(BandwidthSettings::parse(
+            config.bandwidth_limit,
+            &config.bandwidth_schedule,
+        )?);
+
+        // A passphrase is enough to decrypt, uploads are only encrypted when asked to
+        let keyring = config
+            .passphrase()?
+            .map(|passphrase| Arc::new(Keyring::new(passphrase)));
+        if config.encrypt && keyring.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Encryption needs a passphrase, set MEMORA_PASSPHRASE or passphrase_file",
+            ));
+        }
+
+        let roots = config.roots()?;
+        let api = Arc::new(ApiClient::new(
+            client.as_ref().clone(),
+            &config.server_url,
+            config.token.clone().unwrap_or_default(),
+            keyring.clone(),
+            roots.clone(),
+        ));
+
+        Ok(Self {
+            roots,
+            keyring,
+            semaphore,
+            client,
+            api,
+            throttle,
+            progress: Progress::new(),
+        })
+    }
+
+    // progress returns the progress of the downloads
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    // restore rebuilds a local tree under `to` from the files stored on the server,
+    // or from the files as the snapshot captured them
+    pub async fn restore(
+        &self,
+        to: &Path,
+        prefix: Option<&str>,
+        version: Version,
+    ) -> Result<(), std::io::Error> {
+        restore_tree(
+            self.api.clone(),
+            self.client.clone(),
+            self.semaphore.clone(),
+            self.throttle.clone(),
+            self.progress.clone(),
+            to,
+            prefix,
+            version,
+        )
+        .await
+    }
+}
@@ -0,0 +1,328 @@
+use crate::agent::api::ApiClient;
+use crate::agent::chunks::download_chunks;
+use crate::agent::compress::{Codec, Decompressor};
+use crate::agent::crypto::ContentOpener;
+use crate::agent::metadata::FileMetadata;
+use crate::agent::progress::{self, Progress};
+use crate::agent::throttle::Throttle;
+use crate::model::file::File;
+use crate::schema::file::{FileStatus, FileType};
+use charybdis::types::Uuid;
+
+use filetime::FileTime;
+use reqwest::Client;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+// Number of files requested per page when listing the server
+const PAGE_SIZE: i32 = 100;
+
+/// Which version of a file is downloaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    /// The content last uploaded
+    Current,
+    /// The content captured by the snapshot
+    Snapshot(Uuid),
+}
+
+// restore_tree downloads the user's files into `to`, limited to the files
+// under `prefix` when given, or the files as a snapshot captured them.
+// Downloads run in parallel, bounded by the semaphore. A path that fails is
+// logged and skipped, the error returned at the end tells how many failed.
+#[allow(clippy::too_many_arguments)]
+pub async fn restore_tree(
+    api: Arc<ApiClient>,
+    client: Arc<Client>,
+    semaphore: Arc<Semaphore>,
+    throttle: Throttle,
+    progress: Progress,
+    to: &Path,
+    prefix: Option<&str>,
+    version: Version,
+) -> Result<(), std::io::Error> {
+    let mut last_id = None;
+    let mut tasks = Vec::new();
+    let mut directories = Vec::new();
+    let mut links = Vec::new();
+    let mut failed = 0;
+
+    tokio::fs::create_dir_all(to).await?;
+
+    loop {
+        let page = match version {
+            Version::Current => api.list_files(last_id, PAGE_SIZE).await?,
+            Version::Snapshot(snapshot_id) => {
+                api.list_snapshot_files(snapshot_id, last_id, PAGE_SIZE)
+                    .await?
+            }
+        };
+        last_id = page.last_id;
+        failed += page.unreadable.len();
+
+        for file in page.files {
+            let path = match local_path(to, prefix, &file) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if file.file_type == FileType::DIRECTORY.to_string() {
+                tokio::fs::create_dir_all(&path).await?;
+                directories.push((path, file));
+                continue;
+            }
+
+            if file.file_type == FileType::SYMLINK.to_string() {
+                links.push((path, file));
+                continue;
+            }
+
+            if file.status != FileStatus::CLOSED.to_string() {
+                log::warn!("Skip incomplete file: {}", path.to_string_lossy());
+                continue;
+            }
+
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let api_clone = api.clone();
+            let client_clone = client.clone();
+            let throttle_clone = throttle.clone();
+            let transfer = progress.start(
+                &path.file_name().unwrap().to_string_lossy(),
+                file.size.unwrap_or(0).max(0) as u64,
+            );
+
+            tasks.push(task::spawn(async move {
+                let result = transfer
+                    .scope(download_file(
+                        api_clone,
+                        client_clone,
+                        &throttle_clone,
+                        &file,
+                        &path,
+                        version,
+                    ))
+                    .await;
+                drop(permit); // Release the semaphore permit
+                if let Err(e) = &result {
+                    log::error!("Error restoring {}: {}", path.to_string_lossy(), e);
+                }
+                result.is_ok()
+            }));
+        }
+
+        if page.is_last || last_id.is_none() {
+            break;
+        }
+    }
+
+    // Wait for all downloads to complete
+    for task in tasks {
+        match task.await {
+            Ok(true) => {}
+            Ok(false) => failed += 1,
+            Err(e) => {
+                log::error!("Restore task failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    // Links are created once the files they may point to are in place
+    for (path, file) in links {
+        if let Err(e) = create_symlink(&path, &file) {
+            log::error!("Error restoring {}: {}", path.to_string_lossy(), e);
+            failed += 1;
+        }
+    }
+
+    // Directory metadata is applied last, writing files into them changes their
+    // mtime and their mode may not allow it
+    for (path, file) in directories {
+        apply_metadata(&path, &file)?;
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(std::io::Error::other(format!(
+            "{} paths could not be restored",
+            failed
+        ))),
+    }
+}
+
+pub(super) async fn download_file(
+    api: Arc<ApiClient>,
+    client: Arc<Client>,
+    throttle: &Throttle,
+    file: &File,
+    path: &Path,
+    version: Version,
+) -> Result<(), std::io::Error> {
+    // Compressed content is decompressed as it arrives
+    let mut decompressor = match Codec::parse(file.codec.as_deref())? {
+        Some(Codec::Zstd) => Some(Decompressor::new()?),
+        None => None,
+    };
+
+    // Chunked content is put back together from the chunk store
+    if file.chunked == Some(true) {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut out = tokio::fs::File::create(path).await?;
+        download_chunks(
+            &api,
+            &client,
+            throttle,
+            file.id,
+            version,
+            decompressor,
+            &mut out,
+        )
+        .await?;
+        out.flush().await?;
+
+        log::info!("Downloaded: {}", path.to_string_lossy());
+        return apply_metadata(path, file);
+    }
+
+    let response = match version {
+        Version::Current => api.get_file(file.id).await?,
+        Version::Snapshot(snapshot_id) => api.get_snapshot_file(snapshot_id, file.id).await?,
+    };
+    let url = response
+        .presigned_url
+        .ok_or_else(|| std::io::Error::other("No download URL found"))?;
+
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(std::io::Error::other)?;
+
+    if !response.status().is_success() {
+        let kind = match response.status() {
+            reqwest::StatusCode::NOT_FOUND => std::io::ErrorKind::NotFound,
+            _ => std::io::ErrorKind::Other,
+        };
+        return Err(std::io::Error::new(
+            kind,
+            format!("Failed to download: HTTP {}", response.status()),
+        ));
+    }
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Encrypted content is decrypted as it arrives
+    let mut opener = match &file.key_id {
+        Some(key_id) => Some(ContentOpener::new(api.key(key_id)?)),
+        None => None,
+    };
+
+    let mut out = tokio::fs::File::create(path).await?;
+    while let Some(chunk) = response.chunk().await.map_err(std::io::Error::other)? {
+        throttle.acquire(chunk.len()).await;
+        progress::record(chunk.len() as u64);
+        let chunk = match opener.as_mut() {
+            Some(opener) => opener.push(&chunk)?,
+            None => chunk.to_vec(),
+        };
+        write_content(&mut out, decompressor.as_mut(), &chunk).await?;
+    }
+    if let Some(opener) = opener {
+        write_content(&mut out, decompressor.as_mut(), &opener.finish()?).await?;
+    }
+    if let Some(decompressor) = decompressor {
+        decompressor.finish()?;
+    }
+    out.flush().await?;
+
+    log::info!("Downloaded: {}", path.to_string_lossy());
+
+    apply_metadata(path, file)
+}
+
+// create_symlink recreates a stored link, replacing a link already at the path
+pub(super) fn create_symlink(path: &Path, file: &File) -> Result<(), std::io::Error> {
+    let metadata = FileMetadata::parse(file.metadata.as_deref())
+        .filter(|metadata| metadata.symlink_target.is_some())
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "No link target recorded")
+        })?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if path.is_symlink() {
+        std::fs::remove_file(path)?;
+    }
+
+    std::os::unix::fs::symlink(metadata.symlink_target.as_deref().unwrap(), path)?;
+    log::info!("Linked: {}", path.to_string_lossy());
+
+    metadata.apply(path)
+}
+
+// apply_metadata restores the recorded attributes, files synced before they were
+// recorded only get their modification time back
+fn apply_metadata(path: &Path, file: &File) -> Result<(), std::io::Error> {
+    match FileMetadata::parse(file.metadata.as_deref()) {
+        Some(metadata) => metadata.apply(path),
+        None => set_times(path, file),
+    }
+}
+
+// write_content writes downloaded content to the file, decompressing it first if needed
+async fn write_content(
+    out: &mut tokio::fs::File,
+    decompressor: Option<&mut Decompressor>,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    match decompressor {
+        Some(decompressor) => out.write_all(&decompressor.push(data)?).await,
+        None => out.write_all(data).await,
+    }
+}
+
+// local_path maps a server-side file to its location under `to`. Files outside
+// the prefix and paths trying to escape `to` are skipped.
+fn local_path(to: &Path, prefix: Option<&str>, file: &File) -> Option<PathBuf> {
+    let directory = Path::new(&file.directory);
+    let directory = match prefix {
+        Some(prefix) => directory.strip_prefix(prefix).ok()?,
+        None => directory,
+    };
+
+    let mut path = to.to_path_buf();
+    for component in directory.join(&file.name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            Component::ParentDir => return None,
+        }
+    }
+
+    // the prefix directory itself maps onto `to`
+    if path == to {
+        return None;
+    }
+
+    Some(path)
+}
+
+// set_times applies the server-side modified_at as access and modification time;
+// created_at is kept on the server only, most Unix filesystems can't set a birth time
+fn set_times(path: &Path, file: &File) -> Result<(), std::io::Error> {
+    let time = FileTime::from_unix_time(
+        file.modified_at.timestamp(),
+        file.modified_at.timestamp_subsec_nanos(),
+    );
+
+    filetime::set_file_times(path, time, time)
+}
@@ -0,0 +1,286 @@
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// A local directory synced to a prefix on the server, written as
+/// `LOCAL=PREFIX`, e.g. `~/Photos=/photos`.
+///
+/// Paths below the directory are stored on the server relative to the
+/// prefix, so the same data synced from two machines ends up in one place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncRoot {
+    pub local: PathBuf,
+    pub prefix: String,
+}
+
+impl SyncRoot {
+    pub fn new(local: &str, prefix: &str) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            local: expand_home(local),
+            prefix: normalize_prefix(prefix)?,
+        })
+    }
+
+    // from_dir maps a directory given without a prefix to one named after it
+    pub fn from_dir(dir: &Path) -> Self {
+        let prefix = match dir.file_name() {
+            Some(name) => format!("/{}", name.to_string_lossy()),
+            None => "/".to_string(),
+        };
+
+        Self {
+            local: dir.to_path_buf(),
+            prefix,
+        }
+    }
+
+    // holds reports whether the server-side directory is the prefix or below it
+    fn holds(&self, directory: &str) -> bool {
+        self.relative(directory).is_some()
+    }
+
+    // relative strips the prefix from a server-side directory
+    fn relative<'a>(&self, directory: &'a str) -> Option<&'a str> {
+        if self.prefix == "/" {
+            return directory.strip_prefix('/');
+        }
+
+        match directory.strip_prefix(self.prefix.as_str())? {
+            "" => Some(""),
+            rest => rest.strip_prefix('/'),
+        }
+    }
+}
+
+impl FromStr for SyncRoot {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (local, prefix) = value.split_once('=').ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid root {:?}, expected LOCAL=PREFIX", value),
+            )
+        })?;
+
+        Self::new(local.trim(), prefix.trim())
+    }
+}
+
+/// The directories an agent syncs. Neither the local directories nor the
+/// prefixes may be nested in one another, every path maps to one root.
+#[derive(Debug, Clone)]
+pub struct Roots {
+    roots: Vec<SyncRoot>,
+}
+
+impl Roots {
+    pub fn new(roots: Vec<SyncRoot>) -> Result<Self, std::io::Error> {
+        if roots.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "At least one directory to sync is required",
+            ));
+        }
+
+        for (index, root) in roots.iter().enumerate() {
+            for other in &roots[index + 1..] {
+                if root.local.starts_with(&other.local) || other.local.starts_with(&root.local) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "Synced directories {} and {} overlap",
+                            root.local.to_string_lossy(),
+                            other.local.to_string_lossy()
+                        ),
+                    ));
+                }
+                if root.holds(&other.prefix) || other.holds(&root.prefix) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Prefixes {} and {} overlap", root.prefix, other.prefix),
+                    ));
+                }
+            }
+        }
+
+        Ok(Self { roots })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SyncRoot> {
+        self.roots.iter()
+    }
+
+    // dirs returns the local directories
+    pub fn dirs(&self) -> Vec<PathBuf> {
+        self.roots.iter().map(|root| root.local.clone()).collect()
+    }
+
+    pub fn is_root(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| root.local == path)
+    }
+
+    // contains reports whether the path is a root or below one
+    pub fn contains(&self, path: &Path) -> bool {
+        self.find(path).is_some()
+    }
+
+    fn find(&self, path: &Path) -> Option<&SyncRoot> {
+        self.roots.iter().find(|root| path.starts_with(&root.local))
+    }
+
+    // remote_directory returns the server-side directory a local path is stored in
+    pub fn remote_directory(&self, path: &Path) -> Result<String, std::io::Error> {
+        let parent = path.parent().unwrap_or(path);
+        let root = self.find(parent).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is outside the synced directories",
+                    path.to_string_lossy()
+                ),
+            )
+        })?;
+
+        let mut directory = root.prefix.clone();
+        for component in parent.strip_prefix(&root.local).unwrap().components() {
+            if let Component::Normal(part) = component {
+                if !directory.ends_with('/') {
+                    directory.push('/');
+                }
+                directory.push_str(&part.to_string_lossy());
+            }
+        }
+
+        Ok(directory)
+    }
+
+    // local_path maps a server-side file to its local path, `None` for files
+    // outside the prefixes and names that would escape the root
+    pub fn local_path(&self, directory: &str, name: &str) -> Option<PathBuf> {
+        // Files synced before prefixes existed hold their local directory as it
+        // was given with `--dir`, relative or absolute
+        if !directory.starts_with('/') || self.contains(Path::new(directory)) {
+            let path = Path::new(directory).join(name);
+            return (self.contains(&path) && !self.is_root(&path)).then_some(path);
+        }
+
+        let root = self.roots.iter().find(|root| root.holds(directory))?;
+
+        let mut path = root.local.clone();
+        for part in root.relative(directory)?.split('/').chain([name]) {
+            match part {
+                "" => {}
+                "." | ".." => return None,
+                part if part.contains(std::path::MAIN_SEPARATOR) => return None,
+                part => path.push(part),
+            }
+        }
+
+        if path == root.local {
+            return None;
+        }
+
+        Some(path)
+    }
+}
+
+// normalize_prefix turns a prefix into the form `/a/b`, `/` for the top level
+fn normalize_prefix(prefix: &str) -> Result<String, std::io::Error> {
+    let parts: Vec<&str> = prefix.split('/').filter(|part| !part.is_empty()).collect();
+
+    if parts.iter().any(|part| *part == "." || *part == "..") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid prefix {:?}", prefix),
+        ));
+    }
+
+    Ok(format!("/{}", parts.join("/")))
+}
+
+// expand_home resolves a leading `~` to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), std::env::var_os("HOME")) {
+        (Some(""), Some(home)) => PathBuf::from(home),
+        (Some(rest), Some(home)) if rest.starts_with('/') => {
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots(values: &[&str]) -> Result<Roots, std::io::Error> {
+        Roots::new(values.iter().map(|value| value.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn prefixed_directories_map_to_the_root() {
+        let top = roots(&["/srv/docs=/"]).unwrap();
+        let roots = roots(&["/home/me/Photos=/photos", "/srv/docs=/docs"]).unwrap();
+
+        assert_eq!(
+            roots.local_path("/photos/2026", "beach.jpg"),
+            Some(PathBuf::from("/home/me/Photos/2026/beach.jpg"))
+        );
+        assert_eq!(
+            roots.local_path("/docs", "notes.txt"),
+            Some(PathBuf::from("/srv/docs/notes.txt"))
+        );
+        assert_eq!(
+            top.local_path("/", "notes.txt"),
+            Some(PathBuf::from("/srv/docs/notes.txt"))
+        );
+        assert_eq!(
+            roots
+                .remote_directory(Path::new("/home/me/Photos/2026/beach.jpg"))
+                .unwrap(),
+            "/photos/2026"
+        );
+        assert_eq!(
+            top.remote_directory(Path::new("/srv/docs/notes.txt"))
+                .unwrap(),
+            "/"
+        );
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_components() {
+        let roots = roots(&["/home/me/Photos=/photos"]).unwrap();
+
+        assert_eq!(roots.local_path("/photos2", "beach.jpg"), None);
+        assert_eq!(roots.local_path("/photos/..", "passwd"), None);
+        assert_eq!(roots.local_path("/", "photos"), None);
+    }
+
+    #[test]
+    fn legacy_directories_map_to_themselves() {
+        let relative = Roots::new(vec![SyncRoot::from_dir(Path::new("data"))]).unwrap();
+        let absolute = roots(&["/home/me/data=/data"]).unwrap();
+
+        assert_eq!(
+            relative.local_path("data/sub", "a.txt"),
+            Some(PathBuf::from("data/sub/a.txt"))
+        );
+        assert_eq!(relative.local_path("other", "a.txt"), None);
+        assert_eq!(
+            absolute.local_path("/home/me/data/sub", "a.txt"),
+            Some(PathBuf::from("/home/me/data/sub/a.txt"))
+        );
+        assert_eq!(
+            absolute.local_path("/data/sub", "a.txt"),
+            Some(PathBuf::from("/home/me/data/sub/a.txt"))
+        );
+    }
+
+    #[test]
+    fn nested_roots_are_refused() {
+        assert!(roots(&["/home/me=/me", "/home/me/Photos=/photos"]).is_err());
+        assert!(roots(&["/home/me/Photos=/photos", "/srv/photos=/photos/srv"]).is_err());
+        assert!(roots(&["/home/me/Photos=/", "/srv/docs=/docs"]).is_err());
+        assert!(roots(&["/home/me/Photos=/photos", "/home/me/Photos2=/photos2"]).is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs;
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tokio::signal::unix::{signal, SignalKind};
+
+// Signal is what the signals sent to the agent ask for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    // SIGTERM or SIGINT, finish the uploads in progress and exit
+    Stop,
+    // SIGHUP, read the config file again
+    Reload,
+}
+
+// Signals are the signals a supervised agent reacts to. Once they are listened
+// for, they no longer end the process on their own.
+pub struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    // recv waits for the next signal
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.terminate.recv() => Signal::Stop,
+            _ = self.interrupt.recv() => Signal::Stop,
+            _ = self.hangup.recv() => Signal::Reload,
+        }
+    }
+}
+
+// StateLock is an exclusive lock on a state directory, held by the agent using
+// it. The lock file holds the PID of that agent.
+pub struct StateLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl StateLock {
+    // acquire takes the lock, failing if another agent holds it. The lock is
+    // released with the file, also when the agent is killed.
+    pub fn acquire(state_dir: &Path) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(state_dir)?;
+        let path = state_dir.join("agent.pid");
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(e);
+            }
+
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!(
+                    "Another agent (PID {}) is using the state directory {}",
+                    pid.trim(),
+                    state_dir.to_string_lossy()
+                ),
+            ));
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for StateLock {
+    // the file is emptied rather than removed, an agent waiting to open it
+    // could otherwise lock a file nobody else sees
+    fn drop(&mut self) {
+        if let Err(e) = self.file.set_len(0) {
+            log::warn!("Error clearing {}: {}", self.path.to_string_lossy(), e);
+        }
+    }
+}
+
+// ServiceUnit is where a systemd unit is installed and whose agent it runs
+#[derive(Debug, Clone)]
+pub struct ServiceUnit {
+    // Name of the unit, without `.service`
+    pub name: String,
+    // Installed for the user's own service manager instead of the system's
+    pub user: bool,
+    // The agent binary
+    pub executable: PathBuf,
+    // Config file the service reads its settings from
+    pub config: PathBuf,
+    // Directory relative paths in the config are resolved against
+    pub working_directory: PathBuf,
+    // Account a system unit runs the agent as
+    pub account: Option<String>,
+}
+
+impl ServiceUnit {
+    // path returns where the unit file is installed
+    pub fn path(&self) -> Result<PathBuf, std::io::Error> {
+        let file = format!("{}.service", self.name);
+        if !self.user {
+            return Ok(Path::new("/etc/systemd/system").join(file));
+        }
+
+        let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".config"),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "HOME is not set, pass --output",
+                    ))
+                }
+            },
+        };
+        Ok(config_home.join("systemd/user").join(file))
+    }
+
+    // render returns the content of the unit file. Stopping waits for uploads
+    // in progress, a reload sends SIGHUP.
+    pub fn render(&self) -> String {
+        let mut unit = String::new();
+        unit.push_str("[Unit]\n");
+        unit.push_str("Description=memora sync agent\n");
+        unit.push_str("Wants=network-online.target\n");
+        unit.push_str("After=network-online.target\n\n");
+
+        unit.push_str("[Service]\n");
+        unit.push_str("Type=simple\n");
+        unit.push_str(&format!(
+            "ExecStart={} --config {}\n",
+            quote(&self.executable.to_string_lossy()),
+            quote(&self.config.to_string_lossy())
+        ));
+        unit.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+        unit.push_str(&format!(
+            "WorkingDirectory={}\n",
+            quote(&self.working_directory.to_string_lossy())
+        ));
+        if let (false, Some(account)) = (self.user, &self.account) {
+            unit.push_str(&format!("User={}\n", account));
+        }
+        unit.push_str("Restart=on-failure\n");
+        unit.push_str("RestartSec=10\n");
+        unit.push_str("KillMode=mixed\n");
+        unit.push_str("TimeoutStopSec=600\n\n");
+
+        unit.push_str("[Install]\n");
+        unit.push_str(match self.user {
+            true => "WantedBy=default.target\n",
+            false => "WantedBy=multi-user.target\n",
+        });
+        unit
+    }
+}
+
+// quote makes a value a single word of a systemd command line, `%` starts a
+// specifier there and is escaped
+fn quote(value: &str) -> String {
+    let value = value.replace('%', "%%");
+    if !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\' || c == '\'') {
+        return value;
+    }
+
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
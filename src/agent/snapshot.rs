@@ -0,0 +1,386 @@
+use crate::agent::agent::Agent;
+use crate::agent::remote::RemoteAgent;
+use crate::agent::restore::Version;
+use crate::schema::file::FileStatus;
+use crate::schema::snapshot::SnapshotResponse;
+use charybdis::types::Uuid;
+
+use chrono::{Datelike, Local};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// Number of files captured per request, the most the server accepts
+const BATCH_SIZE: usize = 100;
+
+// Period a snapshot falls in, the policy keeps the newest snapshot of each
+type Bucket = fn(&SnapshotResponse) -> (i32, u32);
+
+// Incomplete snapshots younger than this may still be recorded by a backup
+// running elsewhere, pruning leaves them alone
+const INCOMPLETE_GRACE: chrono::Duration = chrono::Duration::hours(24);
+
+/// How many completed snapshots of a name are kept, the newest of each day,
+/// week and month counting back from the latest one. A policy keeping nothing
+/// keeps every snapshot.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetentionPolicy {
+    /// Number of most recent snapshots
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.last == 0 && self.daily == 0 && self.weekly == 0 && self.monthly == 0
+    }
+
+    // expired returns the snapshots the policy doesn't keep, the given snapshots
+    // are all completed and share a name
+    pub fn expired(&self, snapshots: &[SnapshotResponse]) -> Vec<SnapshotResponse> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        let mut snapshots = snapshots.to_vec();
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.created_at));
+
+        let mut kept = HashSet::new();
+        kept.extend(snapshots.iter().take(self.last).map(|snapshot| snapshot.id));
+
+        let buckets: [(usize, Bucket); 3] = [
+            (self.daily, |snapshot| {
+                let time = snapshot.created_at.with_timezone(&Local);
+                (time.year(), time.ordinal())
+            }),
+            (self.weekly, |snapshot| {
+                let week = snapshot.created_at.with_timezone(&Local).iso_week();
+                (week.year(), week.week())
+            }),
+            (self.monthly, |snapshot| {
+                let time = snapshot.created_at.with_timezone(&Local);
+                (time.year(), time.month())
+            }),
+        ];
+        for (count, bucket) in buckets {
+            // newest first, so the first snapshot of each bucket is its newest
+            let mut seen = Vec::new();
+            for snapshot in &snapshots {
+                if seen.len() == count {
+                    break;
+                }
+                let key = bucket(snapshot);
+                if !seen.contains(&key) {
+                    seen.push(key);
+                    kept.insert(snapshot.id);
+                }
+            }
+        }
+
+        snapshots
+            .into_iter()
+            .filter(|snapshot| !kept.contains(&snapshot.id))
+            .collect()
+    }
+}
+
+/// Result of a backup.
+#[derive(Serialize, Debug)]
+pub struct BackupReport {
+    pub snapshot: SnapshotResponse,
+    /// Paths the server had no completed upload of, they are left out of the snapshot
+    pub skipped: Vec<PathBuf>,
+    /// Paths waiting for a retry, the snapshot has the version last uploaded
+    pub queued: Vec<PathBuf>,
+    /// Snapshots removed by the retention policy or left incomplete by an earlier backup
+    pub pruned: Vec<SnapshotResponse>,
+}
+
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.skipped {
+            writeln!(f, "skipped {}", path.to_string_lossy())?;
+        }
+        for path in &self.queued {
+            writeln!(f, "queued  {}", path.to_string_lossy())?;
+        }
+        for snapshot in &self.pruned {
+            writeln!(
+                f,
+                "pruned  {} {}",
+                snapshot.id,
+                snapshot
+                    .created_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Snapshot {} ({}): {} files, {} bytes, {} skipped, {} pruned",
+            self.snapshot.id,
+            self.snapshot.name,
+            self.snapshot.files.unwrap_or(0),
+            self.snapshot.size.unwrap_or(0),
+            self.skipped.len(),
+            self.pruned.len()
+        )
+    }
+}
+
+impl Agent {
+    // backup scans the synced directories and records what the server has for
+    // them as a snapshot named `name`, then removes the snapshots of that name
+    // the retention policy doesn't keep
+    pub async fn backup(
+        &self,
+        name: &str,
+        policy: RetentionPolicy,
+    ) -> Result<BackupReport, std::io::Error> {
+        self.scan_roots().await?;
+
+        let snapshot = self.api.create_snapshot(name).await?;
+        log::info!("Recording snapshot {} ({})", snapshot.id, snapshot.name);
+
+        let mut paths: HashMap<Uuid, PathBuf> = HashMap::new();
+        let mut queued = Vec::new();
+        for (path, entry) in self.indexed_entries(&self.roots.dirs()).await? {
+            if self.queue.is_waiting(&path).await {
+                log::warn!(
+                    "Snapshot has the last uploaded version of queued {}",
+                    path.to_string_lossy()
+                );
+                queued.push(path.clone());
+            }
+            paths.insert(entry.file.id, path);
+        }
+
+        let mut skipped = Vec::new();
+        let file_ids: Vec<Uuid> = paths.keys().copied().collect();
+        for batch in file_ids.chunks(BATCH_SIZE) {
+            let captured = self
+                .api
+                .add_snapshot_files(snapshot.id, batch.to_vec())
+                .await?;
+            for file_id in captured.skipped {
+                if let Some(path) = paths.get(&file_id) {
+                    log::warn!("Not in the snapshot: {}", path.to_string_lossy());
+                    skipped.push(path.clone());
+                }
+            }
+        }
+
+        let snapshot = self.api.complete_snapshot(snapshot.id).await?;
+        let pruned = self.prune_snapshots(&snapshot, policy).await?;
+
+        skipped.sort();
+        queued.sort();
+        Ok(BackupReport {
+            snapshot,
+            skipped,
+            queued,
+            pruned,
+        })
+    }
+
+    // prune_snapshots deletes the snapshots this device recorded under the name
+    // of the latest one that the policy doesn't keep, and those an earlier
+    // backup didn't complete
+    async fn prune_snapshots(
+        &self,
+        latest: &SnapshotResponse,
+        policy: RetentionPolicy,
+    ) -> Result<Vec<SnapshotResponse>, std::io::Error> {
+        let (completed, incomplete): (Vec<_>, Vec<_>) = self
+            .api
+            .list_snapshots()
+            .await?
+            .into_iter()
+            .filter(|snapshot| snapshot.name == latest.name)
+            .filter(|snapshot| snapshot.device_id == latest.device_id)
+            .partition(|snapshot| snapshot.status == FileStatus::CLOSED.to_string());
+
+        let mut pruned = policy.expired(&completed);
+        pruned.extend(
+            incomplete
+                .into_iter()
+                .filter(|snapshot| snapshot.created_at < latest.created_at - INCOMPLETE_GRACE),
+        );
+
+        for snapshot in &pruned {
+            self.api.delete_snapshot(snapshot.id).await?;
+            log::info!("Pruned snapshot {} ({})", snapshot.id, snapshot.name);
+        }
+
+        Ok(pruned)
+    }
+}
+
+impl RemoteAgent {
+    pub async fn snapshots(&self) -> Result<Vec<SnapshotResponse>, std::io::Error> {
+        let mut snapshots = self.api.list_snapshots().await?;
+        snapshots.sort_by_key(|snapshot| snapshot.created_at);
+        Ok(snapshots)
+    }
+
+    // restore_snapshot rebuilds the tree under `to` as the snapshot captured it,
+    // incomplete snapshots are refused
+    pub async fn restore_snapshot(
+        &self,
+        to: &Path,
+        prefix: Option<&str>,
+        snapshot_id: Uuid,
+    ) -> Result<(), std::io::Error> {
+        let snapshot = self
+            .api
+            .list_snapshots()
+            .await?
+            .into_iter()
+            .find(|snapshot| snapshot.id == snapshot_id)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Snapshot {} not found", snapshot_id),
+                )
+            })?;
+        if snapshot.status != FileStatus::CLOSED.to_string() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Snapshot {} was never completed", snapshot_id),
+            ));
+        }
+
+        self.restore(to, prefix, Version::Snapshot(snapshot.id))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    // snapshot returns a completed snapshot taken around midday UTC, the
+    // snapshots of a day stay on one day in any local time zone
+    fn snapshot(id: u128, year: i32, month: u32, day: u32, hour: u32) -> SnapshotResponse {
+        SnapshotResponse {
+            id: Uuid::from_u128(id),
+            name: "host".to_string(),
+            status: FileStatus::CLOSED.to_string(),
+            created_at: Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap(),
+            completed_at: None,
+            files: None,
+            size: None,
+            device_id: None,
+        }
+    }
+
+    fn expired_ids(policy: RetentionPolicy, snapshots: &[SnapshotResponse]) -> Vec<u128> {
+        let mut ids: Vec<u128> = policy
+            .expired(snapshots)
+            .iter()
+            .map(|snapshot| snapshot.id.as_u128())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn empty_policy_keeps_everything() {
+        let snapshots = [snapshot(1, 2026, 3, 4, 11), snapshot(2, 2026, 3, 4, 12)];
+
+        assert!(expired_ids(RetentionPolicy::default(), &snapshots).is_empty());
+    }
+
+    #[test]
+    fn last_keeps_the_most_recent() {
+        let snapshots = [
+            snapshot(1, 2026, 3, 4, 10),
+            snapshot(2, 2026, 3, 4, 14),
+            snapshot(3, 2026, 3, 4, 11),
+            snapshot(4, 2026, 3, 4, 13),
+        ];
+        let policy = RetentionPolicy {
+            last: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(expired_ids(policy, &snapshots), vec![1, 3]);
+    }
+
+    #[test]
+    fn daily_keeps_the_newest_of_each_day() {
+        let snapshots = [
+            snapshot(1, 2026, 3, 2, 11),
+            snapshot(2, 2026, 3, 3, 11),
+            snapshot(3, 2026, 3, 3, 13),
+            snapshot(4, 2026, 3, 4, 11),
+            snapshot(5, 2026, 3, 4, 13),
+        ];
+        let policy = RetentionPolicy {
+            daily: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(expired_ids(policy, &snapshots), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn weekly_keeps_the_newest_of_each_week() {
+        // Wednesdays and Thursdays of three consecutive weeks
+        let snapshots = [
+            snapshot(1, 2026, 3, 4, 12),
+            snapshot(2, 2026, 3, 11, 12),
+            snapshot(3, 2026, 3, 12, 12),
+            snapshot(4, 2026, 3, 18, 12),
+            snapshot(5, 2026, 3, 19, 12),
+        ];
+        let policy = RetentionPolicy {
+            weekly: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(expired_ids(policy, &snapshots), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn monthly_keeps_the_newest_of_each_month() {
+        let snapshots = [
+            snapshot(1, 2026, 1, 15, 12),
+            snapshot(2, 2026, 2, 10, 12),
+            snapshot(3, 2026, 2, 20, 12),
+            snapshot(4, 2026, 3, 10, 12),
+            snapshot(5, 2026, 3, 20, 12),
+        ];
+        let policy = RetentionPolicy {
+            monthly: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(expired_ids(policy, &snapshots), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn buckets_keep_the_union() {
+        let snapshots = [
+            snapshot(1, 2026, 1, 15, 12),
+            snapshot(2, 2026, 2, 20, 12),
+            snapshot(3, 2026, 3, 18, 12),
+            snapshot(4, 2026, 3, 19, 11),
+            snapshot(5, 2026, 3, 19, 13),
+        ];
+        let policy = RetentionPolicy {
+            last: 1,
+            daily: 2,
+            monthly: 3,
+            ..Default::default()
+        };
+
+        // 5 is the last and newest of its day and month, 4 is older on the same day
+        assert_eq!(expired_ids(policy, &snapshots), vec![4]);
+    }
+}
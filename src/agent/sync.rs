@@ -0,0 +1,597 @@
+use crate::agent::agent::Agent;
+use crate::agent::index::{file_digest, IndexEntry};
+use crate::agent::restore::{create_symlink, download_file, Version};
+use crate::model::file::File;
+use crate::schema::file::{FileResponse, FileStatus, FileType, FileUpdateRequest};
+use charybdis::types::Uuid;
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
+
+// Number of files requested per page when listing the server
+const PAGE_SIZE: i32 = 100;
+
+// Key of the time of the newest server change pulled so far
+const PULL_CURSOR: &str = "cursor";
+
+// How far before the cursor changes are listed again, for rows that became
+// visible after newer ones
+const PULL_OVERLAP: chrono::Duration = chrono::Duration::minutes(1);
+
+impl Agent {
+    // pull_changes applies changes made on the server since the last sync to the
+    // local tree. The index holds the server state as of the last sync, so any
+    // server file that differs from its index entry was changed by another device.
+    // Only the files changed since the last pull are listed, deletions show up
+    // in a full listing once every rescan_interval seconds.
+    pub async fn pull_changes(&self) -> Result<(), std::io::Error> {
+        self.control.wait_resumed().await;
+        let mut known: HashMap<Uuid, (PathBuf, IndexEntry)> = self
+            .indexed_entries(&self.roots.dirs())
+            .await?
+            .into_iter()
+            .map(|(path, entry)| (entry.file.id, (path, entry)))
+            .collect();
+
+        let full = match *self.last_full_pull.lock().unwrap() {
+            Some(last) => last.elapsed() >= Duration::from_secs(self.rescan_interval),
+            None => true,
+        };
+        let cursor = match self.pull_cursor().await? {
+            Some(cursor) if !full => cursor,
+            _ => return self.pull_all(known).await,
+        };
+
+        // rows written around the cursor may become visible late, they are
+        // listed again and skipped as unchanged
+        let mut updated_after = cursor - PULL_OVERLAP;
+        let mut newest = cursor;
+        let mut last_id = None;
+        loop {
+            let page = self
+                .api
+                .list_changed_files(updated_after, last_id, PAGE_SIZE)
+                .await?;
+            updated_after = page.last_updated_at.unwrap_or(updated_after);
+            newest = newest.max(updated_after);
+            last_id = page.last_id;
+
+            for file in page.files {
+                let id = file.id;
+
+                if let Err(e) = self.pull_file(file, &mut known).await {
+                    log::error!("Error pulling {}: {}", id, e);
+                }
+            }
+
+            if page.is_last || last_id.is_none() {
+                break;
+            }
+        }
+
+        self.set_pull_cursor(newest).await
+    }
+
+    // pull_all lists every file on the server, applies the changes and removes
+    // what no longer exists there
+    async fn pull_all(
+        &self,
+        mut known: HashMap<Uuid, (PathBuf, IndexEntry)>,
+    ) -> Result<(), std::io::Error> {
+        let started = Instant::now();
+        let mut newest = None;
+
+        let mut last_id = None;
+        loop {
+            let page = self.api.list_files(last_id, PAGE_SIZE).await?;
+            last_id = page.last_id;
+            newest = newest.max(
+                page.files
+                    .iter()
+                    .chain(&page.unreadable)
+                    .filter_map(|file| file.updated_at)
+                    .max(),
+            );
+
+            // files that can't be read are kept as they are
+            for file in page.unreadable {
+                known.remove(&file.id);
+            }
+
+            for file in page.files {
+                let id = file.id;
+
+                if let Err(e) = self.pull_file(file, &mut known).await {
+                    log::error!("Error pulling {}: {}", id, e);
+                }
+            }
+
+            if page.is_last || last_id.is_none() {
+                break;
+            }
+        }
+
+        // Whatever is left in the index no longer exists on the server. Files
+        // go first so that the directories holding them are empty by then.
+        let (directories, files): (Vec<_>, Vec<_>) = known
+            .into_values()
+            .partition(|(_, entry)| entry.file.file_type == FileType::DIRECTORY.to_string());
+
+        for (path, entry) in files.into_iter().chain(directories) {
+            if let Err(e) = self.pull_deletion(&path, &entry).await {
+                log::error!("Error removing {}: {}", path.to_string_lossy(), e);
+            }
+        }
+
+        *self.last_full_pull.lock().unwrap() = Some(started);
+        match newest {
+            Some(newest) => self.set_pull_cursor(newest).await,
+            None => Ok(()),
+        }
+    }
+
+    // pull_cursor returns the time of the newest server change pulled so far
+    async fn pull_cursor(&self) -> Result<Option<DateTime<Utc>>, std::io::Error> {
+        let state = self.pull_state.clone();
+        let value = spawn_blocking(move || state.get(PULL_CURSOR))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)?;
+
+        Ok(value.and_then(|value| {
+            DateTime::parse_from_rfc3339(&String::from_utf8_lossy(&value))
+                .ok()
+                .map(|cursor| cursor.with_timezone(&Utc))
+        }))
+    }
+
+    async fn set_pull_cursor(&self, cursor: DateTime<Utc>) -> Result<(), std::io::Error> {
+        let state = self.pull_state.clone();
+        spawn_blocking(move || state.insert(PULL_CURSOR, cursor.to_rfc3339()))
+            .await
+            .expect("join failed")
+            .map_err(std::io::Error::other)
+    }
+
+    async fn pull_file(
+        &self,
+        file: File,
+        known: &mut HashMap<Uuid, (PathBuf, IndexEntry)>,
+    ) -> Result<(), std::io::Error> {
+        let previous = known.remove(&file.id);
+        let path = match self.roots.local_path(&file.directory, &file.name) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // Ignored paths are left out of the sync in both directions
+        let is_dir = file.file_type == FileType::DIRECTORY.to_string();
+        if self.ignore.is_ignored(&path, is_dir) {
+            return Ok(());
+        }
+
+        if is_dir {
+            let moved = previous.as_ref().map(|(old_path, _)| old_path != &path);
+            if moved == Some(false) {
+                return Ok(());
+            }
+
+            fs::create_dir_all(&path)?;
+            Self::register_upload(
+                self.db.clone(),
+                &path,
+                IndexEntry::new(FileResponse::from(file), &fs::metadata(&path)?, None),
+            )
+            .await?;
+
+            // the children of a moved directory are moved through their own rows
+            if let Some((old_path, _)) = previous {
+                Self::unregister_upload(self.db.clone(), &old_path).await?;
+                let _ = fs::remove_dir(&old_path);
+            }
+
+            return Ok(());
+        }
+
+        if file.file_type == FileType::SYMLINK.to_string() {
+            return self.pull_symlink(file, &path, previous).await;
+        }
+
+        // Files still being uploaded by another device are picked up later
+        if file.status != FileStatus::CLOSED.to_string() {
+            return Ok(());
+        }
+
+        match previous {
+            Some((old_path, entry)) => {
+                let moved = old_path != path;
+                let changed = changed_on_server(&file, &entry);
+
+                if !moved && !changed {
+                    return Ok(());
+                }
+
+                // A local deletion is pushed to the server by the next scan
+                if !old_path.exists() {
+                    return Ok(());
+                }
+
+                if Self::changed_locally(&old_path, &entry).await? {
+                    // Both sides changed, the local version becomes a new file
+                    // next to it and the server version takes over the path
+                    Self::unregister_upload(self.db.clone(), &old_path).await?;
+                    self.keep_conflict_copy(&old_path, None).await?;
+                    return self.pull_download(&file, &path).await;
+                }
+
+                if moved && !changed {
+                    log::info!(
+                        "Moving: {} -> {}",
+                        old_path.to_string_lossy(),
+                        path.to_string_lossy()
+                    );
+
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    Self::register_upload(
+                        self.db.clone(),
+                        &path,
+                        IndexEntry {
+                            file: FileResponse::from(file),
+                            ..entry
+                        },
+                    )
+                    .await?;
+                    Self::unregister_upload(self.db.clone(), &old_path).await?;
+                    return fs::rename(&old_path, &path);
+                }
+
+                self.pull_download(&file, &path).await?;
+                if moved {
+                    Self::unregister_upload(self.db.clone(), &old_path).await?;
+                    fs::remove_file(&old_path)?;
+                }
+
+                Ok(())
+            }
+            None => {
+                if path.exists() {
+                    let current = Self::lookup(self.db.clone(), &path).await?;
+
+                    // Already tracked under this path, e.g. a conflict copy renamed earlier
+                    if current.as_ref().map(|entry| entry.file.id) == Some(file.id) {
+                        return Ok(());
+                    }
+
+                    // Same content created on both sides, just start tracking it
+                    if current.is_none() && file.checksum.is_some() {
+                        let path_clone = path.clone();
+                        let hash = spawn_blocking(move || file_digest(&path_clone))
+                            .await
+                            .expect("join failed")?;
+
+                        let checksum = self.api.checksum(file.key_id.as_deref(), &hash)?;
+                        if file.checksum.as_deref() == Some(checksum.as_str()) {
+                            return Self::register_upload(
+                                self.db.clone(),
+                                &path,
+                                IndexEntry::new(
+                                    FileResponse::from(file),
+                                    &fs::metadata(&path)?,
+                                    Some(hash),
+                                ),
+                            )
+                            .await;
+                        }
+                    }
+
+                    // The file tracked at this path gets renamed on the server,
+                    // its row is no longer where the index last saw it
+                    if let Some(entry) = &current {
+                        known.remove(&entry.file.id);
+                    }
+                    self.keep_conflict_copy(&path, current).await?;
+                }
+
+                self.pull_download(&file, &path).await
+            }
+        }
+    }
+
+    // pull_symlink recreates a link stored by another device. Anything but a link
+    // at its path is left alone.
+    async fn pull_symlink(
+        &self,
+        file: File,
+        path: &Path,
+        previous: Option<(PathBuf, IndexEntry)>,
+    ) -> Result<(), std::io::Error> {
+        if let Some((old_path, entry)) = previous {
+            if old_path == path && entry.file.metadata == file.metadata {
+                return Ok(());
+            }
+
+            Self::unregister_upload(self.db.clone(), &old_path).await?;
+            if old_path != path && old_path.is_symlink() {
+                fs::remove_file(&old_path)?;
+            }
+        }
+
+        if path.exists() && !path.is_symlink() {
+            log::warn!(
+                "Not replacing {} with a link stored on the server",
+                path.to_string_lossy()
+            );
+            return Ok(());
+        }
+
+        create_symlink(path, &file)?;
+        Self::register_upload(
+            self.db.clone(),
+            path,
+            IndexEntry::new(FileResponse::from(file), &fs::symlink_metadata(path)?, None),
+        )
+        .await
+    }
+
+    // pull_deletion removes a local path whose server-side file was deleted,
+    // unless it was changed locally since the last sync
+    async fn pull_deletion(&self, path: &Path, entry: &IndexEntry) -> Result<(), std::io::Error> {
+        Self::unregister_upload(self.db.clone(), path).await?;
+
+        if entry.file.file_type == FileType::DIRECTORY.to_string() {
+            // only empty directories are removed, anything left in them gets uploaded again
+            let _ = fs::remove_dir(path);
+            return Ok(());
+        }
+
+        if entry.file.file_type == FileType::SYMLINK.to_string() {
+            if path.is_symlink() {
+                log::info!("Removing: {}", path.to_string_lossy());
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+
+        if !path.exists() {
+            return Ok(());
+        }
+
+        if Self::changed_locally(path, entry).await? {
+            log::warn!(
+                "Keeping locally modified file deleted on the server: {}",
+                path.to_string_lossy()
+            );
+            return Ok(());
+        }
+
+        log::info!("Removing: {}", path.to_string_lossy());
+        fs::remove_file(path)
+    }
+
+    // pull_download fetches the server version of the file into path. The content
+    // lands in a temporary file first and is indexed before being moved in place.
+    async fn pull_download(&self, file: &File, path: &Path) -> Result<(), std::io::Error> {
+        let partial = path.with_file_name(format!(
+            ".{}.memora-part",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+
+        let transfer = self.progress.start(
+            &path.file_name().unwrap().to_string_lossy(),
+            file.size.unwrap_or(0).max(0) as u64,
+        );
+        let download = download_file(
+            self.api.clone(),
+            self.client.clone(),
+            &self.throttle,
+            file,
+            &partial,
+            Version::Current,
+        );
+
+        if let Err(e) = transfer.scope(download).await {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+
+        let partial_clone = partial.clone();
+        let hash = spawn_blocking(move || file_digest(&partial_clone))
+            .await
+            .expect("join failed")?;
+
+        Self::register_upload(
+            self.db.clone(),
+            path,
+            IndexEntry::new(
+                FileResponse::from(file.clone()),
+                &fs::metadata(&partial)?,
+                Some(hash),
+            ),
+        )
+        .await?;
+
+        fs::rename(&partial, path)
+    }
+
+    // keep_conflict_copy moves the local version of a conflicting file aside.
+    // A tracked file keeps its server-side identity under the new name.
+    async fn keep_conflict_copy(
+        &self,
+        path: &Path,
+        entry: Option<IndexEntry>,
+    ) -> Result<(), std::io::Error> {
+        let conflict = conflict_path(path);
+        log::warn!(
+            "Conflict, keeping local version as: {}",
+            conflict.to_string_lossy()
+        );
+
+        fs::rename(path, &conflict)?;
+
+        if let Some(entry) = entry {
+            let file = self
+                .api
+                .update_file(
+                    entry.file.id,
+                    FileUpdateRequest {
+                        name: conflict.file_name().unwrap().to_string_lossy().to_string(),
+                        directory: self.api.remote_directory(&conflict)?,
+                        file_type: FileType::FILE,
+                        status: FileStatus::CLOSED,
+                        created_at: entry.file.created_at,
+                        modified_at: entry.file.modified_at,
+                        checksum: entry.file.checksum.clone(),
+                        key_id: entry.file.key_id.clone(),
+                        metadata: entry.file.metadata.clone(),
+                    },
+                )
+                .await?;
+
+            // size, mtime and hash still describe the last synced content, so
+            // local edits are uploaded by the next scan
+            Self::register_upload(self.db.clone(), &conflict, IndexEntry { file, ..entry }).await?;
+            Self::unregister_upload(self.db.clone(), path).await?;
+        }
+
+        Ok(())
+    }
+
+    // changed_locally reports whether the file content differs from the last sync
+    async fn changed_locally(path: &Path, entry: &IndexEntry) -> Result<bool, std::io::Error> {
+        if !entry.is_stale(&fs::metadata(path)?) {
+            return Ok(false);
+        }
+
+        let path_clone = path.to_path_buf();
+        let hash = spawn_blocking(move || file_digest(&path_clone))
+            .await
+            .expect("join failed")?;
+
+        Ok(entry.hash.as_deref() != Some(hash.as_str()))
+    }
+}
+
+// conflict_path builds a free `name (conflict from <host> <date>).ext` path
+// next to the original file
+fn conflict_path(path: &Path) -> PathBuf {
+    let host = hostname::get()
+        .map(|host| host.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    let date = chrono::Local::now().format("%Y-%m-%d %H-%M-%S");
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut counter = 1;
+    loop {
+        let suffix = match counter {
+            1 => String::new(),
+            n => format!(" {}", n),
+        };
+        let candidate = path.with_file_name(format!(
+            "{} (conflict from {} {}{}){}",
+            stem, host, date, suffix, extension
+        ));
+
+        if !candidate.exists() {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+// changed_on_server reports whether the server has another version of the file
+// than the one last synced
+pub(super) fn changed_on_server(file: &File, entry: &IndexEntry) -> bool {
+    match (&file.checksum, &entry.file.checksum) {
+        (Some(remote), Some(local)) => remote != local,
+        _ => file.modified_at != entry.file.modified_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // name returns the file name of the conflict copy of `path`
+    fn name(path: &str) -> String {
+        conflict_path(Path::new(path))
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn conflicts_keep_the_extension_last() {
+        let name = name("/photos/beach.jpg");
+
+        assert!(name.starts_with("beach (conflict from "));
+        assert!(name.ends_with(").jpg"));
+    }
+
+    #[test]
+    fn conflicts_of_files_without_extension_end_with_the_marker() {
+        let name = name("/notes/README");
+
+        assert!(name.starts_with("README (conflict from "));
+        assert!(name.ends_with(')'));
+    }
+
+    #[test]
+    fn conflicts_of_dotfiles_keep_the_whole_name() {
+        let name = name("/home/.bashrc");
+
+        assert!(name.starts_with(".bashrc (conflict from "));
+        assert!(name.ends_with(')'));
+    }
+
+    #[test]
+    fn conflicts_stay_in_the_same_directory() {
+        let path = conflict_path(Path::new("/photos/2024/beach.jpg"));
+
+        assert_eq!(path.parent(), Some(Path::new("/photos/2024")));
+    }
+
+    #[test]
+    fn taken_conflict_names_get_a_counter() {
+        let dir = std::env::temp_dir().join(format!("conflict-{}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("beach.jpg");
+
+        // the names carry the time in seconds, so try again if the clock
+        // ticked over in between
+        let mut attempts = 0;
+        let (first, second) = loop {
+            let first = conflict_path(&path);
+            fs::write(&first, b"").unwrap();
+            let second = conflict_path(&path);
+            fs::remove_file(&first).unwrap();
+
+            let expected = first.to_string_lossy().replace(").jpg", " 2).jpg");
+            if second.to_string_lossy() == expected {
+                break (first, second);
+            }
+
+            attempts += 1;
+            assert!(attempts < 3, "{:?} did not get a counter", second);
+        };
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), second.parent());
+    }
+}
@@ -0,0 +1,272 @@
+use chrono::{Local, NaiveTime};
+use futures::{Stream, StreamExt};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A time of day window with its own bandwidth limit, written as
+/// `HH:MM-HH:MM` for full speed or `HH:MM-HH:MM=BYTES` for a limit in bytes
+/// per second. Windows ending before they start span midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandwidthWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    limit: Option<u64>,
+}
+
+impl BandwidthWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for BandwidthWindow {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid bandwidth window {:?}, expected HH:MM-HH:MM or HH:MM-HH:MM=BYTES",
+                    value
+                ),
+            )
+        };
+
+        let (times, limit) = match value.split_once('=') {
+            Some((times, limit)) => (times, Some(parse_limit(limit.trim())?)),
+            None => (value, None),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+
+        Ok(Self {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?,
+            limit,
+        })
+    }
+}
+
+/// Bandwidth limit in bytes per second, `None` for full speed, and the
+/// schedule of windows overriding it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BandwidthSettings {
+    pub limit: Option<u64>,
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+impl BandwidthSettings {
+    pub fn parse(limit: Option<u64>, schedule: &[String]) -> Result<Self, std::io::Error> {
+        if limit == Some(0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The bandwidth limit must be more than 0 bytes per second",
+            ));
+        }
+
+        Ok(Self {
+            limit,
+            schedule: schedule
+                .iter()
+                .map(|window| window.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    // limit_at returns the limit in effect at the time of day, the first matching window wins
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        match self.schedule.iter().find(|window| window.contains(time)) {
+            Some(window) => window.limit,
+            None => self.limit,
+        }
+    }
+}
+
+/// Bandwidth limit shared by all uploads and downloads of the agent. Every
+/// transfer takes its bytes from the same budget, so the limit holds no matter
+/// how many workers run at once. Settings can be swapped while transfers run.
+#[derive(Clone)]
+pub struct Throttle {
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+struct ThrottleState {
+    settings: BandwidthSettings,
+    // when the bytes handed out so far are used up at the current limit
+    next: Instant,
+    // the limit `next` was worked out with
+    limit: Option<u64>,
+}
+
+impl ThrottleState {
+    // reserve hands out `bytes` at the limit in effect at the time of day and
+    // returns when they may be sent. Once a window starts or ends the budget
+    // starts over at the new limit.
+    fn reserve(&mut self, bytes: usize, now: Instant, time: NaiveTime) -> Instant {
+        let limit = self.settings.limit_at(time);
+        if limit != self.limit {
+            self.limit = limit;
+            self.next = now;
+        }
+
+        match limit {
+            Some(limit) => {
+                let start = self.next.max(now);
+                self.next = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+                start
+            }
+            None => {
+                self.next = now;
+                now
+            }
+        }
+    }
+}
+
+impl Throttle {
+    pub fn new(settings: BandwidthSettings) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ThrottleState {
+                settings,
+                next: Instant::now(),
+                limit: None,
+            })),
+        }
+    }
+
+    pub fn settings(&self) -> BandwidthSettings {
+        self.state.lock().unwrap().settings.clone()
+    }
+
+    // set replaces the settings, transfers in progress pick them up with their next bytes
+    pub fn set(&self, settings: BandwidthSettings) {
+        let mut state = self.state.lock().unwrap();
+        state.settings = settings;
+        state.next = Instant::now();
+    }
+
+    // acquire waits until `bytes` more bytes may be transferred
+    pub async fn acquire(&self, bytes: usize) {
+        let now = Instant::now();
+        let start = self
+            .state
+            .lock()
+            .unwrap()
+            .reserve(bytes, now, Local::now().time());
+
+        if start > now {
+            tokio::time::sleep_until(start).await;
+        }
+    }
+
+    // limit passes the stream's data through the throttle
+    pub fn limit<S, T, E>(&self, stream: S) -> impl Stream<Item = Result<T, E>>
+    where
+        S: Stream<Item = Result<T, E>>,
+        T: AsRef<[u8]>,
+    {
+        let throttle = self.clone();
+
+        stream.then(move |item| {
+            let throttle = throttle.clone();
+            async move {
+                if let Ok(data) = &item {
+                    throttle.acquire(data.as_ref().len()).await;
+                }
+                item
+            }
+        })
+    }
+}
+
+fn parse_limit(value: &str) -> Result<u64, std::io::Error> {
+    match value.parse::<u64>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid bandwidth limit {:?}, expected bytes per second",
+                value
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // time parses a HH:MM time of day
+    fn time(value: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(value, "%H:%M").unwrap()
+    }
+
+    // state builds a throttle state that has not handed out any bytes yet
+    fn state(limit: Option<u64>, schedule: &[&str]) -> ThrottleState {
+        let schedule: Vec<String> = schedule.iter().map(|window| window.to_string()).collect();
+
+        ThrottleState {
+            settings: BandwidthSettings::parse(limit, &schedule).unwrap(),
+            next: Instant::now(),
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn windows_spanning_midnight_apply_on_both_days() {
+        let settings = BandwidthSettings::parse(Some(1000), &["22:00-06:00".to_string()]).unwrap();
+
+        assert_eq!(settings.limit_at(time("23:30")), None);
+        assert_eq!(settings.limit_at(time("05:59")), None);
+        assert_eq!(settings.limit_at(time("06:00")), Some(1000));
+        assert_eq!(settings.limit_at(time("12:00")), Some(1000));
+    }
+
+    #[test]
+    fn bytes_are_spread_at_the_limit() {
+        let mut state = state(Some(1000), &[]);
+        let now = Instant::now();
+
+        assert_eq!(state.reserve(500, now, time("12:00")), now);
+        assert_eq!(
+            state.reserve(500, now, time("12:00")),
+            now + Duration::from_millis(500)
+        );
+        assert_eq!(
+            state.reserve(500, now, time("12:00")),
+            now + Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn crossing_into_a_faster_window_starts_over() {
+        let mut state = state(Some(100), &["09:00-17:00=1000000"]);
+        let now = Instant::now();
+
+        // a minute of backlog at the slow limit
+        state.reserve(6000, now, time("08:59"));
+        assert!(state.next >= now + Duration::from_secs(60));
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(state.reserve(1000, later, time("09:00")), later);
+        assert_eq!(state.next, later + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn crossing_into_a_window_without_limit_starts_over() {
+        let mut state = state(Some(100), &["22:00-06:00"]);
+        let now = Instant::now();
+
+        state.reserve(6000, now, time("21:59"));
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(state.reserve(6000, later, time("22:00")), later);
+        assert_eq!(state.reserve(6000, later, time("22:00")), later);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::agent::crypto::{ContentSealer, CHUNK_SIZE, HEADER_LEN, SEALED_CHUNK_SIZE};
+use crate::agent::progress::track;
+use crate::agent::throttle::Throttle;
+
+use futures::Stream;
+use reqwest::Body;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+// Size of the buffer used to stream file content to the server
+const UPLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
+// file_body opens the file as a streaming request body, returning it together
+// with the content length. Only one buffer of the file is held in memory at a
+// time and read errors abort the request instead of sending a truncated body.
+pub async fn file_body(path: &Path, throttle: &Throttle) -> Result<(Body, u64), std::io::Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let stream = ReaderStream::with_capacity(file, UPLOAD_BUFFER_SIZE);
+
+    Ok((Body::wrap_stream(track(throttle.limit(stream))), length))
+}
+
+// file_part_body streams `length` bytes of the file starting at `offset`
+pub async fn file_part_body(
+    path: &Path,
+    offset: u64,
+    length: u64,
+    throttle: &Throttle,
+) -> Result<Body, std::io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let stream = ReaderStream::with_capacity(file.take(length), UPLOAD_BUFFER_SIZE);
+
+    Ok(Body::wrap_stream(track(throttle.limit(stream))))
+}
+
+// buffer_body streams data that is already in memory
+pub fn buffer_body(data: Vec<u8>, throttle: &Throttle) -> Body {
+    let pieces: Vec<Result<Vec<u8>, std::io::Error>> = data
+        .chunks(UPLOAD_BUFFER_SIZE)
+        .map(|piece| Ok(piece.to_vec()))
+        .collect();
+
+    Body::wrap_stream(track(throttle.limit(futures::stream::iter(pieces))))
+}
+
+// encrypted_body streams `length` bytes of the encrypted form of the file,
+// starting at `offset` in the encrypted content. Chunks are encrypted as they
+// are sent, the ones the range only partly covers are cut to fit.
+pub async fn encrypted_body(
+    path: &Path,
+    sealer: Arc<ContentSealer>,
+    size: u64,
+    offset: u64,
+    length: u64,
+    throttle: &Throttle,
+) -> Result<Body, std::io::Error> {
+    let stream = encrypted_stream(path, sealer, size, offset, length).await?;
+
+    Ok(Body::wrap_stream(track(throttle.limit(stream))))
+}
+
+async fn encrypted_stream(
+    path: &Path,
+    sealer: Arc<ContentSealer>,
+    size: u64,
+    offset: u64,
+    length: u64,
+) -> Result<impl Stream<Item = Result<Vec<u8>, std::io::Error>>, std::io::Error> {
+    // the header counts as chunk -1
+    let (index, skip) = match offset.checked_sub(HEADER_LEN) {
+        None => (-1, offset),
+        Some(offset) => (
+            (offset / SEALED_CHUNK_SIZE) as i64,
+            offset % SEALED_CHUNK_SIZE,
+        ),
+    };
+
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(index.max(0) as u64 * CHUNK_SIZE))
+        .await?;
+
+    Ok(futures::stream::try_unfold(
+        (file, index, skip, length),
+        move |(mut file, index, skip, remaining)| {
+            let sealer = sealer.clone();
+            async move {
+                if remaining == 0 {
+                    return Ok(None);
+                }
+
+                let mut sealed = if index < 0 {
+                    sealer.header()
+                } else if index as u64 >= sealer.chunks() {
+                    return Err(std::io::Error::other("Range past the end of the file"));
+                } else {
+                    let start = index as u64 * CHUNK_SIZE;
+                    let mut plaintext =
+                        vec![0u8; CHUNK_SIZE.min(size.saturating_sub(start)) as usize];
+                    file.read_exact(&mut plaintext).await.map_err(|e| {
+                        std::io::Error::new(e.kind(), "File changed during the upload")
+                    })?;
+                    sealer.seal(index as u64, &plaintext)?
+                };
+
+                let end = (skip + remaining).min(sealed.len() as u64);
+                sealed.truncate(end as usize);
+                sealed.drain(..skip as usize);
+                let remaining = remaining - sealed.len() as u64;
+
+                Ok::<_, std::io::Error>(Some((sealed, (file, index + 1, 0, remaining))))
+            }
+        },
+    ))
+}
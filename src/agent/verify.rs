@@ -0,0 +1,394 @@
+use crate::agent::agent::Agent;
+use crate::agent::index::{file_digest, IndexEntry};
+use crate::agent::restore::{download_file, Version};
+use crate::agent::sync::changed_on_server;
+use crate::model::file::File;
+use crate::schema::file::{FileStatus, FileType};
+use charybdis::types::Uuid;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+
+// Number of files requested per page when listing the server
+const PAGE_SIZE: i32 = 100;
+
+/// What a verification checks and does about the problems it finds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VerifyOptions {
+    /// Download the stored content and check it against its checksum
+    pub deep: bool,
+    /// Upload the local version of the files with a problem again
+    pub repair: bool,
+}
+
+/// A problem found with a synced file.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// The server has no record or no stored content for the file
+    Missing,
+    /// The stored content doesn't match the checksum recorded for it
+    Corrupted,
+    /// The local content differs from what the server has
+    Divergent,
+    /// The file couldn't be checked, e.g. because the server wasn't reachable
+    Unchecked,
+}
+
+/// A file that failed verification.
+#[derive(Serialize, Debug, Clone)]
+pub struct Finding {
+    pub problem: Problem,
+    pub path: PathBuf,
+    pub file_id: Uuid,
+    pub detail: String,
+    /// The local version was uploaded again
+    pub repaired: bool,
+}
+
+/// Result of comparing the synced files with the server.
+#[derive(Serialize, Debug)]
+pub struct VerifyReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub deep: bool,
+    pub checked: usize,
+    /// Files changed or removed locally since the last sync, the next scan takes care of them
+    pub skipped: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl VerifyReport {
+    // count returns the number of files with the problem
+    pub fn count(&self, problem: Problem) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.problem == problem)
+            .count()
+    }
+
+    // write stores the report as JSON, replacing the previous one
+    pub fn write(&self, path: &Path) -> Result<(), std::io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let partial = path.with_extension("tmp");
+        fs::write(&partial, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&partial, path)
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(
+                f,
+                "{:<9} {}: {}{}",
+                format!("{:?}", finding.problem).to_lowercase(),
+                finding.path.to_string_lossy(),
+                finding.detail,
+                if finding.repaired {
+                    " (uploaded again)"
+                } else {
+                    ""
+                }
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Checked {} files: {} missing, {} corrupted, {} divergent, {} unchecked, {} skipped",
+            self.checked,
+            self.count(Problem::Missing),
+            self.count(Problem::Corrupted),
+            self.count(Problem::Divergent),
+            self.count(Problem::Unchecked),
+            self.skipped
+        )
+    }
+}
+
+// Outcome of checking a single file
+enum Check {
+    Ok,
+    Skipped,
+    Problem(Problem, String),
+}
+
+impl Agent {
+    // verify compares the synced files with the server: every indexed file needs a
+    // completed server record whose checksum matches the local content. A deep
+    // verification also downloads the stored content and checks it.
+    pub async fn verify(&self, options: VerifyOptions) -> Result<VerifyReport, std::io::Error> {
+        let mut report = VerifyReport {
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            deep: options.deep,
+            checked: 0,
+            skipped: 0,
+            findings: Vec::new(),
+        };
+
+        let mut indexed: HashMap<Uuid, (PathBuf, IndexEntry)> = self
+            .indexed_entries(&self.roots.dirs())
+            .await?
+            .into_iter()
+            .filter(|(_, entry)| entry.file.file_type == FileType::FILE.to_string())
+            .map(|(path, entry)| (entry.file.id, (path, entry)))
+            .collect();
+        let mut broken = Vec::new();
+
+        let mut last_id = None;
+        loop {
+            let page = self.api.list_files(last_id, PAGE_SIZE).await?;
+            last_id = page.last_id;
+
+            for file in page.unreadable {
+                if let Some((path, entry)) = indexed.remove(&file.id) {
+                    report.checked += 1;
+                    let detail = "The record can't be decrypted".to_string();
+                    broken.push((Problem::Unchecked, detail, path, entry.file.id, None));
+                }
+            }
+
+            for file in page.files {
+                let (path, entry) = match indexed.remove(&file.id) {
+                    Some(indexed) => indexed,
+                    None => continue,
+                };
+
+                let check = match self.check_file(&file, &path, &entry, options.deep).await {
+                    Ok(check) => check,
+                    Err(e) => Check::Problem(Problem::Unchecked, e.to_string()),
+                };
+                match check {
+                    Check::Ok => report.checked += 1,
+                    Check::Skipped => report.skipped += 1,
+                    Check::Problem(problem, detail) => {
+                        report.checked += 1;
+                        // a repair re-opens the file, its hash no longer matches so it is uploaded
+                        let previous = IndexEntry {
+                            hash: None,
+                            ..entry
+                        };
+                        broken.push((problem, detail, path, previous.file.id, Some(previous)));
+                    }
+                }
+            }
+
+            if page.is_last || last_id.is_none() {
+                break;
+            }
+        }
+
+        // Whatever the server did not list has no record there anymore. In two-way
+        // mode another device deleted it and the next pull removes it here.
+        for (_, (path, entry)) in indexed {
+            if !path.exists() || self.two_way {
+                report.skipped += 1;
+                continue;
+            }
+
+            report.checked += 1;
+            // a repair creates the file again
+            let detail = "No record on the server".to_string();
+            broken.push((Problem::Missing, detail, path, entry.file.id, None));
+        }
+
+        let mut tasks = Vec::new();
+        for (problem, detail, path, file_id, previous) in broken {
+            log::warn!(
+                "Verification failed, {:?}: {}: {}",
+                problem,
+                path.to_string_lossy(),
+                detail
+            );
+
+            if options.repair && problem != Problem::Unchecked {
+                if previous.is_none() {
+                    Self::unregister_upload(self.db.clone(), &path).await?;
+                }
+                tasks.push((
+                    path.clone(),
+                    self.spawn_upload(path.clone(), previous).await,
+                ));
+            }
+
+            report.findings.push(Finding {
+                problem,
+                path,
+                file_id,
+                detail,
+                repaired: false,
+            });
+        }
+
+        for (path, task) in tasks {
+            task.await.unwrap();
+
+            let repaired = !self.queue.is_waiting(&path).await;
+            for finding in report.findings.iter_mut().filter(|f| f.path == path) {
+                finding.repaired = repaired;
+            }
+        }
+
+        report.findings.sort_by(|a, b| a.path.cmp(&b.path));
+        report.finished_at = Utc::now();
+        Ok(report)
+    }
+
+    // check_file compares a single file with its server record
+    async fn check_file(
+        &self,
+        file: &File,
+        path: &Path,
+        entry: &IndexEntry,
+        deep: bool,
+    ) -> Result<Check, std::io::Error> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Check::Skipped),
+            Err(e) => return Err(e),
+        };
+
+        if file.status != FileStatus::CLOSED.to_string() {
+            return Ok(Check::Problem(
+                Problem::Missing,
+                "The upload was never completed".to_string(),
+            ));
+        }
+
+        // Files uploaded before checksums were recorded can't be compared
+        let checksum = match &file.checksum {
+            Some(checksum) => checksum,
+            None => return Ok(Check::Skipped),
+        };
+
+        let path_clone = path.to_path_buf();
+        let hash = spawn_blocking(move || file_digest(&path_clone))
+            .await
+            .expect("join failed")?;
+
+        // Changed since the last sync, the next scan uploads it
+        if entry.hash.as_deref() != Some(hash.as_str()) && entry.is_stale(&metadata) {
+            return Ok(Check::Skipped);
+        }
+
+        // In two-way mode a version uploaded by another device is pulled, a
+        // repair would overwrite it
+        if self.two_way && changed_on_server(file, entry) {
+            return Ok(Check::Skipped);
+        }
+
+        if *checksum != self.api.checksum(file.key_id.as_deref(), &hash)? {
+            return Ok(Check::Problem(
+                Problem::Divergent,
+                "Local content differs from the server".to_string(),
+            ));
+        }
+
+        if !deep {
+            return Ok(Check::Ok);
+        }
+
+        match self.stored_digest(file).await {
+            Ok(stored) if *checksum == self.api.checksum(file.key_id.as_deref(), &stored)? => {
+                Ok(Check::Ok)
+            }
+            Ok(_) => Ok(Check::Problem(
+                Problem::Corrupted,
+                "Stored content doesn't match its checksum".to_string(),
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Check::Problem(
+                Problem::Missing,
+                format!("Stored content not found: {}", e),
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(Check::Problem(
+                Problem::Corrupted,
+                format!("Stored content can't be read: {}", e),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    // stored_digest downloads the stored content of the file into the state
+    // directory and returns its hash
+    async fn stored_digest(&self, file: &File) -> Result<String, std::io::Error> {
+        let partial = self.state_dir.join(format!("verify-{}.part", file.id));
+
+        let transfer = self
+            .progress
+            .start(&file.name, file.size.unwrap_or(0).max(0) as u64);
+        let download = download_file(
+            self.api.clone(),
+            self.client.clone(),
+            &self.throttle,
+            file,
+            &partial,
+            Version::Current,
+        );
+        let result = match transfer.scope(download).await {
+            Ok(()) => {
+                let partial_clone = partial.clone();
+                spawn_blocking(move || file_digest(&partial_clone))
+                    .await
+                    .expect("join failed")
+            }
+            Err(e) => Err(e),
+        };
+
+        let _ = fs::remove_file(&partial);
+        result
+    }
+
+    // run_verifier verifies the synced files every verify_interval seconds and
+    // writes the report, if a schedule is set
+    pub async fn run_verifier(&self) {
+        let seconds = match self.verify_interval {
+            Some(seconds) => seconds,
+            None => return,
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+        // the first tick completes immediately, the first verification waits a full interval
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.control.stopped() => return,
+            }
+            self.control.wait_resumed().await;
+
+            // only repairs change anything, they are uploads and finish on their own
+            let result = tokio::select! {
+                result = async {
+                    let _sync = self.sync_lock.lock().await;
+                    log::info!("Verifying synced files");
+                    self.verify(self.verify_options).await
+                } => result,
+                _ = self.control.stopped() => return,
+            };
+            match result {
+                Ok(report) => {
+                    log::info!("{}", report.to_string().lines().last().unwrap_or_default());
+                    if let Err(e) = report.write(&self.verify_report) {
+                        log::error!(
+                            "Error writing {}: {}",
+                            self.verify_report.to_string_lossy(),
+                            e
+                        );
+                    }
+                }
+                Err(e) => log::error!("Error verifying: {}", e),
+            }
+        }
+    }
+}
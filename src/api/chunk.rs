@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+
+use charybdis::{
+    operations::{Find, Insert, Update},
+    types::Uuid,
+};
+use serde_json::json;
+use validator::Validate;
+
+use crate::config::app::AppState;
+use crate::error::{ErrorMessage, HttpError};
+use crate::schema::chunk::{ChunkRef, ChunksRequest, ChunksResponse, Manifest, PresignedChunk};
+use crate::{client::Client, jwt_auth, model::chunk::Chunk};
+
+use actix_web::{post, web, HttpResponse, Responder};
+
+// Chunks looked up per query, Scylla limits the size of IN restrictions
+const LOOKUP_BATCH: usize = 100;
+
+// How long a chunk counts as used after a lookup found it, before it is marked again
+const USED_REFRESH: chrono::Duration = chrono::Duration::hours(1);
+
+#[post("/chunks/missing")]
+pub async fn find_missing_chunks(
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    payload: web::Json<ChunksRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    let missing = missing_chunks(&data, &client, user.id, &payload.chunks).await?;
+    let mut chunks = Vec::with_capacity(missing.len());
+
+    for chunk in missing {
+        let url = client
+            .get_upload_presigned_url(&chunk_path(user.id, &chunk.hash), 60 * 60)
+            .await
+            .map_err(|err| {
+                log::error!("Error generating presigned chunk URL: {}", err);
+                HttpError::server_error(ErrorMessage::ServerError)
+            })?;
+
+        chunks.push(PresignedChunk {
+            hash: chunk.hash,
+            size: chunk.size,
+            url,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(json!(ChunksResponse { missing: chunks })))
+}
+
+// missing_chunks returns the chunks the user's store does not have yet. Chunks
+// that were uploaded since they were last looked up are found in S3 and recorded.
+pub async fn missing_chunks(
+    data: &web::Data<AppState>,
+    client: &Client,
+    user_id: Uuid,
+    chunks: &[ChunkRef],
+) -> Result<Vec<ChunkRef>, HttpError> {
+    let mut unique = HashSet::new();
+    let chunks: Vec<&ChunkRef> = chunks
+        .iter()
+        .filter(|chunk| unique.insert(chunk.hash.as_str()))
+        .collect();
+
+    if let Some(chunk) = chunks.iter().find(|chunk| !is_chunk_hash(&chunk.hash)) {
+        log::warn!("Invalid chunk hash: {:?}", chunk.hash);
+        return Err(HttpError::bad_request(ErrorMessage::InvalidChunkHash));
+    }
+
+    let mut missing = Vec::new();
+
+    for batch in chunks.chunks(LOOKUP_BATCH) {
+        let hashes: Vec<String> = batch.iter().map(|chunk| chunk.hash.clone()).collect();
+        let known: Vec<Chunk> = Chunk::find(
+            "SELECT * FROM chunks WHERE user_id = ? AND hash IN ?",
+            (user_id, hashes),
+        )
+        .execute(&data.database)
+        .await
+        .map_err(|err| {
+            log::error!("Error fetching chunks: {:?}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?
+        .try_collect()
+        .await
+        .map_err(|err| {
+            log::error!("Error fetching chunks: {:?}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+        // the chunk sweep keeps chunks used recently, a manifest may soon refer to them
+        let now = chrono::Utc::now();
+        for mut chunk in known
+            .iter()
+            .filter(|chunk| chunk.used_at.unwrap_or(chunk.created_at) < now - USED_REFRESH)
+            .cloned()
+        {
+            chunk.used_at = Some(now);
+            chunk
+                .update()
+                .execute(&data.database)
+                .await
+                .map_err(|err| {
+                    log::error!("Error marking chunk as used: {:?}", err);
+                    HttpError::server_error(ErrorMessage::ServerError)
+                })?;
+        }
+
+        let known: HashSet<&str> = known.iter().map(|chunk| chunk.hash.as_str()).collect();
+
+        for chunk in batch
+            .iter()
+            .filter(|chunk| !known.contains(chunk.hash.as_str()))
+        {
+            let head = client
+                .head_object(&chunk_path(user_id, &chunk.hash))
+                .await
+                .map_err(|err| {
+                    log::error!("Error looking up chunk: {}", err);
+                    HttpError::server_error(ErrorMessage::ServerError)
+                })?;
+
+            match head {
+                Some(head) if head.size == chunk.size => {
+                    Chunk {
+                        user_id,
+                        hash: chunk.hash.clone(),
+                        size: chunk.size,
+                        created_at: now,
+                        used_at: Some(now),
+                    }
+                    .insert()
+                    .execute(&data.database)
+                    .await
+                    .map_err(|err| {
+                        log::error!("Error recording chunk: {:?}", err);
+                        HttpError::server_error(ErrorMessage::ServerError)
+                    })?;
+                }
+                _ => missing.push((*chunk).clone()),
+            }
+        }
+    }
+
+    Ok(missing)
+}
+
+// presign_manifest returns the chunks of a manifest in order, with URLs to download them from
+pub async fn presign_manifest(
+    client: &Client,
+    user_id: Uuid,
+    manifest: Manifest,
+) -> Result<Vec<PresignedChunk>, HttpError> {
+    let mut chunks = Vec::with_capacity(manifest.chunks.len());
+    for chunk in manifest.chunks {
+        let url = client
+            .get_presigned_url(&chunk_path(user_id, &chunk.hash), 60 * 60 * 24)
+            .await
+            .map_err(|err| {
+                log::error!("Error generating presigned chunk URL: {}", err);
+                HttpError::server_error(ErrorMessage::ServerError)
+            })?;
+
+        chunks.push(PresignedChunk {
+            hash: chunk.hash,
+            size: chunk.size,
+            url,
+        });
+    }
+
+    Ok(chunks)
+}
+
+// chunk_path returns the S3 key holding a chunk of the user's chunk store
+pub fn chunk_path(user_id: Uuid, hash: &str) -> String {
+    format!("chunks/{}/{}", user_id, hash)
+}
+
+// is_chunk_hash checks for a lowercase hex encoded SHA-256 digest, hashes end up in object keys
+fn is_chunk_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
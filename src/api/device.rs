@@ -0,0 +1,118 @@
+use charybdis::{
+    operations::{Find, Insert, Update},
+    types::Uuid,
+};
+use serde_json::json;
+use validator::Validate;
+
+use crate::config::app::AppState;
+use crate::error::{ErrorMessage, HttpError};
+use crate::model::device::Device;
+use crate::schema::device::{
+    DeviceCreateRequest, DeviceCreateResponse, DeviceResponse, DevicesResponse,
+};
+use crate::{jwt_auth, utils::token::create_device_token};
+
+use actix_web::{
+    delete, get, post,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+
+// register_device adds a named device to the user and issues its credential.
+// Only the user's login token may do so, a device can't register others.
+#[post("/devices")]
+pub async fn register_device(
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    payload: web::Json<DeviceCreateRequest>,
+) -> Result<impl Responder, HttpError> {
+    jwt.require_user()?;
+    let user = jwt.get_user(&data.database).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    let device = Device::from_request(user.id, &payload);
+    device.insert().execute(&data.database).await.map_err(|e| {
+        log::error!("Error registering device: {:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })?;
+
+    let token = create_device_token(
+        &user.id.to_string(),
+        &device.id.to_string(),
+        data.config.app.jwt_secret.as_bytes(),
+        data.config.app.device_token_maxage_days,
+    )
+    .map_err(|e| {
+        log::error!("Error creating device token: {:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!(DeviceCreateResponse {
+        device: DeviceResponse::from(device),
+        token,
+    })))
+}
+
+#[get("/devices")]
+pub async fn get_devices(
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+
+    let devices = Device::find("SELECT * FROM devices WHERE user_id = ?", (user.id,))
+        .execute(&data.database)
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            log::error!("Error fetching devices: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!(DevicesResponse {
+        objects: devices.into_iter().map(DeviceResponse::from).collect(),
+    })))
+}
+
+// revoke_device rejects the credential of a device from now on. The device
+// stays listed with the time it was revoked, the files it created are kept.
+// A device credential may only revoke its own device.
+#[delete("/devices/{id}")]
+pub async fn revoke_device(
+    device_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let device_id = device_id.into_inner();
+
+    if jwt.device_id.is_some() && jwt.device_id != Some(device_id) {
+        return Err(HttpError::forbidden(ErrorMessage::UserTokenRequired));
+    }
+
+    let mut device = Device {
+        user_id: user.id,
+        id: device_id,
+        ..Default::default()
+    }
+    .find_by_primary_key()
+    .execute(&data.database)
+    .await
+    .map_err(|_| HttpError::not_found(ErrorMessage::DeviceNotFound))?;
+
+    if device.revoked_at.is_none() {
+        device.revoked_at = Some(chrono::Utc::now());
+        device.update().execute(&data.database).await.map_err(|e| {
+            log::error!("Error revoking device: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+    }
+
+    Ok(HttpResponse::Ok().json(json!(DeviceResponse::from(device))))
+}
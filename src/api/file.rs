@@ -8,9 +8,18 @@ use serde_json::json;
 
 use validator::Validate;
 
+use crate::api::chunk::{missing_chunks, presign_manifest};
+use crate::model::file::opened_on;
+use crate::schema::chunk::{Manifest, ManifestRequest, ManifestResponse};
+use crate::schema::file::FileCompleteRequest;
+use crate::schema::file::FileStatus;
 use crate::schema::file::FileType;
 use crate::schema::file::FileUpdateRequest;
 use crate::schema::file::FilesResponse;
+use crate::schema::file::{
+    MultipartAbortQuery, MultipartCompleteRequest, MultipartPartsRequest, MultipartPartsResponse,
+    MultipartUploadResponse, PresignedPart,
+};
 use crate::{client::Client, model::file::File};
 use crate::{error::ErrorMessage, schema::file::FileResponse};
 use crate::{jwt_auth, model::user::User, schema::file::FileCreateRequest};
@@ -26,8 +35,11 @@ use crate::error::HttpError;
 
 #[derive(Deserialize)]
 pub struct PaginationQuery {
-    last_id: Option<Uuid>, // Adjust type based on your ID field type
-    limit: Option<i32>,    // Optional limit parameter
+    pub last_id: Option<Uuid>, // Adjust type based on your ID field type
+    pub limit: Option<i32>,    // Optional limit parameter
+    // only files changed after this time, oldest change first. Pages continue
+    // from the `updated_at` and `id` of the last file.
+    pub updated_after: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[get("/files")]
@@ -43,6 +55,11 @@ pub async fn get_files(
         .await
         .map_err(|_| HttpError::not_found(ErrorMessage::FileNotFound))?;
 
+    if let Some(updated_after) = query.updated_after {
+        let files = changed_files(&data, user.id, updated_after, &query).await?;
+        return Ok(HttpResponse::Ok().json(json!(&FilesResponse { objects: files })));
+    }
+
     match query.last_id.clone() {
         Some(last_id) => {
             let files = File::find(
@@ -90,6 +107,46 @@ pub async fn get_files(
     }
 }
 
+// changed_files returns a page of the user's files changed after the given time
+async fn changed_files(
+    data: &web::Data<AppState>,
+    user_id: Uuid,
+    updated_after: chrono::DateTime<chrono::Utc>,
+    query: &PaginationQuery,
+) -> Result<Vec<File>, HttpError> {
+    let limit = query.limit.unwrap_or(100).min(100);
+    let files = match query.last_id {
+        Some(last_id) => {
+            File::find(
+                "SELECT * FROM files_by_updated_at WHERE user_id = ? AND (updated_at, id) > (?, ?) LIMIT ?",
+                (user_id, updated_after, last_id, limit),
+            )
+            .execute(&data.database)
+            .await
+        }
+        None => {
+            File::find(
+                "SELECT * FROM files_by_updated_at WHERE user_id = ? AND updated_at > ? LIMIT ?",
+                (user_id, updated_after, limit),
+            )
+            .execute(&data.database)
+            .await
+        }
+    };
+
+    files
+        .map_err(|e| {
+            log::error!("Error fetching changed files: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            log::error!("Error fetching changed files: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })
+}
+
 #[post("/files")]
 pub async fn create_file(
     data: web::Data<AppState>,
@@ -106,9 +163,16 @@ pub async fn create_file(
 
     let validated = payload.validate();
 
+    // file content is uploaded first, the file is closed by the complete endpoint
+    if payload.file_type.to_string() == FileType::FILE.to_string()
+        && payload.status.to_string() == FileStatus::CLOSED.to_string()
+    {
+        return Err(HttpError::bad_request(ErrorMessage::UploadNotCompleted));
+    }
+
     let response = match validated {
         Ok(_) => {
-            let file = File::from_request(user.id.clone(), &payload);
+            let file = File::from_request(user.id.clone(), jwt.device_id, &payload);
             file.insert().execute(&data.database).await.map_err(|err| {
                 log::error!("Error fetching files: {:?}", err);
                 HttpError::server_error(ErrorMessage::ServerError)
@@ -122,6 +186,14 @@ pub async fn create_file(
                 status: file.status,
                 created_at: file.created_at,
                 modified_at: file.modified_at,
+                checksum: file.checksum.clone(),
+                size: file.size,
+                etag: file.etag.clone(),
+                key_id: file.key_id.clone(),
+                chunked: file.chunked,
+                codec: file.codec.clone(),
+                metadata: file.metadata.clone(),
+                device_id: file.device_id,
                 presigned_url: None,
                 upload_presigned_url: None,
             };
@@ -160,6 +232,7 @@ pub async fn update_file(
     file_id: Path<Uuid>,
     data: web::Data<AppState>,
     jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
     payload: web::Json<FileUpdateRequest>,
 ) -> Result<impl Responder, HttpError> {
     let user = jwt.get_user(&data.database).await?;
@@ -173,6 +246,64 @@ pub async fn update_file(
 
     let response = match validated {
         Ok(_) => {
+            let existing = File {
+                user_id: user.id.clone(),
+                id: file_id.clone(),
+                ..Default::default()
+            }
+            .find_by_primary_key()
+            .execute(&data.database)
+            .await
+            .map_err(|_| HttpError::not_found(ErrorMessage::FileNotFound))?;
+
+            // an upload is only closed once the complete endpoint has seen the object
+            if existing.file_type == FileType::FILE.to_string()
+                && existing.status == FileStatus::OPEN.to_string()
+                && payload.status.to_string() == FileStatus::CLOSED.to_string()
+            {
+                return Err(HttpError::bad_request(ErrorMessage::UploadNotCompleted));
+            }
+
+            // a renamed or moved file keeps its content, move the object to the new path.
+            // A re-opened file keeps it too, until the new upload is completed.
+            let mut moved_etag = None;
+            if existing.file_type == FileType::FILE.to_string()
+                && existing.etag.is_some()
+                && (existing.directory != payload.directory || existing.name != payload.name)
+            {
+                let old_object_path = std::path::Path::new("memora")
+                    .join(&existing.directory)
+                    .join(&existing.name);
+                let object_path = std::path::Path::new("memora")
+                    .join(&payload.directory)
+                    .join(&payload.name);
+
+                let etag = client
+                    .copy_object(
+                        &old_object_path.to_str().unwrap(),
+                        &object_path.to_str().unwrap(),
+                    )
+                    .await
+                    .map_err(|err| {
+                        log::error!("Error copying object: {}", err);
+                        HttpError::server_error(ErrorMessage::ServerError)
+                    })?;
+                // a copy made in parts gets an ETag of its own
+                moved_etag = Some(etag);
+                client
+                    .delete_object(&old_object_path.to_str().unwrap())
+                    .await
+                    .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?;
+            }
+
+            // size and etag describe the last completed upload, they are kept while
+            // the file is re-opened so an abandoned upload can fall back to it
+            let reopened = payload.status.to_string() == FileStatus::OPEN.to_string();
+            let opened_at = match (reopened, existing.opened_at) {
+                (true, Some(opened_at)) => Some(opened_at),
+                (true, None) => Some(chrono::Utc::now()),
+                (false, _) => None,
+            };
             let file = File {
                 user_id: user.id.clone(),
                 id: file_id.into_inner(),
@@ -182,14 +313,25 @@ pub async fn update_file(
                 status: payload.status.to_string(),
                 created_at: payload.created_at,
                 modified_at: payload.modified_at,
-                ..Default::default()
+                checksum: payload.checksum.clone(),
+                key_id: payload.key_id.clone(),
+                // the stored object, whatever it holds, stays until new content is completed
+                chunked: existing.chunked,
+                codec: existing.codec.clone(),
+                metadata: payload.metadata.clone(),
+                device_id: existing.device_id,
+                size: existing.size,
+                etag: moved_etag.or_else(|| existing.etag.clone()),
+                opened_at,
+                opened_on: opened_on(opened_at),
+                updated_at: Some(chrono::Utc::now()),
             };
             file.update().execute(&data.database).await.map_err(|e| {
                 log::error!("Error updating file: {:?}", e);
                 HttpError::server_error(ErrorMessage::ServerError)
             })?;
 
-            let file_response = FileResponse {
+            let mut file_response = FileResponse {
                 id: file.id,
                 name: file.name,
                 directory: file.directory,
@@ -197,10 +339,41 @@ pub async fn update_file(
                 status: file.status,
                 created_at: file.created_at,
                 modified_at: file.modified_at,
+                checksum: file.checksum.clone(),
+                size: file.size,
+                etag: file.etag.clone(),
+                key_id: file.key_id.clone(),
+                chunked: file.chunked,
+                codec: file.codec.clone(),
+                metadata: file.metadata.clone(),
+                device_id: file.device_id,
                 presigned_url: None,
                 upload_presigned_url: None,
             };
 
+            // a file re-opened for writing gets a new upload URL for its content
+            if file_response.file_type == FileType::FILE.to_string()
+                && file_response.status == FileStatus::OPEN.to_string()
+            {
+                let object_path = std::path::Path::new("memora")
+                    .join(&file_response.directory)
+                    .join(&file_response.name);
+
+                let upload_presigned_url = client
+                    .get_upload_presigned_url(&object_path.to_str().unwrap(), 60 * 60 * 24)
+                    .await;
+
+                match upload_presigned_url {
+                    Ok(url) => {
+                        log::info!("Presigned UPLOAD URL: {:?}", url);
+                        file_response.upload_presigned_url = Some(url);
+                    }
+                    Err(err) => {
+                        log::error!("Error generating presigned URL: {}", err);
+                    }
+                }
+            }
+
             HttpResponse::Ok().json(json!(file_response))
         }
         Err(err) => HttpResponse::BadRequest().json(json!(err)),
@@ -242,11 +415,22 @@ pub async fn get_file(
                 status: file.status,
                 created_at: file.created_at,
                 modified_at: file.modified_at,
+                checksum: file.checksum.clone(),
+                size: file.size,
+                etag: file.etag.clone(),
+                key_id: file.key_id.clone(),
+                chunked: file.chunked,
+                codec: file.codec.clone(),
+                metadata: file.metadata.clone(),
+                device_id: file.device_id,
                 presigned_url: None,
                 upload_presigned_url: None,
             };
 
-            if file_response.file_type == FileType::FILE.to_string() {
+            // chunked content is fetched through the manifest endpoint
+            if file_response.file_type == FileType::FILE.to_string()
+                && file_response.chunked != Some(true)
+            {
                 // join directory and name to create object path
                 let object_path = std::path::Path::new("memora")
                     .join(&file.directory)
@@ -258,8 +442,10 @@ pub async fn get_file(
 
                 match presigned_url {
                     Ok(url) => {
-                        log::info!("Presigned UPLOAD URL: {:?}", url);
-                        file_response.upload_presigned_url = Some(url);
+                        log::info!("Presigned URL: {:?}", url);
+                        // older clients read the download URL from upload_presigned_url
+                        file_response.upload_presigned_url = Some(url.clone());
+                        file_response.presigned_url = Some(url);
                     }
                     Err(err) => {
                         log::error!("Error generating presigned URL: {}", err);
@@ -321,6 +507,293 @@ pub async fn delete_file(
     return Ok(HttpResponse::Ok().json(json!("File deleted")));
 }
 
+// find_upload_target loads a file of the user that content can be uploaded to
+pub(crate) async fn find_upload_target(
+    data: &web::Data<AppState>,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> Result<File, HttpError> {
+    let file = File {
+        user_id,
+        id: file_id,
+        ..Default::default()
+    }
+    .find_by_primary_key()
+    .execute(&data.database)
+    .await
+    .map_err(|_| HttpError::not_found(ErrorMessage::FileNotFound))?;
+
+    if file.file_type != FileType::FILE.to_string() {
+        return Err(HttpError::bad_request(ErrorMessage::NotAFile));
+    }
+
+    Ok(file)
+}
+
+// object_path returns the S3 key holding the content of the file
+pub fn object_path(file: &File) -> String {
+    std::path::Path::new("memora")
+        .join(&file.directory)
+        .join(&file.name)
+        .to_string_lossy()
+        .to_string()
+}
+
+#[post("/files/{id}/multipart")]
+pub async fn create_multipart_upload(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    let upload_id = client
+        .create_multipart_upload(&object_path(&file))
+        .await
+        .map_err(|err| {
+            log::error!("Error creating multipart upload: {}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!(MultipartUploadResponse { upload_id })))
+}
+
+#[post("/files/{id}/multipart/parts")]
+pub async fn presign_multipart_parts(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    payload: web::Json<MultipartPartsRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    let object = object_path(&file);
+    let mut parts = Vec::with_capacity(payload.part_numbers.len());
+
+    for part_number in payload.part_numbers.iter().copied() {
+        if !(1..=10000).contains(&part_number) {
+            return Err(HttpError::bad_request(ErrorMessage::InvalidPartNumber));
+        }
+
+        let url = client
+            .get_upload_part_presigned_url(&object, &payload.upload_id, part_number, 60 * 60)
+            .await
+            .map_err(|err| {
+                log::error!("Error generating presigned part URL: {}", err);
+                HttpError::server_error(ErrorMessage::ServerError)
+            })?;
+
+        parts.push(PresignedPart { part_number, url });
+    }
+
+    Ok(HttpResponse::Ok().json(json!(MultipartPartsResponse { parts })))
+}
+
+#[post("/files/{id}/multipart/complete")]
+pub async fn complete_multipart_upload(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    payload: web::Json<MultipartCompleteRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    let parts = payload
+        .parts
+        .iter()
+        .map(|part| (part.part_number, part.etag.clone()))
+        .collect();
+
+    client
+        .complete_multipart_upload(&object_path(&file), &payload.upload_id, parts)
+        .await
+        .map_err(|err| {
+            log::error!("Error completing multipart upload: {}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!("Multipart upload completed")))
+}
+
+#[delete("/files/{id}/multipart")]
+pub async fn abort_multipart_upload(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    query: web::Query<MultipartAbortQuery>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    client
+        .abort_multipart_upload(&object_path(&file), &query.upload_id)
+        .await
+        .map_err(|err| {
+            log::error!("Error aborting multipart upload: {}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!("Multipart upload aborted")))
+}
+
+#[post("/files/{id}/complete")]
+pub async fn complete_upload(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    payload: web::Json<FileCompleteRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let mut file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    // only trust the upload once the object is actually stored
+    let head = client
+        .head_object(&object_path(&file))
+        .await
+        .map_err(|err| {
+            log::error!("Error looking up uploaded object: {}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?
+        .ok_or_else(|| HttpError::bad_request(ErrorMessage::UploadNotFound))?;
+
+    if payload.size != head.size {
+        log::warn!(
+            "Upload of {} has {} bytes, expected {}",
+            file.id,
+            head.size,
+            payload.size
+        );
+        return Err(HttpError::bad_request(ErrorMessage::UploadSizeMismatch));
+    }
+
+    // a re-opened file still has the object of its last completed upload
+    if file.etag.as_deref() == Some(head.etag.as_str()) {
+        log::warn!("Upload of {} didn't replace the stored object", file.id);
+        return Err(HttpError::bad_request(ErrorMessage::UploadUnchanged));
+    }
+
+    file.status = FileStatus::CLOSED.to_string();
+    file.modified_at = payload.modified_at;
+    file.checksum = payload.checksum.clone();
+    file.size = Some(head.size);
+    file.etag = Some(head.etag);
+    file.opened_at = None;
+    file.opened_on = None;
+    file.chunked = None;
+    file.codec = payload.codec.clone();
+    file.updated_at = Some(chrono::Utc::now());
+
+    file.update().execute(&data.database).await.map_err(|e| {
+        log::error!("Error updating file: {:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!(FileResponse::from(file))))
+}
+
+#[post("/files/{id}/manifest")]
+pub async fn commit_manifest(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    payload: web::Json<ManifestRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let mut file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    // every chunk has to be in the store before the manifest can refer to it
+    let missing = missing_chunks(&data, &client, user.id, &payload.chunks).await?;
+    if !missing.is_empty() {
+        log::warn!(
+            "Manifest of {} refers to {} missing chunks",
+            file.id,
+            missing.len()
+        );
+        return Err(HttpError::bad_request(ErrorMessage::ChunksMissing));
+    }
+
+    let manifest = Manifest {
+        chunks: payload.chunks.clone(),
+    };
+    let head = client
+        .put_manifest(&object_path(&file), &manifest)
+        .await
+        .map_err(|err| {
+            log::error!("Error storing manifest: {}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    file.status = FileStatus::CLOSED.to_string();
+    file.modified_at = payload.modified_at;
+    file.checksum = payload.checksum.clone();
+    // the size of the stored chunks, not of the manifest
+    file.size = Some(manifest.chunks.iter().map(|chunk| chunk.size).sum());
+    file.etag = Some(head.etag);
+    file.opened_at = None;
+    file.opened_on = None;
+    file.chunked = Some(true);
+    file.codec = payload.codec.clone();
+    file.updated_at = Some(chrono::Utc::now());
+
+    file.update().execute(&data.database).await.map_err(|e| {
+        log::error!("Error updating file: {:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!(FileResponse::from(file))))
+}
+
+#[get("/files/{id}/manifest")]
+pub async fn get_file_manifest(
+    file_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let file = find_upload_target(&data, user.id, file_id.into_inner()).await?;
+
+    if file.chunked != Some(true) {
+        return Err(HttpError::bad_request(ErrorMessage::NotChunked));
+    }
+
+    let manifest = client
+        .get_manifest(&object_path(&file))
+        .await
+        .map_err(|err| {
+            log::error!("Error reading manifest: {}", err);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    let chunks = presign_manifest(&client, user.id, manifest).await?;
+    Ok(HttpResponse::Ok().json(json!(ManifestResponse { chunks })))
+}
+
 #[get("/files/{directory:.*}")]
 pub async fn get_files_by_directory(
     directory: Path<String>,
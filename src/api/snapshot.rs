@@ -0,0 +1,434 @@
+use charybdis::{
+    operations::{Find, Insert, Update},
+    types::Uuid,
+};
+use serde_json::json;
+use validator::Validate;
+
+use crate::api::chunk::presign_manifest;
+use crate::api::file::{object_path, PaginationQuery};
+use crate::config::app::AppState;
+use crate::error::{ErrorMessage, HttpError};
+use crate::model::file::File;
+use crate::model::snapshot::{Snapshot, SnapshotFile, SnapshotObject};
+use crate::schema::chunk::ManifestResponse;
+use crate::schema::file::{FileResponse, FileStatus, FileType, FilesResponse};
+use crate::schema::snapshot::{
+    SnapshotCreateRequest, SnapshotFilesRequest, SnapshotFilesResponse, SnapshotResponse,
+    SnapshotsResponse,
+};
+use crate::{
+    client::{Client, S3ExampleError},
+    jwt_auth,
+};
+
+use actix_web::{
+    delete, get, post,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+
+// create_snapshot starts a snapshot, files are added to it until it is completed
+#[post("/snapshots")]
+pub async fn create_snapshot(
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    payload: web::Json<SnapshotCreateRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    let snapshot = Snapshot::from_request(user.id, jwt.device_id, &payload);
+    snapshot
+        .insert()
+        .execute(&data.database)
+        .await
+        .map_err(|e| {
+            log::error!("Error creating snapshot: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!(SnapshotResponse::from(snapshot))))
+}
+
+#[get("/snapshots")]
+pub async fn get_snapshots(
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+
+    let snapshots = Snapshot::find("SELECT * FROM snapshots WHERE user_id = ?", (user.id,))
+        .execute(&data.database)
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            log::error!("Error fetching snapshots: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!(SnapshotsResponse {
+        objects: snapshots.into_iter().map(SnapshotResponse::from).collect(),
+    })))
+}
+
+// add_snapshot_files captures the current version of the files. The stored
+// content is copied to an object of its own, so that later uploads don't
+// replace it; versions captured by an earlier snapshot are shared.
+#[post("/snapshots/{id}/files")]
+pub async fn add_snapshot_files(
+    snapshot_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+    payload: web::Json<SnapshotFilesRequest>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let snapshot = find_snapshot(&data, user.id, snapshot_id.into_inner()).await?;
+
+    if snapshot.status != FileStatus::OPEN.to_string() {
+        return Err(HttpError::bad_request(ErrorMessage::SnapshotClosed));
+    }
+
+    if let Err(err) = payload.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!(err)));
+    }
+
+    let mut captured = 0;
+    let mut skipped = Vec::new();
+
+    for file_id in &payload.file_ids {
+        let file = match (File {
+            user_id: user.id,
+            id: *file_id,
+            ..Default::default()
+        })
+        .find_by_primary_key()
+        .execute(&data.database)
+        .await
+        {
+            Ok(file) => file,
+            Err(_) => {
+                skipped.push(*file_id);
+                continue;
+            }
+        };
+
+        let object = if file.file_type == FileType::FILE.to_string() {
+            if file.status != FileStatus::CLOSED.to_string() {
+                skipped.push(*file_id);
+                continue;
+            }
+
+            // one file that can't be captured doesn't fail the others
+            match capture_object(&client, &file).await {
+                Ok(Some(object)) => Some(object),
+                Ok(None) => {
+                    log::warn!("No stored content to capture for {}", file.id);
+                    skipped.push(*file_id);
+                    continue;
+                }
+                Err(err) => {
+                    log::error!("Error capturing {}: {}", file.id, err);
+                    skipped.push(*file_id);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+
+        if let Some(object) = &object {
+            SnapshotObject {
+                user_id: user.id,
+                object: object.clone(),
+                snapshot_id: snapshot.id,
+            }
+            .insert()
+            .execute(&data.database)
+            .await
+            .map_err(|e| {
+                log::error!("Error recording snapshot object: {:?}", e);
+                HttpError::server_error(ErrorMessage::ServerError)
+            })?;
+        }
+
+        SnapshotFile::capture(snapshot.id, &file, object)
+            .insert()
+            .execute(&data.database)
+            .await
+            .map_err(|e| {
+                log::error!("Error recording snapshot file: {:?}", e);
+                HttpError::server_error(ErrorMessage::ServerError)
+            })?;
+        captured += 1;
+    }
+
+    Ok(HttpResponse::Ok().json(json!(SnapshotFilesResponse { captured, skipped })))
+}
+
+// complete_snapshot closes the snapshot and records what it captured
+#[post("/snapshots/{id}/complete")]
+pub async fn complete_snapshot(
+    snapshot_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let mut snapshot = find_snapshot(&data, user.id, snapshot_id.into_inner()).await?;
+
+    if snapshot.status != FileStatus::OPEN.to_string() {
+        return Err(HttpError::bad_request(ErrorMessage::SnapshotClosed));
+    }
+
+    let files = snapshot_files(&data, user.id, snapshot.id).await?;
+
+    snapshot.status = FileStatus::CLOSED.to_string();
+    snapshot.completed_at = Some(chrono::Utc::now());
+    snapshot.files = Some(files.len() as i64);
+    snapshot.size = Some(files.iter().filter_map(|file| file.size).sum());
+
+    snapshot
+        .update()
+        .execute(&data.database)
+        .await
+        .map_err(|e| {
+            log::error!("Error completing snapshot: {:?}", e);
+            HttpError::server_error(ErrorMessage::ServerError)
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!(SnapshotResponse::from(snapshot))))
+}
+
+// get_snapshot_files returns a page of the files of the snapshot as they were captured
+#[get("/snapshots/{id}/files")]
+pub async fn get_snapshot_files(
+    snapshot_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    query: web::Query<PaginationQuery>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let snapshot = find_snapshot(&data, user.id, snapshot_id.into_inner()).await?;
+    let limit = query.limit.unwrap_or(100).min(100);
+
+    let files = match query.last_id {
+        Some(last_id) => SnapshotFile::find(
+            "SELECT * FROM snapshot_files WHERE user_id = ? AND snapshot_id = ? AND file_id > ? LIMIT ?",
+            (user.id, snapshot.id, last_id, limit),
+        )
+        .execute(&data.database)
+        .await,
+        None => SnapshotFile::find(
+            "SELECT * FROM snapshot_files WHERE user_id = ? AND snapshot_id = ? LIMIT ?",
+            (user.id, snapshot.id, limit),
+        )
+        .execute(&data.database)
+        .await,
+    }
+    .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?
+    .try_collect()
+    .await
+    .map_err(|e| {
+        log::error!("Error fetching snapshot files: {:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!(&FilesResponse {
+        objects: files.into_iter().map(File::from).collect(),
+    })))
+}
+
+// get_snapshot_file returns a file of the snapshot with a URL to download the
+// captured content from, chunked content is fetched through its manifest
+#[get("/snapshots/{id}/files/{file_id}")]
+pub async fn get_snapshot_file(
+    path: Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let (snapshot_id, file_id) = path.into_inner();
+    let file = find_snapshot_file(&data, user.id, snapshot_id, file_id).await?;
+
+    let object = file.object.clone();
+    let chunked = file.chunked == Some(true);
+    let mut file_response = FileResponse::from(File::from(file));
+
+    if let (Some(object), false) = (object, chunked) {
+        match client.get_presigned_url(&object, 60 * 60 * 24).await {
+            Ok(url) => file_response.presigned_url = Some(url),
+            Err(err) => log::error!("Error generating presigned URL: {}", err),
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!(file_response)))
+}
+
+#[get("/snapshots/{id}/files/{file_id}/manifest")]
+pub async fn get_snapshot_manifest(
+    path: Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let (snapshot_id, file_id) = path.into_inner();
+    let file = find_snapshot_file(&data, user.id, snapshot_id, file_id).await?;
+
+    let object = match (file.chunked, file.object) {
+        (Some(true), Some(object)) => object,
+        _ => return Err(HttpError::bad_request(ErrorMessage::NotChunked)),
+    };
+
+    let manifest = client.get_manifest(&object).await.map_err(|err| {
+        log::error!("Error reading manifest: {}", err);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })?;
+
+    let chunks = presign_manifest(&client, user.id, manifest).await?;
+    Ok(HttpResponse::Ok().json(json!(ManifestResponse { chunks })))
+}
+
+// delete_snapshot removes the snapshot and the captured content no other
+// snapshot refers to. Chunks are left to the chunk sweep.
+#[delete("/snapshots/{id}")]
+pub async fn delete_snapshot(
+    snapshot_id: Path<Uuid>,
+    data: web::Data<AppState>,
+    jwt: jwt_auth::JwtMiddleware,
+    client: web::Data<Client>,
+) -> Result<impl Responder, HttpError> {
+    let user = jwt.get_user(&data.database).await?;
+    let snapshot = find_snapshot(&data, user.id, snapshot_id.into_inner()).await?;
+
+    for file in snapshot_files(&data, user.id, snapshot.id).await? {
+        let object = match file.object {
+            Some(object) => object,
+            None => continue,
+        };
+
+        SnapshotObject::delete_by_user_id_and_object_and_snapshot_id(
+            user.id,
+            object.clone(),
+            snapshot.id,
+        )
+        .execute(&data.database)
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?;
+
+        let referenced: Vec<SnapshotObject> = SnapshotObject::find(
+            "SELECT * FROM snapshot_objects WHERE user_id = ? AND object = ? LIMIT 1",
+            (user.id, object.clone()),
+        )
+        .execute(&data.database)
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?
+        .try_collect()
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?;
+
+        if referenced.is_empty() {
+            client
+                .delete_object(&object)
+                .await
+                .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?;
+        }
+    }
+
+    SnapshotFile::delete_by_user_id_and_snapshot_id(user.id, snapshot.id)
+        .execute(&data.database)
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?;
+    Snapshot::delete_by_user_id_and_id(user.id, snapshot.id)
+        .execute(&data.database)
+        .await
+        .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?;
+
+    Ok(HttpResponse::Ok().json(json!("Snapshot deleted")))
+}
+
+async fn find_snapshot(
+    data: &web::Data<AppState>,
+    user_id: Uuid,
+    snapshot_id: Uuid,
+) -> Result<Snapshot, HttpError> {
+    Snapshot {
+        user_id,
+        id: snapshot_id,
+        ..Default::default()
+    }
+    .find_by_primary_key()
+    .execute(&data.database)
+    .await
+    .map_err(|_| HttpError::not_found(ErrorMessage::SnapshotNotFound))
+}
+
+async fn find_snapshot_file(
+    data: &web::Data<AppState>,
+    user_id: Uuid,
+    snapshot_id: Uuid,
+    file_id: Uuid,
+) -> Result<SnapshotFile, HttpError> {
+    SnapshotFile {
+        user_id,
+        snapshot_id,
+        file_id,
+        ..Default::default()
+    }
+    .find_by_primary_key()
+    .execute(&data.database)
+    .await
+    .map_err(|_| HttpError::not_found(ErrorMessage::FileNotFound))
+}
+
+// snapshot_files returns every file captured by the snapshot
+async fn snapshot_files(
+    data: &web::Data<AppState>,
+    user_id: Uuid,
+    snapshot_id: Uuid,
+) -> Result<Vec<SnapshotFile>, HttpError> {
+    SnapshotFile::find(
+        "SELECT * FROM snapshot_files WHERE user_id = ? AND snapshot_id = ?",
+        (user_id, snapshot_id),
+    )
+    .execute(&data.database)
+    .await
+    .map_err(|_| HttpError::server_error(ErrorMessage::ServerError))?
+    .try_collect()
+    .await
+    .map_err(|e| {
+        log::error!("Error fetching snapshot files: {:?}", e);
+        HttpError::server_error(ErrorMessage::ServerError)
+    })
+}
+
+// capture_object copies the stored content of a file to an object named after
+// its ETag, which stays unchanged until the file is uploaded again. `None` if
+// there is no stored content.
+async fn capture_object(client: &Client, file: &File) -> Result<Option<String>, S3ExampleError> {
+    let source = object_path(file);
+    let head = match client.head_object(&source).await? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+
+    let version: String = head
+        .etag
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect();
+    let object = format!("versions/{}/{}/{}", file.user_id, file.id, version);
+
+    if client.head_object(&object).await?.is_none() {
+        client.copy_object(&source, &object).await?;
+    }
+
+    Ok(Some(object))
+}
@@ -69,9 +69,11 @@ pub async fn create_user(
 pub async fn update_user_me(
     req: HttpRequest,
     data: web::Data<AppState>,
-    _: jwt_auth::JwtMiddleware,
+    jwt: jwt_auth::JwtMiddleware,
     payload: web::Json<UserUpdateRequest>,
 ) -> Result<impl Responder, HttpError> {
+    jwt.require_user()?;
+
     let ext = req.extensions();
     let user_id = ext.get::<uuid::Uuid>().unwrap();
 
@@ -130,8 +132,10 @@ pub async fn update_user_me(
 pub async fn get_user_me(
     req: HttpRequest,
     data: web::Data<AppState>,
-    _: jwt_auth::JwtMiddleware,
+    jwt: jwt_auth::JwtMiddleware,
 ) -> Result<impl Responder, HttpError> {
+    jwt.get_user(&data.database).await?;
+
     let ext = req.extensions();
     let user_id = ext.get::<uuid::Uuid>().unwrap();
 
@@ -165,8 +169,10 @@ pub async fn get_user_me(
 pub async fn delete_user(
     req: HttpRequest,
     data: web::Data<AppState>,
-    _: jwt_auth::JwtMiddleware,
+    jwt: jwt_auth::JwtMiddleware,
 ) -> Result<impl Responder, HttpError> {
+    jwt.require_user()?;
+
     let ext = req.extensions();
     let user_id = ext.get::<uuid::Uuid>().unwrap();
 
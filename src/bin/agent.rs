@@ -1,30 +1,1017 @@
 use memora::agent::agent::Agent;
+use memora::agent::config::{AgentConfig, BandwidthOverride};
+use memora::agent::control::{self, ControlCommand};
+use memora::agent::device::{self, DeviceClient, DeviceCredential};
+use memora::agent::logging::{self, Console, LogFormat};
+use memora::agent::metadata::SymlinkPolicy;
+use memora::agent::plan::StateCopy;
+use memora::agent::remote::RemoteAgent;
+use memora::agent::restore::Version;
+use memora::agent::service::{ServiceUnit, Signal, Signals};
+use memora::agent::snapshot::RetentionPolicy;
+use memora::agent::verify::VerifyOptions;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use uuid::Uuid;
 
 /// Command-line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Directory where files are stored
-    #[arg(short, long, default_value = "./data")]
-    dir: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Token for authentication
-    #[arg(short = 't', long)]
-    token: String,
+    /// Path to a TOML config file, command-line arguments take precedence
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Directory where files are stored [default: ./data]
+    #[arg(short, long, global = true)]
+    dir: Option<PathBuf>,
+
+    /// Directory synced to a prefix on the server, LOCAL=PREFIX such as
+    /// ~/Photos=/photos, can be repeated and replaces --dir
+    #[arg(long, global = true)]
+    root: Vec<String>,
+
+    /// Token for authentication [default: the credential stored by `login`]
+    #[arg(short = 't', long, global = true)]
+    token: Option<String>,
+
+    /// Base URL of the memora server [default: http://localhost:8000]
+    #[arg(short, long, global = true)]
+    server_url: Option<String>,
+
+    /// Directory for the agent's state database [default: .fjall_data]
+    #[arg(long, global = true)]
+    state_dir: Option<PathBuf>,
+
+    /// Seconds between scans in polling mode [default: 5]
+    #[arg(long)]
+    scan_interval: Option<u64>,
+
+    /// Seconds between full rescans in watch mode [default: 3600]
+    #[arg(long)]
+    rescan_interval: Option<u64>,
+
+    /// Number of concurrent upload and download workers [default: 4]
+    #[arg(long, global = true)]
+    workers: Option<usize>,
+
+    /// Sync changes as filesystem events arrive instead of polling
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Also pull changes made on the server by other devices
+    #[arg(long)]
+    two_way: bool,
+
+    /// Files of at least this many bytes are uploaded in parts [default: 67108864]
+    #[arg(long)]
+    multipart_threshold: Option<u64>,
+
+    /// Size in bytes of each part of a multipart upload [default: 16777216]
+    #[arg(long)]
+    multipart_part_size: Option<u64>,
+
+    /// Upload files as whole objects instead of deduplicated chunks
+    #[arg(long)]
+    no_chunking: bool,
+
+    /// Files of at least this many bytes are uploaded in chunks [default: 1048576]
+    #[arg(long)]
+    chunk_threshold: Option<u64>,
+
+    /// Compress files that benefit from it with zstd before they are uploaded
+    #[arg(long)]
+    compress: bool,
+
+    /// zstd compression level, from 1 (fastest) to 22 (smallest) [default: 3]
+    #[arg(long)]
+    compression_level: Option<i32>,
+
+    /// Upload and download limit in bytes per second, shared by all workers
+    #[arg(long, global = true)]
+    bandwidth_limit: Option<u64>,
+
+    /// Time window with its own limit, HH:MM-HH:MM for full speed or
+    /// HH:MM-HH:MM=BYTES, can be repeated
+    #[arg(long, global = true)]
+    bandwidth_window: Vec<String>,
+
+    /// Gitignore-style pattern to exclude everywhere, can be repeated
+    #[arg(short, long, global = true)]
+    exclude: Vec<String>,
+
+    /// Delete files from the server when they become ignored
+    #[arg(long)]
+    remove_ignored: bool,
+
+    /// Follow symbolic links or store them as links, follow or store [default: follow]
+    #[arg(long, global = true)]
+    symlinks: Option<SymlinkPolicy>,
+
+    /// Encrypt file content and names with a key derived from the passphrase
+    #[arg(long)]
+    encrypt: bool,
+
+    /// File holding the encryption passphrase, MEMORA_PASSPHRASE takes precedence
+    #[arg(long, global = true)]
+    passphrase_file: Option<PathBuf>,
+
+    /// Lowest level of log records: error, warn, info, debug or trace [default: info]
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Format of the log lines, text or json [default: text]
+    #[arg(long, global = true)]
+    log_format: Option<LogFormat>,
+
+    /// Don't show the progress of transfers in an interactive terminal
+    #[arg(long, global = true)]
+    no_progress: bool,
+
+    /// Seconds between scheduled verifications against the server, see `verify`
+    #[arg(long)]
+    verify_interval: Option<u64>,
+
+    /// Unix socket of the local control API [default: <state_dir>/agent.sock]
+    #[arg(long, global = true)]
+    control_socket: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download files from the server into a local directory
+    Restore {
+        /// Directory the files are restored into
+        #[arg(long)]
+        to: PathBuf,
+
+        /// Only restore files under this server-side directory
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// Restore the files as the snapshot captured them, see `snapshots`
+        #[arg(long)]
+        snapshot: Option<Uuid>,
+    },
+    /// Scan the synced directories and record them as a snapshot on the server
+    Backup {
+        /// Name of the snapshot, retention applies per name [default: the hostname]
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Keep this many most recent snapshots
+        #[arg(long)]
+        keep_last: Option<usize>,
+
+        /// Keep the newest snapshot of this many days
+        #[arg(long)]
+        keep_daily: Option<usize>,
+
+        /// Keep the newest snapshot of this many weeks
+        #[arg(long)]
+        keep_weekly: Option<usize>,
+
+        /// Keep the newest snapshot of this many months
+        #[arg(long)]
+        keep_monthly: Option<usize>,
+
+        /// Keep running and back up every this many seconds
+        #[arg(long)]
+        every: Option<u64>,
+    },
+    /// List the snapshots recorded by backups
+    Snapshots {
+        /// Print the snapshots as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show what a sync would change on the server without changing anything
+    Plan {
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the synced files against the checksums stored on the server
+    Verify {
+        /// Also download the stored content and check it
+        #[arg(long)]
+        deep: bool,
+
+        /// Upload the local version of files with a problem again
+        #[arg(long)]
+        repair: bool,
+
+        /// File the report is written to [default: <state_dir>/verify-report.json]
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Rebuild the state database from the files on the server, e.g. after it was lost
+    Reindex,
+    /// Show what the running agent is doing
+    Status {
+        /// Print the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Stop the running agent from starting new scans and uploads
+    Pause,
+    /// Let the running agent continue after a pause
+    Resume,
+    /// Make the running agent scan its directory right away
+    Scan,
+    /// Register this machine as a named device and store its credential in the state directory
+    Login {
+        /// Name of the device [default: the hostname]
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Email of the account, the password is read from MEMORA_PASSWORD or
+        /// asked for. Without it the user's --token is used
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// List the devices registered to the account
+    Devices {
+        /// Print the devices as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Revoke a device, its credential stops working
+    Revoke {
+        /// Id of the device, see `devices`
+        id: Uuid,
+    },
+    /// Write a systemd unit that runs the agent with the settings of --config
+    InstallService {
+        /// Name of the unit
+        #[arg(long, default_value = "memora-agent")]
+        name: String,
+
+        /// Install for the user's service manager instead of the system's
+        #[arg(long)]
+        user: bool,
+
+        /// Write the unit to this file instead, - prints it
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+impl Args {
+    // into_config merges the arguments over the config file, if any
+    fn into_config(self) -> Result<(AgentConfig, Option<Command>), std::io::Error> {
+        let mut config = match &self.config {
+            Some(path) => AgentConfig::from_file(path)?,
+            None => AgentConfig::default(),
+        };
+
+        if let Some(dir) = self.dir {
+            config.dir = dir;
+        }
+        if !self.root.is_empty() {
+            config.roots = self.root;
+        }
+        if let Some(token) = self.token {
+            config.token = Some(token);
+        }
+        if let Some(server_url) = self.server_url {
+            config.server_url = server_url;
+        }
+        if let Some(state_dir) = self.state_dir {
+            config.state_dir = state_dir;
+        }
+        if let Some(scan_interval) = self.scan_interval {
+            config.scan_interval = scan_interval;
+        }
+        if let Some(rescan_interval) = self.rescan_interval {
+            config.rescan_interval = rescan_interval;
+        }
+        if let Some(workers) = self.workers {
+            config.workers = workers;
+        }
+        if self.watch {
+            config.watch = true;
+        }
+        if self.two_way {
+            config.two_way = true;
+        }
+        if let Some(multipart_threshold) = self.multipart_threshold {
+            config.multipart_threshold = multipart_threshold;
+        }
+        if let Some(multipart_part_size) = self.multipart_part_size {
+            config.multipart_part_size = multipart_part_size;
+        }
+        if self.no_chunking {
+            config.chunking = false;
+        }
+        if let Some(chunk_threshold) = self.chunk_threshold {
+            config.chunk_threshold = chunk_threshold;
+        }
+        if self.compress {
+            config.compress = true;
+        }
+        if let Some(compression_level) = self.compression_level {
+            config.compression_level = compression_level;
+        }
+        let bandwidth_override = BandwidthOverride {
+            limit: self.bandwidth_limit,
+            schedule: self.bandwidth_window,
+        };
+        bandwidth_override.apply(&mut config);
+        config.bandwidth_override = bandwidth_override;
+        config.exclude.extend(self.exclude);
+        if self.remove_ignored {
+            config.remove_ignored = true;
+        }
+        if let Some(symlinks) = self.symlinks {
+            config.symlinks = symlinks;
+        }
+        if self.encrypt {
+            config.encrypt = true;
+        }
+        if let Some(passphrase_file) = self.passphrase_file {
+            config.passphrase_file = Some(passphrase_file);
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        if self.no_progress {
+            config.progress = false;
+        }
+        if let Some(verify_interval) = self.verify_interval {
+            config.verify_interval = Some(verify_interval);
+        }
+        if let Some(control_socket) = self.control_socket {
+            config.control_socket = Some(control_socket);
+        }
+
+        // a token given explicitly takes precedence over the device credential
+        if config.token.is_none() {
+            if let Some(credential) = DeviceCredential::load(&config.state_dir)? {
+                config.token = Some(credential.token);
+            }
+        }
+
+        Ok((config, self.command))
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let (mut config, command) = match Args::parse().into_config() {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let console = match logging::init(&config.log_level, config.log_format, config.secrets()) {
+        Ok(console) => console,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    if !args.dir.is_dir() {
-        eprintln!("The specified directory does not exist: {:?}", args.dir);
+    // Commands for a running agent only talk to its control socket
+    let control_command = match &command {
+        Some(Command::Status { .. }) => Some(ControlCommand::Status),
+        Some(Command::Pause) => Some(ControlCommand::Pause),
+        Some(Command::Resume) => Some(ControlCommand::Resume),
+        Some(Command::Scan) => Some(ControlCommand::Scan),
+        _ => None,
+    };
+    if let Some(control_command) = control_command {
+        let json = matches!(command, Some(Command::Status { json: true }));
+        match control::request(&config.control_socket(), control_command).await {
+            Ok(status) if json => match serde_json::to_string_pretty(&status) {
+                Ok(status) => println!("{}", status),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            },
+            Ok(status) => print!("{}", status),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Plan { json }) = command {
+        check_roots(&config);
+
+        // A running agent may hold the state directory, the plan reads a copy
+        let plan = match StateCopy::open(&config) {
+            Ok(state) => state.plan().await,
+            Err(e) => Err(e),
+        };
+        let plan = match plan {
+            Ok(plan) => plan,
+            Err(e) => {
+                eprintln!("Plan failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if json {
+            match serde_json::to_string_pretty(&plan) {
+                Ok(plan) => println!("{}", plan),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            print!("{}", plan);
+        }
+        return;
+    }
+
+    if let Some(Command::Login { name, email }) = command {
+        if let Err(e) = login(&config, name, email).await {
+            eprintln!("Login failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::InstallService { name, user, output }) = command {
+        check_roots(&config);
+
+        if let Err(e) = install_service(&config, name, user, output) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if config.token.is_none() {
+        eprintln!(
+            "A token is required, run `agent login`, pass --token or set it in the config file"
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(Command::Devices { json }) = command {
+        if let Err(e) = list_devices(&config, json).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Revoke { id }) = command {
+        if let Err(e) = revoke_device(&config, id).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = config.validate() {
+        eprintln!("{}", e);
         std::process::exit(1);
     }
 
-    let agent = Agent::new(args.token, args.dir);
-    agent.run_scanner().await;
+    if let Some(Command::Restore {
+        to,
+        prefix,
+        snapshot,
+    }) = command
+    {
+        let agent = create_remote(&config, &console);
+        let result = match snapshot {
+            Some(snapshot_id) => {
+                agent
+                    .restore_snapshot(&to, prefix.as_deref(), snapshot_id)
+                    .await
+            }
+            None => {
+                agent
+                    .restore(&to, prefix.as_deref(), Version::Current)
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Restore failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Verify {
+        deep,
+        repair,
+        report,
+    }) = command
+    {
+        check_roots(&config);
+
+        let report_path = report.unwrap_or_else(|| config.verify_report());
+        let agent = create_agent(config, &console).await;
+        let report = match agent.verify(VerifyOptions { deep, repair }).await {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("Verification failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        print!("{}", report);
+        if let Err(e) = report.write(&report_path) {
+            eprintln!("Error writing {}: {}", report_path.to_string_lossy(), e);
+            std::process::exit(1);
+        }
+        println!("Report written to {}", report_path.to_string_lossy());
+
+        // problems that were not repaired fail the command, e.g. for cron jobs
+        if report.findings.iter().any(|finding| !finding.repaired) {
+            std::process::exit(2);
+        }
+        return;
+    }
+
+    if let Some(Command::Snapshots { json }) = command {
+        let agent = create_remote(&config, &console);
+        if let Err(e) = list_snapshots(&agent, json).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Backup {
+        name,
+        keep_last,
+        keep_daily,
+        keep_weekly,
+        keep_monthly,
+        every,
+    }) = command
+    {
+        check_roots(&config);
+
+        if name.is_some() {
+            config.backup_name = name;
+        }
+        config.keep_last = keep_last.unwrap_or(config.keep_last);
+        config.keep_daily = keep_daily.unwrap_or(config.keep_daily);
+        config.keep_weekly = keep_weekly.unwrap_or(config.keep_weekly);
+        config.keep_monthly = keep_monthly.unwrap_or(config.keep_monthly);
+
+        let name = match config.backup_name() {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let policy = config.retention();
+        let agent = create_agent(config, &console).await;
+        backup(&agent, &name, policy, every).await;
+        return;
+    }
+
+    if let Some(Command::Reindex) = command {
+        check_roots(&config);
+
+        let agent = create_agent(config, &console).await;
+        match agent.reindex().await {
+            Ok(report) => print!("{}", report),
+            Err(e) => {
+                eprintln!("Reindex failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    check_roots(&config);
+    run_service(config, &console).await;
+}
+
+// run_service runs the agent until SIGTERM or SIGINT, then waits for the uploads
+// in progress. SIGHUP reads the config file again and restarts the agent with
+// it, an invalid config keeps the running one. A second stop signal exits right away.
+async fn run_service(mut config: AgentConfig, console: &Console) {
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Error listening for signals: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    loop {
+        let watch = config.watch;
+        let agent = create_agent(config, console).await;
+
+        let next = {
+            let loops = async {
+                if watch {
+                    tokio::join!(
+                        agent.run_control(),
+                        agent.run_watcher(),
+                        agent.run_verifier(),
+                        agent.run_bandwidth_reload()
+                    );
+                } else {
+                    tokio::join!(
+                        agent.run_control(),
+                        agent.run_scanner(),
+                        agent.run_verifier(),
+                        agent.run_bandwidth_reload()
+                    );
+                }
+            };
+            tokio::pin!(loops);
+
+            tokio::select! {
+                _ = &mut loops => None,
+                next = next_config(&mut signals) => {
+                    agent.stop();
+                    tokio::select! {
+                        _ = loops => {}
+                        _ = force_stop(&mut signals) => {}
+                    }
+                    next
+                }
+            }
+        };
+
+        let closed = tokio::select! {
+            closed = agent.close() => closed,
+            never = force_stop(&mut signals) => never,
+        };
+        if let Err(e) = closed {
+            log::error!("Error writing the state database: {}", e);
+        }
+        drop(agent);
+
+        match next {
+            Some(next) => {
+                log::info!("Restarting with the reloaded config");
+                config = next;
+            }
+            None => break,
+        }
+    }
+
+    log::info!("Stopped");
+}
+
+// next_config waits for SIGHUP with a valid config to restart with, or for a
+// stop signal
+async fn next_config(signals: &mut Signals) -> Option<AgentConfig> {
+    loop {
+        match signals.recv().await {
+            Signal::Stop => return None,
+            Signal::Reload => match Args::parse().into_config() {
+                Ok((config, _)) => match config.validate().and_then(|_| config.roots()) {
+                    Ok(_) => return Some(config),
+                    Err(e) => log::error!("Keeping the current config: {}", e),
+                },
+                Err(e) => log::error!("Keeping the current config: {}", e),
+            },
+        }
+    }
+}
+
+// force_stop exits without waiting for the uploads on the next stop signal
+async fn force_stop(signals: &mut Signals) -> ! {
+    stop_requested(signals).await;
+    log::warn!("Stopping without waiting for the uploads in progress");
+    std::process::exit(1);
+}
+
+// stop_requested completes on SIGTERM or SIGINT
+async fn stop_requested(signals: &mut Signals) {
+    while signals.recv().await != Signal::Stop {
+        log::info!("Reloading is only supported by the sync service");
+    }
+}
+
+// check_roots exits unless the directories to sync are valid and exist
+fn check_roots(config: &AgentConfig) {
+    let roots = match config.roots() {
+        Ok(roots) => roots,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    for root in roots.iter() {
+        if !root.local.is_dir() {
+            eprintln!("The specified directory does not exist: {:?}", root.local);
+            std::process::exit(1);
+        }
+    }
+}
+
+// backup records a snapshot once and exits with the error, or keeps recording
+// one every `every` seconds and only logs errors
+async fn backup(agent: &Agent, name: &str, policy: RetentionPolicy, every: Option<u64>) {
+    let seconds = match every {
+        Some(seconds) => seconds,
+        None => match agent.backup(name, policy).await {
+            Ok(report) => {
+                print!("{}", report);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Backup failed: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Error listening for signals: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(seconds));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop_requested(&mut signals) => break,
+        }
+
+        // a stop signal ends the backup once the uploads in progress are done
+        let backup = agent.backup(name, policy);
+        tokio::pin!(backup);
+        let (result, stopping) = tokio::select! {
+            result = &mut backup => (result, false),
+            _ = stop_requested(&mut signals) => {
+                agent.stop();
+                (backup.await, true)
+            }
+        };
+
+        match result {
+            Ok(report) => log::info!("{}", report.to_string().lines().last().unwrap_or_default()),
+            Err(e) if stopping => log::warn!("Backup interrupted: {}", e),
+            Err(e) => log::error!("Backup failed: {}", e),
+        }
+        if stopping {
+            break;
+        }
+    }
+
+    if let Err(e) = agent.close().await {
+        log::error!("Error writing the state database: {}", e);
+    }
+}
+
+// install_service writes a systemd unit running this binary with the config
+// file. The service has no command line of its own, so the config has to hold
+// the settings and a token, or the state directory a device credential.
+fn install_service(
+    config: &AgentConfig,
+    name: String,
+    user: bool,
+    output: Option<PathBuf>,
+) -> Result<(), std::io::Error> {
+    let path = config.path.as_ref().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The service reads its settings from a config file, pass --config",
+        )
+    })?;
+
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.@:".contains(c))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid unit name {:?}", name),
+        ));
+    }
+
+    let settings = AgentConfig::from_file(path)?;
+    if settings.token.is_none() && DeviceCredential::load(&settings.state_dir)?.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The service has no token, run `agent login` or set token in the config file",
+        ));
+    }
+
+    let unit = ServiceUnit {
+        name,
+        user,
+        executable: std::env::current_exe()?,
+        config: std::fs::canonicalize(path)?,
+        working_directory: std::env::current_dir()?,
+        account: std::env::var("SUDO_USER")
+            .or_else(|_| std::env::var("USER"))
+            .ok(),
+    };
+
+    let target = match output {
+        Some(output) if output.as_os_str() == "-" => {
+            print!("{}", unit.render());
+            return Ok(());
+        }
+        Some(output) => output,
+        None => unit.path()?,
+    };
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&target, unit.render())?;
+
+    let systemctl = match user {
+        true => "systemctl --user",
+        false => "systemctl",
+    };
+    println!("Wrote {}, start the service with", target.to_string_lossy());
+    println!("  {} daemon-reload", systemctl);
+    println!("  {} enable --now {}", systemctl, unit.name);
+    Ok(())
+}
+
+// list_snapshots prints the snapshots, oldest first
+async fn list_snapshots(agent: &RemoteAgent, json: bool) -> Result<(), std::io::Error> {
+    let snapshots = agent.snapshots().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&snapshots)?);
+        return Ok(());
+    }
+
+    for snapshot in snapshots {
+        println!(
+            "{} {:<24} {} {}",
+            snapshot.id,
+            snapshot.name,
+            snapshot
+                .created_at
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M"),
+            match (snapshot.files, snapshot.size) {
+                (Some(files), Some(size)) => format!("{} files, {} bytes", files, size),
+                _ => "incomplete".to_string(),
+            }
+        );
+    }
+    Ok(())
+}
+
+// create_agent opens the agent or exits with the error, and shows the progress
+// of its transfers
+async fn create_agent(config: AgentConfig, console: &Console) -> Agent {
+    let show_progress = shows_progress(&config);
+
+    match Agent::new(config).await {
+        Ok(agent) => {
+            if show_progress {
+                console.show_progress(agent.progress());
+            }
+            agent
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// create_remote opens the server side of the agent or exits with the error,
+// it leaves the state directory to a running agent
+fn create_remote(config: &AgentConfig, console: &Console) -> RemoteAgent {
+    match RemoteAgent::new(config) {
+        Ok(agent) => {
+            if shows_progress(config) {
+                console.show_progress(agent.progress());
+            }
+            agent
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// shows_progress reports whether transfers are shown, when logging as text to
+// an interactive terminal
+fn shows_progress(config: &AgentConfig) -> bool {
+    config.progress && config.log_format == LogFormat::Text && logging::is_interactive()
+}
+
+// login registers the machine as a device with the user's login token, from
+// the email and password or --token, and stores the device credential
+async fn login(
+    config: &AgentConfig,
+    name: Option<String>,
+    email: Option<String>,
+) -> Result<(), std::io::Error> {
+    if let Some(credential) = DeviceCredential::load(&config.state_dir)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "This agent is already registered as device {} ({}), revoke it with `agent revoke {}` first",
+                credential.name, credential.id, credential.id
+            ),
+        ));
+    }
+
+    let client = DeviceClient::new(&config.server_url);
+    let token = match (email, &config.token) {
+        (Some(email), _) => {
+            let password = device::read_password(&format!("Password for {}: ", email))?;
+            client.login(&email, &password).await?
+        }
+        (None, Some(token)) => token.clone(),
+        (None, None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Pass --email or the user's --token to log in",
+            ))
+        }
+    };
+
+    let name = match name {
+        Some(name) => name,
+        None => hostname::get()?.to_string_lossy().to_string(),
+    };
+
+    let credential = client.register(&token, &name).await?;
+    credential.save(&config.state_dir)?;
+    println!(
+        "Registered as device {} ({}), the credential is stored in {}",
+        credential.name,
+        credential.id,
+        config.state_dir.to_string_lossy()
+    );
+    Ok(())
+}
+
+// list_devices prints the devices of the account, marking this one
+async fn list_devices(config: &AgentConfig, json: bool) -> Result<(), std::io::Error> {
+    let token = config.token.as_deref().unwrap_or_default();
+    let devices = DeviceClient::new(&config.server_url).list(token).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+
+    let current = DeviceCredential::load(&config.state_dir)?.map(|credential| credential.id);
+    for device in devices {
+        println!(
+            "{} {:<24} registered {}{}{}",
+            device.id,
+            device.name,
+            device.created_at.format("%Y-%m-%d %H:%M"),
+            match device.revoked_at {
+                Some(revoked_at) => format!(", revoked {}", revoked_at.format("%Y-%m-%d %H:%M")),
+                None => String::new(),
+            },
+            if current == Some(device.id) {
+                " (this device)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+// revoke_device revokes a device, the stored credential is removed when it is this one
+async fn revoke_device(config: &AgentConfig, id: Uuid) -> Result<(), std::io::Error> {
+    let token = config.token.as_deref().unwrap_or_default();
+    let device = DeviceClient::new(&config.server_url)
+        .revoke(token, id)
+        .await?;
+
+    let current = DeviceCredential::load(&config.state_dir)?;
+    if current.is_some_and(|credential| credential.id == device.id) {
+        DeviceCredential::remove(&config.state_dir)?;
+        println!(
+            "Revoked this device ({}), run `agent login` to register it again",
+            device.name
+        );
+    } else {
+        println!("Revoked device {} ({})", device.name, device.id);
+    }
+    Ok(())
 }
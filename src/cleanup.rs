@@ -0,0 +1,282 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use charybdis::operations::{Delete, Find, Update};
+use charybdis::types::Uuid;
+use scylla::CachingSession;
+
+use crate::api::chunk::chunk_path;
+use crate::api::file::object_path;
+use crate::client::Client;
+use crate::model::chunk::Chunk;
+use crate::model::file::File;
+use crate::model::snapshot::{Snapshot, SnapshotFile};
+use crate::model::user::User;
+use crate::schema::file::{FileStatus, FileType};
+
+// How often abandoned uploads are looked for
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How many days before the deadline open uploads are looked for, older ones were
+// handled by earlier runs
+const CLEANUP_LOOKBACK_DAYS: i64 = 7;
+
+// How often the chunk stores are swept for chunks nothing refers to
+const CHUNK_SWEEP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Periodically report and clean up files that stayed OPEN past the upload deadline.
+pub async fn run_stale_upload_cleanup(
+    database: Arc<CachingSession>,
+    client: Client,
+    deadline: chrono::Duration,
+) {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = cleanup_stale_uploads(&database, &client, deadline).await {
+            log::error!("Error cleaning up stale uploads: {}", err);
+        }
+    }
+}
+
+async fn cleanup_stale_uploads(
+    database: &CachingSession,
+    client: &Client,
+    deadline: chrono::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cutoff = chrono::Utc::now() - deadline;
+
+    // the view only has the files open for writing, by the day they were opened
+    let mut files: Vec<File> = Vec::new();
+    let mut day = (cutoff - chrono::Duration::days(CLEANUP_LOOKBACK_DAYS)).date_naive();
+    while day <= cutoff.date_naive() {
+        let opened: Vec<File> = File::find(
+            "SELECT * FROM files_by_opened_on WHERE opened_on = ?",
+            (day,),
+        )
+        .execute(database)
+        .await?
+        .try_collect()
+        .await?;
+        files.extend(opened);
+        day = day.succ_opt().ok_or("date out of range")?;
+    }
+
+    let stale: Vec<File> = files
+        .into_iter()
+        .filter(|file| file.status == FileStatus::OPEN.to_string())
+        .filter(|file| file.file_type == FileType::FILE.to_string())
+        .filter(|file| file.opened_at.is_some_and(|opened_at| opened_at < cutoff))
+        .collect();
+
+    if !stale.is_empty() {
+        log::warn!("Found {} uploads open since before {}", stale.len(), cutoff);
+    }
+
+    for file in stale {
+        if let Err(err) = cleanup_stale_upload(database, client, file).await {
+            log::error!("Error cleaning up stale upload: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+// cleanup_stale_upload drops an abandoned upload. A file goes back to the content
+// stored for it, a file that never had an upload completed is removed.
+async fn cleanup_stale_upload(
+    database: &CachingSession,
+    client: &Client,
+    mut file: File,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let object = object_path(&file);
+
+    let aborted = client.abort_multipart_uploads(&object).await?;
+    let head = client.head_object(&object).await?;
+
+    match head {
+        Some(head) => {
+            log::warn!(
+                "Stale upload of {} ({}), {} multipart uploads aborted, reverting to stored object",
+                file.id,
+                object,
+                aborted
+            );
+
+            // the object may hold the old or the new content, so the checksum is unknown
+            file.status = FileStatus::CLOSED.to_string();
+            file.checksum = None;
+            // the object of a chunked file is its manifest, not the content
+            if file.chunked != Some(true) {
+                file.size = Some(head.size);
+            }
+            file.etag = Some(head.etag);
+            file.opened_at = None;
+            file.opened_on = None;
+            file.updated_at = Some(chrono::Utc::now());
+            file.update().execute(database).await?;
+        }
+        None if file.etag.is_some() => {
+            // the completed upload is gone, keep the row so it can be uploaded again
+            log::error!(
+                "Stale upload of {} ({}), {} multipart uploads aborted, stored object is missing",
+                file.id,
+                object,
+                aborted
+            );
+        }
+        None => {
+            log::warn!(
+                "Stale upload of {} ({}), {} multipart uploads aborted, removing file",
+                file.id,
+                object,
+                aborted
+            );
+
+            file.delete().execute(database).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Periodically remove the chunks no manifest of a file or snapshot refers to.
+/// Chunks used within the grace period are kept, an upload in progress may
+/// refer to them once its manifest is committed.
+pub async fn run_chunk_sweep(
+    database: Arc<CachingSession>,
+    client: Client,
+    grace: chrono::Duration,
+) {
+    let mut interval = tokio::time::interval(CHUNK_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = sweep_chunks(&database, &client, grace).await {
+            log::error!("Error sweeping chunks: {}", err);
+        }
+    }
+}
+
+async fn sweep_chunks(
+    database: &CachingSession,
+    client: &Client,
+    grace: chrono::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let users: Vec<User> = User::find("SELECT * FROM users", ())
+        .execute(database)
+        .await?
+        .try_collect()
+        .await?;
+
+    for user in users {
+        if let Err(err) = sweep_user_chunks(database, client, user.id, grace).await {
+            log::error!("Error sweeping chunks of {}: {}", user.id, err);
+        }
+    }
+
+    Ok(())
+}
+
+// sweep_user_chunks removes the unreferenced chunks of the user's chunk store.
+// Nothing is removed unless every manifest could be read.
+async fn sweep_user_chunks(
+    database: &CachingSession,
+    client: &Client,
+    user_id: Uuid,
+    grace: chrono::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // chunks used after this may belong to a manifest committed during the sweep
+    let cutoff = chrono::Utc::now() - grace;
+
+    let chunks: Vec<Chunk> = Chunk::find("SELECT * FROM chunks WHERE user_id = ?", (user_id,))
+        .execute(database)
+        .await?
+        .try_collect()
+        .await?;
+    let candidates: Vec<Chunk> = chunks
+        .into_iter()
+        .filter(|chunk| chunk.used_at.unwrap_or(chunk.created_at) < cutoff)
+        .collect();
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let mut manifests = HashSet::new();
+    let files: Vec<File> = File::find("SELECT * FROM files WHERE user_id = ?", (user_id,))
+        .execute(database)
+        .await?
+        .try_collect()
+        .await?;
+    manifests.extend(
+        files
+            .iter()
+            .filter(|file| file.chunked == Some(true))
+            .map(object_path),
+    );
+
+    let snapshots: Vec<Snapshot> =
+        Snapshot::find("SELECT * FROM snapshots WHERE user_id = ?", (user_id,))
+            .execute(database)
+            .await?
+            .try_collect()
+            .await?;
+    for snapshot in snapshots {
+        let files: Vec<SnapshotFile> = SnapshotFile::find(
+            "SELECT * FROM snapshot_files WHERE user_id = ? AND snapshot_id = ?",
+            (user_id, snapshot.id),
+        )
+        .execute(database)
+        .await?
+        .try_collect()
+        .await?;
+        manifests.extend(
+            files
+                .into_iter()
+                .filter(|file| file.chunked == Some(true))
+                .filter_map(|file| file.object),
+        );
+    }
+
+    let mut referenced = HashSet::new();
+    for manifest in manifests {
+        let manifest = client.get_manifest(&manifest).await?;
+        referenced.extend(manifest.chunks.into_iter().map(|chunk| chunk.hash));
+    }
+
+    let mut removed = 0;
+    for chunk in candidates
+        .into_iter()
+        .filter(|chunk| !referenced.contains(&chunk.hash))
+    {
+        chunk.delete().execute(database).await?;
+
+        // a client that found the object in the meantime recorded the chunk again
+        let recorded: Vec<Chunk> = Chunk::find(
+            "SELECT * FROM chunks WHERE user_id = ? AND hash = ?",
+            (user_id, chunk.hash.clone()),
+        )
+        .execute(database)
+        .await?
+        .try_collect()
+        .await?;
+        if !recorded.is_empty() {
+            continue;
+        }
+
+        client
+            .delete_object(&chunk_path(user_id, &chunk.hash))
+            .await?;
+        removed += 1;
+    }
+
+    if removed > 0 {
+        log::info!("Removed {} unreferenced chunks of {}", removed, user_id);
+    }
+
+    Ok(())
+}
@@ -1,7 +1,26 @@
 use std::env;
 
 use aws_config::SdkConfig as AwsConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::{presigning::PresigningConfig, Client as S3Client};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::schema::chunk::Manifest;
+
+/// Characters escaped in an object key used as a copy source; `/` is kept.
+const OBJECT_KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Largest object S3 copies in a single request, 5 GiB.
+const MAX_COPY_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+
+/// Size of the ranges larger objects are copied in.
+const COPY_PART_SIZE: i64 = 1024 * 1024 * 1024;
 
 /// S3 client wrapper to expose semantic upload operations.
 #[derive(Debug, Clone)]
@@ -68,6 +87,283 @@ impl Client {
         Ok(presigned_request.uri().into())
     }
 
+    pub async fn create_multipart_upload(&self, object: &str) -> Result<String, S3ExampleError> {
+        let output = self
+            .s3
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .send()
+            .await?;
+
+        output.upload_id().map(String::from).ok_or_else(|| {
+            S3ExampleError::new("Missing upload id in CreateMultipartUpload response")
+        })
+    }
+
+    pub async fn get_upload_part_presigned_url(
+        &self,
+        object: &str,
+        upload_id: &str,
+        part_number: i32,
+        expires_in: u64,
+    ) -> Result<String, S3ExampleError> {
+        let expires_in: std::time::Duration = std::time::Duration::from_secs(expires_in);
+        let expires_in: aws_sdk_s3::presigning::PresigningConfig =
+            PresigningConfig::expires_in(expires_in).map_err(|err| {
+                S3ExampleError::new(format!(
+                    "Failed to convert expiration to PresigningConfig: {err:?}"
+                ))
+            })?;
+        let presigned_request = self
+            .s3
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .presigned(expires_in)
+            .await?;
+
+        Ok(presigned_request.uri().into())
+    }
+
+    /// Complete a multipart upload from `(part_number, etag)` pairs.
+    pub async fn complete_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+        parts: Vec<(i32, String)>,
+    ) -> Result<(), S3ExampleError> {
+        let parts = parts
+            .into_iter()
+            .map(|(part_number, etag)| {
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .e_tag(etag)
+                    .build()
+            })
+            .collect();
+
+        self.s3
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn abort_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<(), S3ExampleError> {
+        self.s3
+            .abort_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .upload_id(upload_id)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Abort every unfinished multipart upload of the object, returns how many were aborted.
+    pub async fn abort_multipart_uploads(&self, object: &str) -> Result<usize, S3ExampleError> {
+        let output = self
+            .s3
+            .list_multipart_uploads()
+            .bucket(&self.bucket_name)
+            .prefix(object)
+            .send()
+            .await?;
+
+        let mut aborted = 0;
+        // the listing matches by prefix, other objects may start with the same key
+        for upload in output.uploads() {
+            if let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) {
+                if key == object {
+                    self.abort_multipart_upload(object, upload_id).await?;
+                    aborted += 1;
+                }
+            }
+        }
+
+        Ok(aborted)
+    }
+
+    /// Look up the size and ETag of an object, `None` if it does not exist.
+    pub async fn head_object(&self, object: &str) -> Result<Option<ObjectHead>, S3ExampleError> {
+        let result = self
+            .s3
+            .head_object()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .send()
+            .await;
+
+        match result {
+            Ok(output) => Ok(Some(ObjectHead {
+                size: output.content_length().unwrap_or_default(),
+                etag: output.e_tag().unwrap_or_default().to_string(),
+            })),
+            Err(err) if err.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Copy an object, returns the ETag of the copy. Objects larger than a
+    /// single copy allows are copied in parts.
+    pub async fn copy_object(
+        &self,
+        source: &str,
+        destination: &str,
+    ) -> Result<String, S3ExampleError> {
+        let copy_source = format!(
+            "{}/{}",
+            self.bucket_name,
+            utf8_percent_encode(source, OBJECT_KEY_ENCODE_SET)
+        );
+
+        let size = match self.head_object(source).await? {
+            Some(head) => head.size,
+            None => return Err(S3ExampleError::new(format!("{source} not found"))),
+        };
+        if size > MAX_COPY_SIZE {
+            return self
+                .copy_object_in_parts(&copy_source, destination, size)
+                .await;
+        }
+
+        let output = self
+            .s3
+            .copy_object()
+            .bucket(&self.bucket_name)
+            .copy_source(copy_source)
+            .key(destination)
+            .send()
+            .await?;
+
+        Ok(output
+            .copy_object_result()
+            .and_then(|result| result.e_tag())
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    // copy_object_in_parts copies an object through a multipart upload of
+    // ranges of the source, the upload is aborted if a part fails
+    async fn copy_object_in_parts(
+        &self,
+        copy_source: &str,
+        destination: &str,
+        size: i64,
+    ) -> Result<String, S3ExampleError> {
+        let upload_id = self.create_multipart_upload(destination).await?;
+
+        let mut parts = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            let part_number = parts.len() as i32 + 1;
+
+            let result = self
+                .s3
+                .upload_part_copy()
+                .bucket(&self.bucket_name)
+                .key(destination)
+                .upload_id(&upload_id)
+                .part_number(part_number)
+                .copy_source(copy_source)
+                .copy_source_range(format!("bytes={start}-{end}"))
+                .send()
+                .await;
+            let output = match result {
+                Ok(output) => output,
+                Err(err) => {
+                    self.abort_multipart_upload(destination, &upload_id).await?;
+                    return Err(err.into());
+                }
+            };
+
+            let etag = output
+                .copy_part_result()
+                .and_then(|result| result.e_tag())
+                .unwrap_or_default()
+                .to_string();
+            parts.push((part_number, etag));
+            start = end + 1;
+        }
+
+        if let Err(err) = self
+            .complete_multipart_upload(destination, &upload_id, parts)
+            .await
+        {
+            self.abort_multipart_upload(destination, &upload_id).await?;
+            return Err(err);
+        }
+
+        self.head_object(destination)
+            .await?
+            .map(|head| head.etag)
+            .ok_or_else(|| S3ExampleError::new(format!("{destination} not found after copy")))
+    }
+
+    /// Store the manifest of a chunked file as its object, returns the stored object.
+    pub async fn put_manifest(
+        &self,
+        object: &str,
+        manifest: &Manifest,
+    ) -> Result<ObjectHead, S3ExampleError> {
+        let body = serde_json::to_vec(manifest)
+            .map_err(|err| S3ExampleError::new(format!("Failed to encode manifest: {err}")))?;
+        let size = body.len() as i64;
+
+        let output = self
+            .s3
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .content_type("application/json")
+            .body(ByteStream::from(body))
+            .send()
+            .await?;
+
+        Ok(ObjectHead {
+            size,
+            etag: output.e_tag().unwrap_or_default().to_string(),
+        })
+    }
+
+    /// Read the manifest of a chunked file.
+    pub async fn get_manifest(&self, object: &str) -> Result<Manifest, S3ExampleError> {
+        let output = self
+            .s3
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(object)
+            .send()
+            .await?;
+
+        let body = output
+            .body
+            .collect()
+            .await
+            .map_err(|err| S3ExampleError::new(format!("Failed to read manifest: {err}")))?;
+
+        serde_json::from_slice(&body.into_bytes())
+            .map_err(|err| S3ExampleError::new(format!("Invalid manifest {object}: {err}")))
+    }
+
     pub async fn delete_object(&self, object: &str) -> Result<(), S3ExampleError> {
         self.s3
             .delete_object()
@@ -80,6 +376,13 @@ impl Client {
     }
 }
 
+/// Size and ETag of a stored object as reported by HeadObject.
+#[derive(Debug, Clone)]
+pub struct ObjectHead {
+    pub size: i64,
+    pub etag: String,
+}
+
 /// S3ExampleError provides a From<T: ProvideErrorMetadata> impl to extract
 /// client-specific error details. This serves as a consistent backup to handling
 /// specific service errors, depending on what is needed by the scenario.
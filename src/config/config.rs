@@ -10,6 +10,12 @@ pub struct App {
     pub jwt_secret: String,
     pub jwt_expires_in: String,
     pub jwt_maxage: i32,
+
+    // hours a file may stay OPEN before its upload is considered abandoned
+    pub upload_deadline_hours: i64,
+
+    // days a device credential is valid for unless the device is revoked first
+    pub device_token_maxage_days: i64,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -42,6 +48,14 @@ impl Config {
                 jwt_secret,
                 jwt_expires_in,
                 jwt_maxage: jwt_maxage.parse::<i32>().unwrap(),
+                upload_deadline_hours: dotenvy::var("UPLOAD_DEADLINE_HOURS")
+                    .ok()
+                    .and_then(|hours| hours.parse::<i64>().ok())
+                    .unwrap_or(24),
+                device_token_maxage_days: dotenvy::var("DEVICE_TOKEN_MAXAGE_DAYS")
+                    .ok()
+                    .and_then(|days| days.parse::<i64>().ok())
+                    .unwrap_or(3650),
             },
             database: Database {
                 nodes: dotenvy::var("SCYLLA_NODES")
@@ -30,6 +30,20 @@ pub enum ErrorMessage {
     UserNoLongerExist,
     TokenNotProvided,
     FileNotFound,
+    NotAFile,
+    InvalidPartNumber,
+    UploadNotCompleted,
+    UploadNotFound,
+    UploadSizeMismatch,
+    UploadUnchanged,
+    InvalidChunkHash,
+    ChunksMissing,
+    NotChunked,
+    DeviceRevoked,
+    DeviceNotFound,
+    UserTokenRequired,
+    SnapshotNotFound,
+    SnapshotClosed,
 }
 
 impl ToString for ErrorMessage {
@@ -58,6 +72,36 @@ impl ErrorMessage {
                 "You are not logged in, please provide token".to_string()
             }
             ErrorMessage::FileNotFound => "File not found".to_string(),
+            ErrorMessage::NotAFile => "Content can only be uploaded to a file".to_string(),
+            ErrorMessage::InvalidPartNumber => {
+                "Part numbers must be between 1 and 10000".to_string()
+            }
+            ErrorMessage::UploadNotCompleted => {
+                "Uploads must be finished through the complete upload endpoint".to_string()
+            }
+            ErrorMessage::UploadNotFound => "The uploaded content was not found".to_string(),
+            ErrorMessage::UploadSizeMismatch => {
+                "The uploaded content does not have the expected size".to_string()
+            }
+            ErrorMessage::UploadUnchanged => {
+                "The stored content is still the one from before the file was re-opened".to_string()
+            }
+            ErrorMessage::InvalidChunkHash => {
+                "Chunk hashes must be hex encoded SHA-256 digests".to_string()
+            }
+            ErrorMessage::ChunksMissing => {
+                "Some chunks of the manifest were not uploaded".to_string()
+            }
+            ErrorMessage::NotChunked => "The file content is not stored in chunks".to_string(),
+            ErrorMessage::DeviceRevoked => "The device of this token was revoked".to_string(),
+            ErrorMessage::DeviceNotFound => "Device not found".to_string(),
+            ErrorMessage::SnapshotNotFound => "Snapshot not found".to_string(),
+            ErrorMessage::SnapshotClosed => {
+                "Files can only be added to a snapshot that is not complete".to_string()
+            }
+            ErrorMessage::UserTokenRequired => {
+                "This action requires a user's login token, not a device token".to_string()
+            }
         }
     }
 }
@@ -97,6 +141,20 @@ impl HttpError {
         }
     }
 
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        HttpError {
+            message: message.into(),
+            status: 401,
+        }
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        HttpError {
+            message: message.into(),
+            status: 403,
+        }
+    }
+
     pub fn into_http_response(self) -> HttpResponse {
         match self.status {
             400 => HttpResponse::BadRequest().json(Response {
@@ -107,6 +165,10 @@ impl HttpError {
                 status: "fail",
                 message: self.message.into(),
             }),
+            403 => HttpResponse::Forbidden().json(Response {
+                status: "fail",
+                message: self.message.into(),
+            }),
             404 => HttpResponse::NotFound().json(Response {
                 status: "fail",
                 message: self.message.into(),
@@ -1,7 +1,15 @@
 use actix_web::web;
 
+use crate::api::chunk::find_missing_chunks;
+use crate::api::device::{get_devices, register_device, revoke_device};
 use crate::api::file::{
-    create_file, delete_file, get_file, get_files, get_files_by_directory, update_file,
+    abort_multipart_upload, commit_manifest, complete_multipart_upload, complete_upload,
+    create_file, create_multipart_upload, delete_file, get_file, get_file_manifest, get_files,
+    get_files_by_directory, presign_multipart_parts, update_file,
+};
+use crate::api::snapshot::{
+    add_snapshot_files, complete_snapshot, create_snapshot, delete_snapshot, get_snapshot_file,
+    get_snapshot_files, get_snapshot_manifest, get_snapshots,
 };
 use crate::api::user::{auth_login, create_user, delete_user, get_user_me, update_user_me};
 
@@ -9,10 +17,29 @@ pub fn config(conf: &mut web::ServiceConfig) {
     let scope = web::scope("/v1")
         .service(get_file)
         .service(get_files)
+        .service(get_file_manifest)
         .service(get_files_by_directory)
         .service(create_file)
         .service(update_file)
         .service(delete_file)
+        .service(complete_upload)
+        .service(create_multipart_upload)
+        .service(presign_multipart_parts)
+        .service(complete_multipart_upload)
+        .service(abort_multipart_upload)
+        .service(commit_manifest)
+        .service(find_missing_chunks)
+        .service(register_device)
+        .service(get_devices)
+        .service(revoke_device)
+        .service(create_snapshot)
+        .service(get_snapshots)
+        .service(add_snapshot_files)
+        .service(complete_snapshot)
+        .service(get_snapshot_files)
+        .service(get_snapshot_manifest)
+        .service(get_snapshot_file)
+        .service(delete_snapshot)
         .service(auth_login)
         .service(create_user)
         .service(update_user_me)
@@ -4,11 +4,13 @@ use std::future::{ready, Ready};
 use actix_web::error::ErrorUnauthorized;
 use actix_web::{dev::Payload, Error as ActixWebError};
 use actix_web::{http, web, FromRequest, HttpMessage, HttpRequest};
+use charybdis::operations::Find;
 use jsonwebtoken::{decode, DecodingKey, Validation};
 use scylla::CachingSession;
 use serde::Serialize;
 
 use crate::error::{ErrorMessage, HttpError};
+use crate::model::device::Device;
 use crate::model::user::User;
 use crate::schema::user::TokenClaims;
 use crate::AppState;
@@ -27,6 +29,8 @@ impl fmt::Display for ErrorResponse {
 
 pub struct JwtMiddleware {
     pub user_id: uuid::Uuid,
+    // device a device credential was issued to, None for a user's login token
+    pub device_id: Option<uuid::Uuid>,
 }
 
 impl FromRequest for JwtMiddleware {
@@ -64,10 +68,21 @@ impl FromRequest for JwtMiddleware {
         };
 
         let user_id = uuid::Uuid::parse_str(claims.sub.as_str()).unwrap();
+        let device_id = match claims.device.as_deref().map(uuid::Uuid::parse_str) {
+            Some(Ok(device_id)) => Some(device_id),
+            Some(Err(_)) => {
+                let json_error = ErrorResponse {
+                    status: "fail".to_string(),
+                    message: ErrorMessage::InvalidToken.to_string(),
+                };
+                return ready(Err(ErrorUnauthorized(json_error)));
+            }
+            None => None,
+        };
         req.extensions_mut()
             .insert::<uuid::Uuid>(user_id.to_owned());
 
-        ready(Ok(JwtMiddleware { user_id }))
+        ready(Ok(JwtMiddleware { user_id, device_id }))
     }
 }
 
@@ -78,6 +93,32 @@ impl JwtMiddleware {
             .await
             .map_err(|_| HttpError::not_found(ErrorMessage::UserNoLongerExist))?;
 
+        // a device credential only works while its device is registered
+        if let Some(device_id) = self.device_id {
+            let device = Device {
+                user_id: self.user_id,
+                id: device_id,
+                ..Default::default()
+            }
+            .find_by_primary_key()
+            .execute(session)
+            .await
+            .map_err(|_| HttpError::unauthorized(ErrorMessage::DeviceRevoked))?;
+
+            if device.revoked_at.is_some() {
+                return Err(HttpError::unauthorized(ErrorMessage::DeviceRevoked));
+            }
+        }
+
         Ok(user)
     }
+
+    // require_user rejects device credentials, managing the account and its
+    // devices needs the user's own login token
+    pub fn require_user(&self) -> Result<(), HttpError> {
+        match self.device_id {
+            Some(_) => Err(HttpError::forbidden(ErrorMessage::UserTokenRequired)),
+            None => Ok(()),
+        }
+    }
 }
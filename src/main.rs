@@ -1,5 +1,6 @@
 mod agent;
 mod api;
+mod cleanup;
 mod client;
 mod error;
 mod handler;
@@ -10,7 +11,11 @@ mod utils;
 
 use std::{env, fs};
 
-use actix_web::{middleware::Logger, web::Data, App, HttpServer};
+use actix_web::{
+    middleware::Logger,
+    web::{Data, JsonConfig},
+    App, HttpServer,
+};
 use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
 
 use crate::client::Client;
@@ -18,6 +23,9 @@ use crate::config::app::AppState;
 
 mod config;
 
+// Largest JSON request body accepted, in bytes
+const JSON_LIMIT: usize = 16 * 1024 * 1024;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let app_data: AppState = AppState::new().await;
@@ -48,6 +56,17 @@ async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
+    actix_web::rt::spawn(cleanup::run_stale_upload_cleanup(
+        app_data.database.clone(),
+        client.clone(),
+        chrono::Duration::hours(app_data.config.app.upload_deadline_hours),
+    ));
+    actix_web::rt::spawn(cleanup::run_chunk_sweep(
+        app_data.database.clone(),
+        client.clone(),
+        chrono::Duration::hours(app_data.config.app.upload_deadline_hours),
+    ));
+
     HttpServer::new(move || {
         let logger = Logger::default();
 
@@ -57,6 +76,8 @@ async fn main() -> std::io::Result<()> {
                 database: app_data.database.clone(),
             }))
             .app_data(Data::new(client.clone()))
+            // manifests of large files list thousands of chunks
+            .app_data(JsonConfig::default().limit(JSON_LIMIT))
             .configure(handler::config)
             .wrap(logger)
     })
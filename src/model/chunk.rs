@@ -0,0 +1,21 @@
+use charybdis::macros::charybdis_model;
+use charybdis::types::{BigInt, Text, Timestamp, Uuid};
+use serde::{Deserialize, Serialize};
+
+#[charybdis_model(
+    table_name = chunks,
+    partition_keys = [user_id],
+    clustering_keys = [hash],
+    global_secondary_indexes = [],
+    local_secondary_indexes = [],
+    table_options = "",
+)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Chunk {
+    pub user_id: Uuid,
+    pub hash: Text,
+    pub size: BigInt,
+    pub created_at: Timestamp,
+    // last time a client was told the store has the chunk
+    pub used_at: Option<Timestamp>,
+}
@@ -0,0 +1,36 @@
+use charybdis::macros::charybdis_model;
+use charybdis::types::{Text, Timestamp, Uuid};
+use serde::{Deserialize, Serialize};
+
+use crate::schema::device::DeviceCreateRequest;
+use crate::utils::node::generate_uuid_v1;
+
+#[charybdis_model(
+    table_name = devices,
+    partition_keys = [user_id],
+    clustering_keys = [id],
+    global_secondary_indexes = [],
+    local_secondary_indexes = [],
+    table_options = "",
+)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Device {
+    pub user_id: Uuid,
+    pub id: Uuid,
+    pub name: Text,
+    pub created_at: Timestamp,
+    // credentials issued to a revoked device are rejected
+    pub revoked_at: Option<Timestamp>,
+}
+
+impl Device {
+    pub fn from_request(user_id: Uuid, payload: &DeviceCreateRequest) -> Self {
+        Device {
+            user_id,
+            id: generate_uuid_v1().unwrap(),
+            name: payload.name.to_string(),
+            created_at: chrono::Utc::now(),
+            revoked_at: None,
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use charybdis::macros::charybdis_model;
 use charybdis::macros::charybdis_view_model;
-use charybdis::types::{Text, Timestamp, Uuid};
+use charybdis::types::{BigInt, Boolean, Date, Text, Timestamp, Uuid};
 use serde::{Deserialize, Serialize};
 
-use crate::schema::file::FileCreateRequest;
+use crate::schema::file::{FileCreateRequest, FileStatus};
 use crate::utils::node::generate_uuid_v1;
 
 #[charybdis_model(
@@ -24,10 +24,32 @@ pub struct File {
     pub status: Text,
     pub created_at: Timestamp,
     pub modified_at: Timestamp,
+    pub checksum: Option<Text>,
+    pub size: Option<BigInt>,
+    pub etag: Option<Text>,
+    pub opened_at: Option<Timestamp>,
+    // the day of `opened_at`, open files are looked up by the day they were opened
+    pub opened_on: Option<Date>,
+    pub key_id: Option<Text>,
+    pub chunked: Option<Boolean>,
+    pub codec: Option<Text>,
+    pub metadata: Option<Text>,
+    pub device_id: Option<Uuid>,
+    // set by the server on every change, clients pull the rows changed since they last looked
+    pub updated_at: Option<Timestamp>,
 }
 
 impl File {
-    pub fn from_request(user_id: Uuid, payload: &FileCreateRequest) -> Self {
+    pub fn from_request(
+        user_id: Uuid,
+        device_id: Option<Uuid>,
+        payload: &FileCreateRequest,
+    ) -> Self {
+        let opened_at = match payload.status {
+            FileStatus::OPEN => Some(chrono::Utc::now()),
+            FileStatus::CLOSED => None,
+        };
+
         File {
             user_id: user_id,
             id: generate_uuid_v1().unwrap(),
@@ -37,11 +59,22 @@ impl File {
             status: payload.status.to_string(),
             created_at: chrono::Utc::now(),
             modified_at: chrono::Utc::now(),
+            opened_at,
+            opened_on: opened_on(opened_at),
+            key_id: payload.key_id.clone(),
+            metadata: payload.metadata.clone(),
+            device_id,
+            updated_at: Some(chrono::Utc::now()),
             ..Default::default()
         }
     }
 }
 
+// opened_on returns the day an upload opened at the given time is looked up by
+pub fn opened_on(opened_at: Option<Timestamp>) -> Option<Date> {
+    opened_at.map(|opened_at| opened_at.date_naive())
+}
+
 #[charybdis_view_model(
     table_name=files_by_directory,
     base_table=files,
@@ -60,4 +93,78 @@ pub struct FileByDirectory {
     pub status: Text,
     pub created_at: Timestamp,
     pub modified_at: Timestamp,
+    pub checksum: Option<Text>,
+    pub size: Option<BigInt>,
+    pub etag: Option<Text>,
+    pub opened_at: Option<Timestamp>,
+    pub opened_on: Option<Date>,
+    pub key_id: Option<Text>,
+    pub chunked: Option<Boolean>,
+    pub codec: Option<Text>,
+    pub metadata: Option<Text>,
+    pub device_id: Option<Uuid>,
+    pub updated_at: Option<Timestamp>,
+}
+
+// files open for writing by the day they were opened, a file leaves the view
+// once it is closed
+#[charybdis_view_model(
+    table_name=files_by_opened_on,
+    base_table=files,
+    partition_keys=[opened_on],
+    clustering_keys=[user_id, id],
+    table_options = ""
+)]
+pub struct FileByOpenedOn {
+    pub user_id: Uuid,
+    pub id: Uuid,
+    pub name: Text,
+    pub directory: Text,
+    pub file_type: Text,
+    pub status: Text,
+    pub created_at: Timestamp,
+    pub modified_at: Timestamp,
+    pub checksum: Option<Text>,
+    pub size: Option<BigInt>,
+    pub etag: Option<Text>,
+    pub opened_at: Option<Timestamp>,
+    pub opened_on: Date,
+    pub key_id: Option<Text>,
+    pub chunked: Option<Boolean>,
+    pub codec: Option<Text>,
+    pub metadata: Option<Text>,
+    pub device_id: Option<Uuid>,
+    pub updated_at: Option<Timestamp>,
+}
+
+// files by the time they last changed, for pulling the changes since a point in time
+#[charybdis_view_model(
+    table_name=files_by_updated_at,
+    base_table=files,
+    partition_keys=[user_id],
+    clustering_keys=[updated_at, id],
+    table_options = r#"
+        CLUSTERING ORDER BY (updated_at Asc, id Asc)
+    "#
+)]
+pub struct FileByUpdatedAt {
+    pub user_id: Uuid,
+    pub id: Uuid,
+    pub name: Text,
+    pub directory: Text,
+    pub file_type: Text,
+    pub status: Text,
+    pub created_at: Timestamp,
+    pub modified_at: Timestamp,
+    pub checksum: Option<Text>,
+    pub size: Option<BigInt>,
+    pub etag: Option<Text>,
+    pub opened_at: Option<Timestamp>,
+    pub opened_on: Option<Date>,
+    pub key_id: Option<Text>,
+    pub chunked: Option<Boolean>,
+    pub codec: Option<Text>,
+    pub metadata: Option<Text>,
+    pub device_id: Option<Uuid>,
+    pub updated_at: Timestamp,
 }
@@ -0,0 +1,137 @@
+use charybdis::macros::charybdis_model;
+use charybdis::types::{BigInt, Boolean, Text, Timestamp, Uuid};
+use serde::{Deserialize, Serialize};
+
+use crate::model::file::File;
+use crate::schema::file::FileStatus;
+use crate::schema::snapshot::SnapshotCreateRequest;
+use crate::utils::node::generate_uuid_v1;
+
+#[charybdis_model(
+    table_name = snapshots,
+    partition_keys = [user_id],
+    clustering_keys = [id],
+    global_secondary_indexes = [],
+    local_secondary_indexes = [],
+    table_options = "",
+)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Snapshot {
+    pub user_id: Uuid,
+    pub id: Uuid,
+    pub name: Text,
+    // OPEN while files are added, CLOSED once complete
+    pub status: Text,
+    pub created_at: Timestamp,
+    pub completed_at: Option<Timestamp>,
+    pub files: Option<BigInt>,
+    pub size: Option<BigInt>,
+    pub device_id: Option<Uuid>,
+}
+
+impl Snapshot {
+    pub fn from_request(
+        user_id: Uuid,
+        device_id: Option<Uuid>,
+        payload: &SnapshotCreateRequest,
+    ) -> Self {
+        Snapshot {
+            user_id,
+            id: generate_uuid_v1().unwrap(),
+            name: payload.name.to_string(),
+            status: FileStatus::OPEN.to_string(),
+            created_at: chrono::Utc::now(),
+            device_id,
+            ..Default::default()
+        }
+    }
+}
+
+// A file as a snapshot captured it, the content is kept in its own object
+#[charybdis_model(
+    table_name = snapshot_files,
+    partition_keys = [user_id, snapshot_id],
+    clustering_keys = [file_id],
+    global_secondary_indexes = [],
+    local_secondary_indexes = [],
+    table_options = "",
+)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct SnapshotFile {
+    pub user_id: Uuid,
+    pub snapshot_id: Uuid,
+    pub file_id: Uuid,
+    pub name: Text,
+    pub directory: Text,
+    pub file_type: Text,
+    pub created_at: Timestamp,
+    pub modified_at: Timestamp,
+    pub checksum: Option<Text>,
+    pub size: Option<BigInt>,
+    pub key_id: Option<Text>,
+    pub chunked: Option<Boolean>,
+    pub codec: Option<Text>,
+    pub metadata: Option<Text>,
+    // S3 key of the captured content, or of the manifest of a chunked file
+    pub object: Option<Text>,
+}
+
+impl SnapshotFile {
+    pub fn capture(snapshot_id: Uuid, file: &File, object: Option<String>) -> Self {
+        SnapshotFile {
+            user_id: file.user_id,
+            snapshot_id,
+            file_id: file.id,
+            name: file.name.clone(),
+            directory: file.directory.clone(),
+            file_type: file.file_type.clone(),
+            created_at: file.created_at,
+            modified_at: file.modified_at,
+            checksum: file.checksum.clone(),
+            size: file.size,
+            key_id: file.key_id.clone(),
+            chunked: file.chunked,
+            codec: file.codec.clone(),
+            metadata: file.metadata.clone(),
+            object,
+        }
+    }
+}
+
+impl From<SnapshotFile> for File {
+    fn from(file: SnapshotFile) -> Self {
+        File {
+            user_id: file.user_id,
+            id: file.file_id,
+            name: file.name,
+            directory: file.directory,
+            file_type: file.file_type,
+            status: FileStatus::CLOSED.to_string(),
+            created_at: file.created_at,
+            modified_at: file.modified_at,
+            checksum: file.checksum,
+            size: file.size,
+            key_id: file.key_id,
+            chunked: file.chunked,
+            codec: file.codec,
+            metadata: file.metadata,
+            ..Default::default()
+        }
+    }
+}
+
+// Snapshots referring to a captured object, it is deleted with the last of them
+#[charybdis_model(
+    table_name = snapshot_objects,
+    partition_keys = [user_id, object],
+    clustering_keys = [snapshot_id],
+    global_secondary_indexes = [],
+    local_secondary_indexes = [],
+    table_options = "",
+)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct SnapshotObject {
+    pub user_id: Uuid,
+    pub object: Text,
+    pub snapshot_id: Uuid,
+}
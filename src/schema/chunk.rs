@@ -0,0 +1,55 @@
+use charybdis::types::{Text, Timestamp};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/// A content-defined chunk of a file, identified by the hex encoded SHA-256 of its content.
+#[derive(Serialize, Deserialize, Debug, Validate, Clone, PartialEq)]
+pub struct ChunkRef {
+    pub hash: Text,
+    #[validate(range(min = 0))]
+    pub size: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct ChunksRequest {
+    #[validate(length(min = 1, max = 100), nested)]
+    pub chunks: Vec<ChunkRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PresignedChunk {
+    pub hash: Text,
+    pub size: i64,
+    pub url: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunksResponse {
+    // chunks the store does not have, with URLs to upload them to
+    pub missing: Vec<PresignedChunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct ManifestRequest {
+    pub modified_at: Timestamp,
+    // hex encoded SHA-256 of the whole content
+    pub checksum: Option<Text>,
+    // the chunks making up the content, in order
+    #[validate(length(max = 100000), nested)]
+    pub chunks: Vec<ChunkRef>,
+    // compression applied to every chunk, sizes and hashes are of the compressed chunks
+    #[serde(default)]
+    pub codec: Option<Text>,
+}
+
+/// Manifest stored as the object of a chunked file.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub chunks: Vec<ChunkRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestResponse {
+    // the chunks of the content in order, with URLs to download them from
+    pub chunks: Vec<PresignedChunk>,
+}
@@ -0,0 +1,44 @@
+use charybdis::types::{Text, Timestamp, Uuid};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::model::device::Device;
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct DeviceCreateRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceResponse {
+    pub id: Uuid,
+    pub name: Text,
+    pub created_at: Timestamp,
+    #[serde(default)]
+    pub revoked_at: Option<Timestamp>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceCreateResponse {
+    #[serde(flatten)]
+    pub device: DeviceResponse,
+    // long-lived credential of the device, only returned when it is registered
+    pub token: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DevicesResponse {
+    pub objects: Vec<DeviceResponse>,
+}
+
+impl From<Device> for DeviceResponse {
+    fn from(device: Device) -> Self {
+        DeviceResponse {
+            id: device.id,
+            name: device.name,
+            created_at: device.created_at,
+            revoked_at: device.revoked_at,
+        }
+    }
+}
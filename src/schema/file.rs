@@ -10,6 +10,8 @@ use crate::model::file::File;
 pub enum FileType {
     FILE,
     DIRECTORY,
+    // a symbolic link stored as a link, its target is kept in the metadata
+    SYMLINK,
 }
 
 impl fmt::Display for FileType {
@@ -41,6 +43,27 @@ pub struct FileResponse {
     pub upload_presigned_url: Option<Text>,
     pub created_at: Timestamp,
     pub modified_at: Timestamp,
+    #[serde(default)]
+    pub checksum: Option<Text>,
+    // size and ETag of the stored object, recorded when the upload is completed
+    #[serde(default)]
+    pub size: Option<i64>,
+    #[serde(default)]
+    pub etag: Option<Text>,
+    #[serde(default)]
+    pub key_id: Option<Text>,
+    // the stored object is a manifest of content-defined chunks
+    #[serde(default)]
+    pub chunked: Option<bool>,
+    // compression of the stored content, e.g. `zstd`
+    #[serde(default)]
+    pub codec: Option<Text>,
+    // POSIX attributes recorded by the agent, opaque to the server
+    #[serde(default)]
+    pub metadata: Option<Text>,
+    // device that created the file, files created with a user's token have none
+    #[serde(default)]
+    pub device_id: Option<Uuid>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Validate)]
@@ -49,6 +72,13 @@ pub struct FileCreateRequest {
     pub directory: Text,
     pub file_type: FileType,
     pub status: FileStatus,
+    // opaque id of the key name and content are encrypted with, if any
+    #[serde(default)]
+    pub key_id: Option<Text>,
+    // POSIX attributes of the file, encrypted with the key when there is one
+    #[serde(default)]
+    #[validate(length(max = 65536))]
+    pub metadata: Option<Text>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Validate)]
@@ -59,9 +89,96 @@ pub struct FileUpdateRequest {
     pub status: FileStatus,
     pub created_at: Timestamp,
     pub modified_at: Timestamp,
+    // hex encoded SHA-256 of the content, set by the agent once the upload is done
+    #[serde(default)]
+    pub checksum: Option<Text>,
+    #[serde(default)]
+    pub key_id: Option<Text>,
+    // replaces the stored attributes, a request without them clears them
+    #[serde(default)]
+    #[validate(length(max = 65536))]
+    pub metadata: Option<Text>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct FileCompleteRequest {
+    pub modified_at: Timestamp,
+    // hex encoded SHA-256 of the uploaded content
+    pub checksum: Option<Text>,
+    // expected size in bytes, the upload is rejected if the object differs
+    #[validate(range(min = 0))]
+    pub size: i64,
+    // compression applied to the content before it was uploaded
+    #[serde(default)]
+    pub codec: Option<Text>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultipartUploadResponse {
+    pub upload_id: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct MultipartPartsRequest {
+    pub upload_id: Text,
+    #[validate(length(min = 1, max = 100))]
+    pub part_numbers: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PresignedPart {
+    pub part_number: i32,
+    pub url: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultipartPartsResponse {
+    pub parts: Vec<PresignedPart>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletedPart {
+    pub part_number: i32,
+    pub etag: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct MultipartCompleteRequest {
+    pub upload_id: Text,
+    #[validate(length(min = 1, max = 10000))]
+    pub parts: Vec<CompletedPart>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultipartAbortQuery {
+    pub upload_id: Text,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct FilesResponse {
     pub objects: Vec<File>,
 }
+
+impl From<File> for FileResponse {
+    fn from(file: File) -> Self {
+        FileResponse {
+            id: file.id,
+            name: file.name,
+            directory: file.directory,
+            file_type: file.file_type,
+            status: file.status,
+            presigned_url: None,
+            upload_presigned_url: None,
+            created_at: file.created_at,
+            modified_at: file.modified_at,
+            checksum: file.checksum,
+            size: file.size,
+            etag: file.etag,
+            key_id: file.key_id,
+            chunked: file.chunked,
+            codec: file.codec,
+            metadata: file.metadata,
+            device_id: file.device_id,
+        }
+    }
+}
@@ -1,2 +1,5 @@
+pub mod chunk;
+pub mod device;
 pub mod file;
+pub mod snapshot;
 pub mod user;
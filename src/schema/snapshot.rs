@@ -0,0 +1,62 @@
+use charybdis::types::{Text, Timestamp, Uuid};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::model::snapshot::Snapshot;
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct SnapshotCreateRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: Text,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotResponse {
+    pub id: Uuid,
+    pub name: Text,
+    pub status: Text,
+    pub created_at: Timestamp,
+    #[serde(default)]
+    pub completed_at: Option<Timestamp>,
+    // number of files and bytes captured, recorded when the snapshot is completed
+    #[serde(default)]
+    pub files: Option<i64>,
+    #[serde(default)]
+    pub size: Option<i64>,
+    #[serde(default)]
+    pub device_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotsResponse {
+    pub objects: Vec<SnapshotResponse>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct SnapshotFilesRequest {
+    // files whose current version the snapshot captures
+    #[validate(length(min = 1, max = 100))]
+    pub file_ids: Vec<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotFilesResponse {
+    pub captured: i64,
+    // files that don't exist, are still being uploaded or have no stored content
+    pub skipped: Vec<Uuid>,
+}
+
+impl From<Snapshot> for SnapshotResponse {
+    fn from(snapshot: Snapshot) -> Self {
+        SnapshotResponse {
+            id: snapshot.id,
+            name: snapshot.name,
+            status: snapshot.status,
+            created_at: snapshot.created_at,
+            completed_at: snapshot.completed_at,
+            files: snapshot.files,
+            size: snapshot.size,
+            device_id: snapshot.device_id,
+        }
+    }
+}
@@ -40,15 +40,18 @@ pub struct TokenClaims {
     pub sub: String,
     pub iat: usize,
     pub exp: usize,
+    // id of the device a device credential was issued to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Validate)]
+#[derive(Serialize, Deserialize, Debug, Validate)]
 pub struct LoginUserRequest {
     pub email: Text,
     pub password: Text,
 }
 
-#[derive(Serialize, Debug, Validate)]
+#[derive(Serialize, Deserialize, Debug, Validate)]
 pub struct LoginUserResponse {
     pub id: Uuid,
     pub email: Text,
@@ -7,6 +7,9 @@ pub struct TokenClaims {
     pub sub: String,
     pub iat: usize,
     pub exp: usize,
+    // id of the device a device credential was issued to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
 }
 
 pub fn create_token(
@@ -25,6 +28,34 @@ pub fn create_token(
         sub: user_id.to_string(),
         exp,
         iat,
+        device: None,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )
+}
+
+// create_device_token issues the long-lived credential of a registered device,
+// it stays valid until the device is revoked or it expires
+pub fn create_device_token(
+    user_id: &str,
+    device_id: &str,
+    secret: &[u8],
+    expires_in_days: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    if user_id.is_empty() {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidSubject.into());
+    }
+
+    let now = Utc::now();
+    let claims = TokenClaims {
+        sub: user_id.to_string(),
+        iat: now.timestamp() as usize,
+        exp: (now + Duration::days(expires_in_days)).timestamp() as usize,
+        device: Some(device_id.to_string()),
     };
 
     encode(